@@ -1,37 +1,69 @@
 use crate::{
     client::{
-        clients::{ClientState, ClientsRefStorage, ClientsStorage},
+        clients::{Client, ClientState, ClientsRefStorage, ClientsStorage},
         dtls::{extract_dtls, push_dtls},
-        group::{Group, GroupId},
+        group::{Group, GroupId, GroupMembers, GroupSettingsRequest},
+        sessions::SessionDescription,
+        tracks::{TrackKey, Tracks},
     },
     dtls::{
-        connector::connect,
-        message::{DtlsMessage, MessageType},
+        connector::{connect, DtlsRole},
+        message::DtlsMessage,
     },
-    rtp::core::{is_rtcp, rtcp_processor, rtp_processor},
+    rtp::{
+        codec::{Codec, MediaKind},
+        core::{is_rtcp, parse_rtp, rtcp_processor, rtp_processor},
+        packet::RtpPacket,
+        rtcp::{ReceiverReport, RtcpPacket, TransportFeedback, TransportFeedbackMessage},
+        srtp::ErrorParse,
+    },
+    sctp::datachannel::ChannelMessage,
     server::udp::{UdpSend, WebRtcRequest},
 };
 use actix::prelude::*;
-use futures::stream::{iter, StreamExt, TryStreamExt};
-use log::warn;
-use openssl::ssl::SslAcceptor;
-use std::{net::SocketAddr, sync::Arc};
+use futures::{
+    future::FutureExt,
+    lock::MutexGuard,
+    stream::{iter, StreamExt, TryStreamExt},
+};
+use log::{debug, info, warn};
+use openssl::ssl::{SslAcceptor, SslConnector};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
 use tokio::time::{timeout, Duration};
 
+/// SCTP packets are never larger than a DTLS record.
+const DTLS_RECORD_MAX_LEN: usize = 16384;
+const DATA_CHANNEL_TIMER_INTERVAL: Duration = Duration::from_millis(200);
+
 pub struct ClientActor {
     client_storage: ClientsRefStorage,
     groups: Group,
     ssl_acceptor: Arc<SslAcceptor>,
+    ssl_connector: Arc<SslConnector>,
     udp_send: Arc<Addr<UdpSend>>,
+    migrations: HashMap<SocketAddr, SocketAddr>,
+    disconnect_subscribers: Vec<Recipient<Disconnected>>,
 }
 
 impl ClientActor {
-    pub fn new(ssl_acceptor: Arc<SslAcceptor>, udp_send: Arc<Addr<UdpSend>>) -> Addr<ClientActor> {
-        ClientActor::create(|_| ClientActor {
-            ssl_acceptor,
-            udp_send,
-            client_storage: ClientsRefStorage::new(),
-            groups: Group::default(),
+    pub fn new(
+        ssl_acceptor: Arc<SslAcceptor>,
+        ssl_connector: Arc<SslConnector>,
+        udp_send: Arc<Addr<UdpSend>>,
+    ) -> Addr<ClientActor> {
+        ClientActor::create(|ctx| {
+            ctx.add_stream(
+                tokio::time::interval(DATA_CHANNEL_TIMER_INTERVAL).map(|_| DataChannelTimers),
+            );
+            ClientActor {
+                ssl_acceptor,
+                ssl_connector,
+                udp_send,
+                client_storage: ClientsRefStorage::new(),
+                groups: Group::default(),
+                migrations: HashMap::new(),
+                disconnect_subscribers: Vec::new(),
+            }
         })
     }
 }
@@ -48,11 +80,23 @@ impl Handler<WebRtcRequest> for ClientActor {
             WebRtcRequest::Dtls(message, addr) => {
                 let client_ref = self.client_storage.entry(addr).or_default();
                 let acceptor = Arc::clone(&self.ssl_acceptor);
+                let connector = Arc::clone(&self.ssl_connector);
 
                 ctx.add_message_stream(client_ref.outgoing_stream(addr));
 
                 let incoming_writer = Arc::clone(&client_ref.get_channels().incoming_writer);
                 let client = client_ref.get_client();
+                let members: ClientsStorage = self
+                    .groups
+                    .get_addressess(addr)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|g_addr| {
+                        self.client_storage
+                            .get(&g_addr)
+                            .map(|client_ref| (g_addr, client_ref.get_client()))
+                    })
+                    .collect();
 
                 let self_addr = ctx.address();
 
@@ -71,33 +115,45 @@ impl Handler<WebRtcRequest> for ClientActor {
 
                         match client_unlocked.state {
                             ClientState::New(_) => {
-                                if let Err(e) = connect(&mut client_unlocked, acceptor).await {
-                                    warn!("connect err: {}", e);
-                                    match self_addr.send(DeleteMessage(addr)).await {
-                                        Err(e) => warn!("delete err: {}", e),
-                                        Ok(is_deleted) => println!("deleted {}", is_deleted),
-                                    }
-                                }
+                                handshake(client_unlocked, acceptor, connector, self_addr, addr)
+                                    .await
                             }
                             ClientState::Connected(_, _) => {
-                                let mut buf = vec![0; 512];
-                                let result = timeout(
-                                    Duration::from_millis(10),
-                                    extract_dtls(&mut client_unlocked, &mut buf),
-                                )
-                                .await
-                                .map_err(|_| std::io::ErrorKind::TimedOut.into())
-                                .and_then(|r| r);
-
+                                // Drain all decrypted records, only the first read waits
+                                let mut first = true;
+                                let mut messages = Vec::new();
+                                let closed = loop {
+                                    let mut buf = vec![0; DTLS_RECORD_MAX_LEN];
+                                    let read = extract_dtls(&mut client_unlocked, &mut buf);
+                                    let result = if first {
+                                        timeout(Duration::from_millis(10), read).await.ok()
+                                    } else {
+                                        read.now_or_never()
+                                    };
+                                    let len = match result {
+                                        Some(Ok(0)) => break true,
+                                        Some(Ok(len)) => len,
+                                        _ => break false,
+                                    };
+                                    match client_unlocked.receive_data(&buf[..len]).await {
+                                        Ok(received) => messages.extend(received),
+                                        Err(e) => warn!("data channel err: {}", e),
+                                    }
+                                    first = false;
+                                };
                                 drop(client_unlocked);
 
-                                if matches!(result, Ok(0)) {
+                                if closed {
                                     match self_addr.send(DeleteMessage(addr)).await {
                                         Err(e) => warn!("delete err: {}", e),
-                                        Ok(d) if d => println!("success deleted"),
-                                        Ok(_) => println!("fail deleting"),
+                                        Ok(deleted) => debug!(
+                                            "closed dtls client {} deleted: {}",
+                                            addr, deleted
+                                        ),
                                     }
                                 }
+
+                                relay_data(&messages, &members).await;
                             }
                             ClientState::Shutdown => {}
                         }
@@ -121,63 +177,78 @@ impl Handler<WebRtcRequest> for ClientActor {
 
                 ctx.spawn(
                     async move {
-                        let mut client_unlocked = client.lock().await;
-                        if let ClientState::Connected(_, srtp) = &mut client_unlocked.state {
-                            let message_processed = if is_rtcp(&message) {
-                                rtcp_processor(WebRtcRequest::Rtc(message, addr), Some(srtp))
-                                    .map_err(|e| {
-                                        if !e.should_ignored() {
-                                            warn!("rtp err: {}", e);
-                                        }
-                                        e
-                                    })
-                                    .ok()
-                            } else {
-                                rtp_processor(WebRtcRequest::Rtc(message, addr), Some(srtp))
-                                    .map_err(|e| {
-                                        if !e.should_ignored() {
-                                            warn!("rtp err: {}", e);
-                                        }
-                                        e
-                                    })
-                                    .ok()
-                            };
-
-                            let addresses_processed = addresses
-                                .filter(|addresses| !addresses.is_empty())
-                                .and_then(|addresses| Some((addresses, message_processed?)));
-
-                            if let Some((addresses, message)) = addresses_processed {
-                                let is_sent = iter(addresses)
-                                    .then(|(addr, client)| {
-                                        let mut message = message.clone();
-                                        let udp_send = Arc::clone(&udp_send);
-                                        async move {
-                                            let mut client = client.lock().await;
-                                            if let ClientState::Connected(_, srtp) =
-                                                &mut client.state
-                                            {
-                                                if is_rtcp(&message) {
-                                                    match srtp.protect_rtcp(&message) {
-                                                        Ok(m) => message = m,
-                                                        Err(e) => warn!("protect rtcp err: {}", e),
-                                                    }
-                                                } else {
-                                                    match srtp.protect(&message) {
-                                                        Ok(m) => message = m,
-                                                        Err(e) => warn!("protect rtp err: {}", e),
-                                                    }
-                                                }
-                                            }
-                                            udp_send.send(WebRtcRequest::Rtc(message, addr)).await
-                                        }
-                                    })
-                                    .try_collect::<Vec<_>>()
-                                    .await;
+                        let mut client_guard = client.lock().await;
+                        let client_unlocked = &mut *client_guard;
+                        let srtp = match &mut client_unlocked.state {
+                            ClientState::Connected(_, srtp) => srtp,
+                            _ => return,
+                        };
+
+                        let origin = Arc::clone(&client_unlocked.description);
+
+                        let message_processed = if is_rtcp(&message) {
+                            rtcp_processor(WebRtcRequest::Rtc(message, addr), Some(srtp))
+                                .and_then(|message| RtcpPacket::parse_compound(&message))
+                                .map(|packets| {
+                                    Forwarded::rtcp(
+                                        packets,
+                                        &client_unlocked.streams,
+                                        &client_unlocked.tracks,
+                                    )
+                                })
+                        } else if origin.publishes {
+                            rtp_processor(
+                                WebRtcRequest::Rtc(message, addr),
+                                Some(srtp),
+                                &origin.codecs,
+                            )
+                            .map(|(message, codec)| {
+                                if let Some(rtp) = parse_rtp(&message) {
+                                    client_unlocked.streams.insert(rtp.ssrc(), codec.kind);
+                                }
+                                Forwarded::Rtp(message, codec)
+                            })
+                        } else {
+                            return;
+                        };
 
-                                if let Err(e) = is_sent {
-                                    warn!("udp send err: {}", e)
+                        let message_processed = message_processed
+                            .map_err(|e| {
+                                if !e.should_ignored() {
+                                    warn!("rtp err: {}", e);
                                 }
+                                e
+                            })
+                            .ok();
+
+                        drop(client_guard);
+
+                        let addresses_processed = addresses
+                            .filter(|addresses| !addresses.is_empty())
+                            .and_then(|addresses| Some((addresses, message_processed?)));
+
+                        if let Some((addresses, forwarded)) = addresses_processed {
+                            let forwarded = &forwarded;
+                            let origin = &origin;
+                            let is_sent = iter(addresses)
+                                .filter_map(|(g_addr, client)| async move {
+                                    let mut client = client.lock().await;
+                                    match forwarded.protect_for(&mut client, addr, origin)? {
+                                        Ok(message) => Some((message, g_addr)),
+                                        Err(e) => {
+                                            warn!("protect err: {}", e);
+                                            None
+                                        }
+                                    }
+                                })
+                                .then(|(message, addr)| {
+                                    udp_send.send(WebRtcRequest::Rtc(message, addr))
+                                })
+                                .try_collect::<Vec<_>>()
+                                .await;
+
+                            if let Err(e) = is_sent {
+                                warn!("udp send err: {}", e)
                             }
                         }
                     }
@@ -190,24 +261,52 @@ impl Handler<WebRtcRequest> for ClientActor {
     }
 }
 
+impl StreamHandler<DataChannelTimers> for ClientActor {
+    /// Clients in the middle of a handshake or a packet are skipped until the next tick.
+    fn handle(&mut self, _: DataChannelTimers, ctx: &mut Context<Self>) {
+        let clients: Vec<_> = self
+            .client_storage
+            .values()
+            .map(|client_ref| client_ref.get_client())
+            .collect();
+        ctx.spawn(
+            async move {
+                for client in clients {
+                    let mut client = match client.try_lock() {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    if !client.data_channels.is_established() {
+                        continue;
+                    }
+                    client.data_channels.handle_timeout(Instant::now());
+                    if let Err(e) = client.flush_data().await {
+                        warn!("data channel err: {}", e)
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
 impl Handler<DtlsMessage> for ClientActor {
     type Result = ();
 
     fn handle(&mut self, item: DtlsMessage, ctx: &mut Context<Self>) {
-        match item.get_type() {
-            MessageType::Incoming => warn!("accepted incoming dtls message in the ClientActor"),
-            MessageType::Outgoing => {
-                let udp_send = Arc::clone(&self.udp_send);
-                ctx.spawn(
-                    async move {
-                        if let Err(e) = udp_send.send(item.into_webrtc()).await {
-                            warn!("udp sender: {}", e)
-                        }
-                    }
-                    .into_actor(self),
-                );
+        let item = match self.migrations.get(&item.get_addr()) {
+            Some(addr) => item.redirect(*addr),
+            None => item,
+        };
+        let udp_send = Arc::clone(&self.udp_send);
+        ctx.spawn(
+            async move {
+                if let Err(e) = udp_send.send(item.into_webrtc()).await {
+                    warn!("udp sender: {}", e)
+                }
             }
-        }
+            .into_actor(self),
+        );
     }
 }
 
@@ -217,9 +316,45 @@ impl Handler<DeleteMessage> for ClientActor {
     fn handle(
         &mut self,
         DeleteMessage(addr): DeleteMessage,
-        _ctx: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> Self::Result {
-        self.client_storage
+        let subscribers: ClientsStorage = self
+            .groups
+            .get_addressess(addr)
+            .into_iter()
+            .flatten()
+            .filter_map(|g_addr| {
+                self.client_storage
+                    .get(&g_addr)
+                    .map(|client_ref| (g_addr, client_ref.get_client()))
+            })
+            .collect();
+        let udp_send = Arc::clone(&self.udp_send);
+        ctx.spawn(
+            async move {
+                for (g_addr, client) in subscribers {
+                    let goodbye = client.lock().await.goodbye(addr);
+                    match goodbye {
+                        Some(Ok(message)) => {
+                            if let Err(e) = udp_send.send(WebRtcRequest::Rtc(message, g_addr)).await
+                            {
+                                warn!("udp send err: {}", e)
+                            }
+                        }
+                        Some(Err(e)) => warn!("protect rtcp err: {}", e),
+                        None => {}
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+
+        self.migrations
+            .retain(|from, to| *from != addr && *to != addr);
+
+        let group_id = self.groups.group_of(addr);
+        let deleted = self
+            .client_storage
             .remove(&addr)
             .and_then(|_| {
                 if self.groups.remove_client(addr) {
@@ -228,7 +363,66 @@ impl Handler<DeleteMessage> for ClientActor {
                     None
                 }
             })
-            .is_some()
+            .is_some();
+        if deleted {
+            info!("client {} disconnected", addr);
+            let event = Disconnected { addr, group_id };
+            self.disconnect_subscribers
+                .retain(|subscriber| subscriber.do_send(event).is_ok());
+        }
+        deleted
+    }
+}
+
+impl Handler<SubscribeDisconnected> for ClientActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SubscribeDisconnected(subscriber): SubscribeDisconnected,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.disconnect_subscribers.push(subscriber);
+    }
+}
+
+impl Handler<MigrateClient> for ClientActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        MigrateClient(from, to): MigrateClient,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let client_ref = match self.client_storage.remove(&from) {
+            Some(client_ref) => client_ref,
+            None => return,
+        };
+        self.client_storage.insert(to, client_ref);
+        self.groups.replace_client(from, to);
+        self.migrations
+            .values_mut()
+            .filter(|addr| **addr == from)
+            .for_each(|addr| *addr = to);
+        self.migrations.insert(from, to);
+
+        let members: Vec<_> = self
+            .groups
+            .get_addressess(to)
+            .into_iter()
+            .flatten()
+            .filter_map(|g_addr| self.client_storage.get(&g_addr))
+            .map(|client_ref| client_ref.get_client())
+            .collect();
+        ctx.spawn(
+            async move {
+                for client in members {
+                    client.lock().await.tracks.migrate_publisher(from, to);
+                }
+            }
+            .into_actor(self),
+        );
+        info!("client {} moved to {}", from, to);
     }
 }
 
@@ -237,17 +431,369 @@ impl Handler<GroupId> for ClientActor {
 
     fn handle(
         &mut self,
-        GroupId(group_id, addr): GroupId,
+        GroupId(group_id, addr, description): GroupId,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        // Late binding requests must not bring back a client that has been deleted
+        let client_ref = match self.client_storage.get(&addr) {
+            Some(client_ref) => client_ref,
+            None => return,
+        };
+        let client = client_ref.get_client();
+        // A passive peer waits for the ClientHello, it is sent once ICE has connected
+        let mut start_handshake = None;
+        if self.groups.insert_client(group_id, addr) {
+            ctx.notify(RequestKeyframe(addr));
+            if description.dtls_role == DtlsRole::Active {
+                ctx.add_message_stream(client_ref.outgoing_stream(addr));
+                start_handshake = Some((
+                    Arc::clone(&self.ssl_acceptor),
+                    Arc::clone(&self.ssl_connector),
+                ));
+            }
+        }
+
+        let self_addr = ctx.address();
+        ctx.spawn(
+            async move {
+                let mut client = client.lock().await;
+                let renegotiated = !Arc::ptr_eq(&client.description, &description)
+                    && !description.announced.is_empty();
+                description
+                    .announced
+                    .iter()
+                    .for_each(|announced| client.tracks.announce(announced));
+                client.description = description;
+                if let Some((acceptor, connector)) = start_handshake {
+                    if matches!(client.state, ClientState::New(_)) {
+                        handshake(client, acceptor, connector, self_addr, addr).await;
+                        return;
+                    }
+                }
+                drop(client);
+
+                if renegotiated {
+                    self_addr.do_send(RequestKeyframe(addr));
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+impl Handler<NewClient> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, NewClient(addr): NewClient, _ctx: &mut Context<Self>) -> Self::Result {
+        self.client_storage.entry(addr).or_default();
+    }
+}
+
+impl Handler<GroupSettingsRequest> for ClientActor {
+    type Result = MessageResult<GroupSettingsRequest>;
+
+    fn handle(
+        &mut self,
+        GroupSettingsRequest(group_id, requested): GroupSettingsRequest,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        MessageResult(self.groups.settings(group_id, requested))
+    }
+}
+
+impl Handler<GroupMembers> for ClientActor {
+    type Result = ResponseFuture<Vec<(SocketAddr, Arc<SessionDescription>)>>;
+
+    fn handle(
+        &mut self,
+        GroupMembers(group_id): GroupMembers,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        if self.client_storage.contains_key(&addr) {
-            self.groups.insert_client(group_id, addr)
+        let members: ClientsStorage = self
+            .groups
+            .get_group(group_id)
+            .into_iter()
+            .filter_map(|g_addr| {
+                self.client_storage
+                    .get(&g_addr)
+                    .map(|client_ref| (g_addr, client_ref.get_client()))
+            })
+            .collect();
+
+        Box::pin(async move {
+            let mut descriptions = Vec::with_capacity(members.len());
+            for (addr, client) in members {
+                descriptions.push((addr, Arc::clone(&client.lock().await.description)));
+            }
+            descriptions
+        })
+    }
+}
+
+impl Handler<RequestKeyframe> for ClientActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        RequestKeyframe(addr): RequestKeyframe,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let publishers: ClientsStorage = match self.groups.get_addressess(addr) {
+            Some(addresses) => addresses
+                .into_iter()
+                .filter_map(|g_addr| {
+                    self.client_storage
+                        .get(&g_addr)
+                        .map(|client_ref| (g_addr, client_ref.get_client()))
+                })
+                .collect(),
+            None => return,
+        };
+        let udp_send = Arc::clone(&self.udp_send);
+
+        ctx.spawn(
+            async move {
+                for (addr, client) in publishers {
+                    let request = client.lock().await.keyframe_request();
+                    match request {
+                        Some(Ok(message)) => {
+                            if let Err(e) = udp_send.send(WebRtcRequest::Rtc(message, addr)).await {
+                                warn!("udp send err: {}", e)
+                            }
+                        }
+                        Some(Err(e)) => warn!("protect rtcp err: {}", e),
+                        None => {}
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+enum Forwarded {
+    Rtp(Vec<u8>, Codec),
+    Rtcp {
+        sender_ssrc: u32,
+        kind: Option<MediaKind>,
+        reports: Vec<RtcpPacket>,
+        feedback: Vec<(u32, RtcpPacket)>,
+    },
+}
+
+impl Forwarded {
+    fn rtcp(
+        packets: Vec<RtcpPacket>,
+        streams: &HashMap<u32, MediaKind>,
+        tracks: &Tracks,
+    ) -> Forwarded {
+        let mut sender_ssrc = None;
+        let mut reports = Vec::new();
+        let mut feedback = Vec::new();
+
+        packets.into_iter().for_each(|packet| match packet {
+            RtcpPacket::SenderReport(mut sr) => {
+                sender_ssrc.get_or_insert(sr.ssrc);
+                sr.reports.clear();
+                reports.push(RtcpPacket::SenderReport(sr));
+            }
+            RtcpPacket::ReceiverReport(rr) => {
+                sender_ssrc.get_or_insert(rr.ssrc);
+            }
+            packet @ RtcpPacket::SourceDescription(_) | packet @ RtcpPacket::Goodbye(_) => {
+                reports.push(packet)
+            }
+            RtcpPacket::TransportFeedback(TransportFeedback {
+                message: TransportFeedbackMessage::TransportCc(_),
+                ..
+            }) => {}
+            mut packet @ RtcpPacket::TransportFeedback(_)
+            | mut packet @ RtcpPacket::PayloadFeedback(_) => {
+                tracks.translate_feedback(&mut packet);
+                packet
+                    .media_ssrcs()
+                    .into_iter()
+                    .for_each(|ssrc| feedback.push((ssrc, packet.clone())));
+            }
+            RtcpPacket::App(_) | RtcpPacket::Unknown(_) => {}
+        });
+
+        let sender_ssrc = sender_ssrc.unwrap_or_default();
+        let kind = streams.get(&sender_ssrc).copied();
+        if kind.is_none() {
+            reports.clear();
+        }
+
+        Forwarded::Rtcp {
+            sender_ssrc,
+            kind,
+            reports,
+            feedback,
+        }
+    }
+
+    fn protect_for(
+        &self,
+        client: &mut Client,
+        from: SocketAddr,
+        origin: &SessionDescription,
+    ) -> Option<Result<Vec<u8>, ErrorParse>> {
+        let srtp = match &mut client.state {
+            ClientState::Connected(_, srtp) => srtp,
+            _ => return None,
+        };
+
+        let subscribes = client.description.subscribes;
+        match self {
+            Forwarded::Rtp(_, _) if !subscribes => None,
+            Forwarded::Rtp(message, codec) => {
+                let target = client
+                    .description
+                    .codecs
+                    .find_equivalent(codec, &origin.codecs)?;
+                let mut packet = RtpPacket::new(message.clone()).ok()?;
+                packet.set_payload_type(target.payload_type);
+
+                let key = TrackKey::new(from, codec.kind);
+                let repaired = origin.rtx_sources.get(&packet.ssrc()).copied();
+                if !client.tracks.forward(
+                    key,
+                    codec.apt.is_some(),
+                    repaired,
+                    codec.clock_rate,
+                    &mut packet,
+                ) {
+                    return None;
+                }
+
+                Some(srtp.protect(packet.as_bytes()))
+            }
+            Forwarded::Rtcp {
+                sender_ssrc,
+                kind,
+                reports,
+                feedback,
+            } => {
+                let tracks = &client.tracks;
+                let mut packets: Vec<RtcpPacket> = match kind {
+                    Some(kind) if subscribes && client.description.codecs.has_kind(*kind) => {
+                        reports
+                            .iter()
+                            .filter_map(|report| tracks.translate_report(from, report))
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+                let streams = &client.streams;
+                feedback
+                    .iter()
+                    .filter(|(ssrc, _)| streams.contains_key(ssrc))
+                    .for_each(|(_, packet)| {
+                        if !packets.contains(packet) {
+                            packets.push(packet.clone())
+                        }
+                    });
+
+                match packets.first() {
+                    None => return None,
+                    Some(RtcpPacket::SenderReport(_)) | Some(RtcpPacket::ReceiverReport(_)) => {}
+                    Some(_) => packets.insert(
+                        0,
+                        RtcpPacket::ReceiverReport(ReceiverReport {
+                            ssrc: *sender_ssrc,
+                            reports: Vec::new(),
+                            extension: Vec::new(),
+                        }),
+                    ),
+                }
+
+                Some(srtp.protect_rtcp(&RtcpPacket::write_compound(&packets)))
+            }
+        }
+    }
+}
+
+/// Relays data channel messages to the group members with a channel of the same label.
+async fn relay_data(messages: &[ChannelMessage], members: &ClientsStorage) {
+    for message in messages {
+        for (g_addr, client) in members {
+            if let Err(e) = client.lock().await.send_data(message).await {
+                warn!("data channel relay to {} err: {}", g_addr, e)
+            }
+        }
+    }
+}
+
+/// Runs the DTLS handshake of a new client, the client is dropped if it fails.
+async fn handshake(
+    mut client: MutexGuard<'_, Client>,
+    ssl_acceptor: Arc<SslAcceptor>,
+    ssl_connector: Arc<SslConnector>,
+    self_addr: Addr<ClientActor>,
+    addr: SocketAddr,
+) {
+    match connect(&mut client, ssl_acceptor, ssl_connector).await {
+        Ok(()) => {
+            drop(client);
+            if let Err(e) = self_addr.send(RequestKeyframe(addr)).await {
+                warn!("keyframe request err: {}", e)
+            }
+        }
+        Err(e) => {
+            warn!("connect err: {}", e);
+            match self_addr.send(DeleteMessage(addr)).await {
+                Err(e) => warn!("delete err: {}", e),
+                Ok(deleted) => debug!("failed dtls client {} deleted: {}", addr, deleted),
+            }
         }
     }
 }
 
-struct DeleteMessage(SocketAddr);
+struct RequestKeyframe(SocketAddr);
+
+struct DataChannelTimers;
+
+impl Message for RequestKeyframe {
+    type Result = ();
+}
+
+/// Creates the client of an address that has just passed its first binding check.
+pub struct NewClient(pub SocketAddr);
+
+impl Message for NewClient {
+    type Result = ();
+}
+
+/// Moves the client with its DTLS and SRTP state to a new address after an ICE restart.
+pub struct MigrateClient(pub SocketAddr, pub SocketAddr);
+
+impl Message for MigrateClient {
+    type Result = ();
+}
+
+/// Drops the client and its group membership, the rest of the group gets a BYE for its tracks.
+pub struct DeleteMessage(pub SocketAddr);
 
 impl Message for DeleteMessage {
     type Result = bool;
 }
+
+/// Sent to the subscribers once a client is dropped, whether its consent expired, its DTLS
+/// connection was closed or the handshake failed.
+#[derive(Debug, Clone, Copy)]
+pub struct Disconnected {
+    pub addr: SocketAddr,
+    /// `None` when the client left before joining a group.
+    pub group_id: Option<usize>,
+}
+
+impl Message for Disconnected {
+    type Result = ();
+}
+
+/// Registers a recipient of the `Disconnected` events.
+pub struct SubscribeDisconnected(pub Recipient<Disconnected>);
+
+impl Message for SubscribeDisconnected {
+    type Result = ();
+}
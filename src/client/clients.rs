@@ -1,15 +1,30 @@
 use crate::{
-    client::stream::{ClientSslPackets, ClientSslPacketsChannels},
+    client::{
+        dtls::write_message,
+        sessions::SessionDescription,
+        stream::{ClientSslPackets, ClientSslPacketsChannels},
+        tracks::Tracks,
+    },
     dtls::message::DtlsMessage,
-    rtp::srtp::{ErrorParse, SrtpTransport},
+    rtp::{
+        codec::MediaKind,
+        rtcp::{Goodbye, PayloadFeedback, PayloadFeedbackMessage, ReceiverReport, RtcpPacket},
+        srtp::{ErrorParse, SrtpTransport},
+    },
+    sctp::{
+        datachannel::{ChannelMessage, DataChannels},
+        SctpError,
+    },
 };
 use futures::{channel::mpsc::SendError, lock::Mutex, prelude::*, stream::FusedStream};
+use openssl::error::ErrorStack;
 use std::{
     collections::HashMap,
     error::Error,
     fmt::{Display, Formatter},
     net::SocketAddr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio_openssl::SslStream;
 
@@ -17,6 +32,103 @@ use tokio_openssl::SslStream;
 pub struct Client {
     pub(crate) state: ClientState,
     pub(crate) channels: ClientSslPacketsChannels,
+    pub(crate) description: Arc<SessionDescription>,
+    pub(crate) streams: HashMap<u32, MediaKind>,
+    pub(crate) last_keyframe_request: Option<Instant>,
+    pub(crate) tracks: Tracks,
+    pub(crate) data_channels: DataChannels,
+}
+
+pub const SFU_RTCP_SSRC: u32 = 1;
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Client {
+    pub fn keyframe_request(&mut self) -> Option<Result<Vec<u8>, ErrorParse>> {
+        let srtp = match &mut self.state {
+            ClientState::Connected(_, srtp) => srtp,
+            _ => return None,
+        };
+
+        let mut packets: Vec<RtcpPacket> = self
+            .streams
+            .iter()
+            .filter(|(_, kind)| **kind == MediaKind::Video)
+            .map(|(ssrc, _)| {
+                RtcpPacket::PayloadFeedback(PayloadFeedback {
+                    sender_ssrc: SFU_RTCP_SSRC,
+                    media_ssrc: *ssrc,
+                    message: PayloadFeedbackMessage::Pli,
+                })
+            })
+            .collect();
+        if packets.is_empty() {
+            return None;
+        }
+
+        if matches!(self.last_keyframe_request, Some(last) if last.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return None;
+        }
+        self.last_keyframe_request = Some(Instant::now());
+
+        packets.insert(
+            0,
+            RtcpPacket::ReceiverReport(ReceiverReport {
+                ssrc: SFU_RTCP_SSRC,
+                reports: Vec::new(),
+                extension: Vec::new(),
+            }),
+        );
+        Some(srtp.protect_rtcp(&RtcpPacket::write_compound(&packets)))
+    }
+
+    /// Drops the tracks forwarded from `publisher` and builds a BYE for them.
+    pub fn goodbye(&mut self, publisher: SocketAddr) -> Option<Result<Vec<u8>, ErrorParse>> {
+        let sources = self.tracks.remove_publisher(publisher);
+        let srtp = match &mut self.state {
+            ClientState::Connected(_, srtp) if !sources.is_empty() => srtp,
+            _ => return None,
+        };
+
+        let packets = [
+            RtcpPacket::ReceiverReport(ReceiverReport {
+                ssrc: SFU_RTCP_SSRC,
+                reports: Vec::new(),
+                extension: Vec::new(),
+            }),
+            RtcpPacket::Goodbye(Goodbye {
+                sources,
+                reason: None,
+            }),
+        ];
+        Some(srtp.protect_rtcp(&RtcpPacket::write_compound(&packets)))
+    }
+}
+
+impl Client {
+    /// Passes an SCTP packet read from the DTLS stream to the data channels
+    /// and returns the messages received on them.
+    pub async fn receive_data(&mut self, buf: &[u8]) -> Result<Vec<ChannelMessage>, ClientError> {
+        let messages = self.data_channels.receive(buf, Instant::now())?;
+        self.flush_data().await?;
+        Ok(messages)
+    }
+
+    /// Sends a message relayed from another member if the client has a channel with its label.
+    pub async fn send_data(&mut self, message: &ChannelMessage) -> Result<(), ClientError> {
+        if self.data_channels.send(message, Instant::now())? {
+            self.flush_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pending SCTP packets into the DTLS stream, retransmissions included.
+    pub async fn flush_data(&mut self) -> Result<(), ClientError> {
+        for packet in self.data_channels.poll_transmit(Instant::now()) {
+            write_message(self, &packet).await?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for Client {
@@ -25,6 +137,11 @@ impl Default for Client {
         Client {
             state: ClientState::New(stream),
             channels,
+            description: Arc::default(),
+            streams: HashMap::new(),
+            last_keyframe_request: None,
+            tracks: Tracks::default(),
+            data_channels: DataChannels::default(),
         }
     }
 }
@@ -41,8 +158,11 @@ pub enum ClientError {
     Receive(SendError),
     NotConnected,
     AlreadyConnected,
+    FingerprintMismatch,
+    Openssl(ErrorStack),
     Read(std::io::Error),
     SrtpParseError(ErrorParse),
+    Sctp(SctpError),
 }
 
 impl Display for ClientError {
@@ -51,8 +171,14 @@ impl Display for ClientError {
             ClientError::Receive(e) => write!(f, "Receive: {}", e),
             ClientError::NotConnected => write!(f, "Client not connected"),
             ClientError::AlreadyConnected => write!(f, "Client already connected"),
+            ClientError::FingerprintMismatch => write!(
+                f,
+                "Client DTLS certificate does not match the SDP fingerprint"
+            ),
+            ClientError::Openssl(e) => write!(f, "Openssl: {}", e),
             ClientError::Read(e) => write!(f, "Read: {}", e),
             ClientError::SrtpParseError(e) => write!(f, "Srtp parsing error: {}", e),
+            ClientError::Sctp(e) => write!(f, "Sctp: {}", e),
         }
     }
 }
@@ -64,6 +190,11 @@ impl From<SendError> for ClientError {
         ClientError::Receive(e)
     }
 }
+impl From<ErrorStack> for ClientError {
+    fn from(e: ErrorStack) -> Self {
+        ClientError::Openssl(e)
+    }
+}
 impl From<std::io::Error> for ClientError {
     fn from(e: std::io::Error) -> Self {
         ClientError::Read(e)
@@ -79,6 +210,11 @@ impl From<ErrorParse> for ClientError {
         ClientError::SrtpParseError(e)
     }
 }
+impl From<SctpError> for ClientError {
+    fn from(e: SctpError) -> Self {
+        ClientError::Sctp(e)
+    }
+}
 
 pub type ClientsRefStorage = HashMap<SocketAddr, ClientRef>;
 pub type ClientsStorage = HashMap<SocketAddr, Arc<Mutex<Client>>>;
@@ -19,8 +19,7 @@ pub async fn extract_dtls(client: &mut Client, buf: &mut [u8]) -> Result<usize,
     Err(ClientError::NotConnected)
 }
 
-#[allow(dead_code)]
-pub async fn write_message(client: &mut Client, buf: &mut [u8]) -> Result<usize, ClientError> {
+pub async fn write_message(client: &mut Client, buf: &[u8]) -> Result<usize, ClientError> {
     if let ClientState::Connected(ssl_stream, _) = &mut client.state {
         return ssl_stream.write(buf).await.map_err(|e| e.into());
     }
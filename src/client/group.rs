@@ -1,13 +1,22 @@
+use crate::client::sessions::SessionDescription;
 use actix::prelude::*;
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 pub type GroupsStorage = HashMap<SocketAddr, usize>;
 pub type GroupsAddrStorage = HashMap<usize, Vec<SocketAddr>>;
 
+/// Options of a group, chosen by the client whose offer creates it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GroupSettings {
+    /// Members have to share one codec per media kind, instead of any codec of the group.
+    pub single_codec: bool,
+}
+
 #[derive(Default)]
 pub struct Group {
     groups_storage: GroupsStorage,
     groups_addr_storage: GroupsAddrStorage,
+    settings: HashMap<usize, GroupSettings>,
 }
 
 impl Group {
@@ -23,26 +32,133 @@ impl Group {
         )
     }
 
-    pub fn insert_client(&mut self, group_id: usize, addr: SocketAddr) {
+    pub fn group_of(&self, addr: SocketAddr) -> Option<usize> {
+        self.groups_storage.get(&addr).copied()
+    }
+
+    pub fn get_group(&self, group_id: usize) -> Vec<SocketAddr> {
+        self.groups_addr_storage
+            .get(&group_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Settings of the group, `requested` ones replace them while the group has no members.
+    pub fn settings(&mut self, group_id: usize, requested: Option<GroupSettings>) -> GroupSettings {
+        let is_empty = self.get_group(group_id).is_empty();
+        let settings = self.settings.entry(group_id).or_default();
+        if let (Some(requested), true) = (requested, is_empty) {
+            *settings = requested;
+        }
+        *settings
+    }
+
+    pub fn insert_client(&mut self, group_id: usize, addr: SocketAddr) -> bool {
+        match self.groups_storage.get(&addr) {
+            Some(current) if *current == group_id => return false,
+            Some(_) => {
+                self.remove_client(addr);
+            }
+            None => {}
+        }
         let groups_addr_storage = self.groups_addr_storage.entry(group_id).or_default();
         groups_addr_storage.push(addr);
         self.groups_storage.insert(addr, group_id);
+        true
+    }
+
+    /// Moves the membership of `from` to `to`, keeping its position in the group.
+    pub fn replace_client(&mut self, from: SocketAddr, to: SocketAddr) -> bool {
+        let group_id = match self.groups_storage.remove(&from) {
+            Some(group_id) => group_id,
+            None => return false,
+        };
+        self.remove_client(to);
+        self.groups_storage.insert(to, group_id);
+        if let Some(group_addrs) = self.groups_addr_storage.get_mut(&group_id) {
+            group_addrs
+                .iter_mut()
+                .filter(|g_addr| **g_addr == from)
+                .for_each(|g_addr| *g_addr = to);
+        }
+        true
     }
 
     pub fn remove_client(&mut self, addr: SocketAddr) -> bool {
-        self.groups_storage
-            .remove(&addr)
-            .and_then(|group_id| self.groups_addr_storage.get_mut(&group_id))
+        let group_id = match self.groups_storage.remove(&addr) {
+            Some(group_id) => group_id,
+            None => return false,
+        };
+        let removed = self
+            .groups_addr_storage
+            .get_mut(&group_id)
             .and_then(|group_addrs| {
                 let pos = group_addrs.iter().position(|x| *x == addr)?;
                 Some(group_addrs.remove(pos))
             })
-            .is_some()
+            .is_some();
+        if self.get_group(group_id).is_empty() {
+            self.groups_addr_storage.remove(&group_id);
+            self.settings.remove(&group_id);
+        }
+        removed
     }
 }
 
-pub struct GroupId(pub usize, pub SocketAddr);
+pub struct GroupId(pub usize, pub SocketAddr, pub Arc<SessionDescription>);
 
 impl Message for GroupId {
     type Result = ();
 }
+
+pub struct GroupMembers(pub usize);
+
+impl Message for GroupMembers {
+    type Result = Vec<(SocketAddr, Arc<SessionDescription>)>;
+}
+
+/// Looks up the settings of a group, applying the given ones when the group has no members yet.
+pub struct GroupSettingsRequest(pub usize, pub Option<GroupSettings>);
+
+impl Message for GroupSettingsRequest {
+    type Result = GroupSettings;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SINGLE_CODEC: GroupSettings = GroupSettings { single_codec: true };
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn settings_are_chosen_by_the_first_member() {
+        let mut group = Group::default();
+        assert_eq!(group.settings(1, None), GroupSettings::default());
+        assert_eq!(group.settings(1, Some(SINGLE_CODEC)), SINGLE_CODEC);
+        assert!(group.insert_client(1, addr(1)));
+
+        assert_eq!(
+            group.settings(1, Some(GroupSettings::default())),
+            SINGLE_CODEC
+        );
+        assert_eq!(group.settings(2, None), GroupSettings::default());
+    }
+
+    #[test]
+    fn settings_are_dropped_with_the_last_member() {
+        let mut group = Group::default();
+        group.settings(1, Some(SINGLE_CODEC));
+        group.insert_client(1, addr(1));
+        group.insert_client(1, addr(2));
+
+        assert!(group.remove_client(addr(1)));
+        assert_eq!(group.settings(1, None), SINGLE_CODEC);
+        assert!(group.remove_client(addr(2)));
+        assert_eq!(group.settings(1, None), GroupSettings::default());
+        assert!(!group.remove_client(addr(2)));
+    }
+}
@@ -4,3 +4,4 @@ pub mod dtls;
 pub mod group;
 pub mod sessions;
 pub mod stream;
+pub mod tracks;
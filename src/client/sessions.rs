@@ -1,6 +1,14 @@
+use crate::{
+    client::tracks::AnnouncedTrack,
+    dtls::{connector::DtlsRole, fingerprint::Fingerprint},
+    rtp::codec::{Codecs, MediaKind},
+};
 use actix::Message;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::SystemTime;
+use webrtc_sdp::SdpSession;
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct Session {
@@ -15,12 +23,135 @@ impl Session {
             client_user,
         }
     }
+
+    pub fn client_user(&self) -> &str {
+        &self.client_user
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionDescription {
+    pub codecs: Codecs,
+    pub plan_b: bool,
+    pub publishes: bool,
+    pub subscribes: bool,
+    pub cname: String,
+    pub stream_id: String,
+    pub track_ids: HashMap<MediaKind, String>,
+    /// Retransmission ssrcs of the publisher mapped to the ssrcs they repair.
+    pub rtx_sources: HashMap<u32, u32>,
+    pub announced: Vec<AnnouncedTrack>,
+    /// DTLS certificate fingerprints of the offer, the client certificate must match one.
+    pub fingerprints: Vec<Fingerprint>,
+    pub dtls_role: DtlsRole,
+}
+
+impl SessionDescription {
+    /// Moves the tracks announced from `from` to the publisher address `to`.
+    pub fn migrate_publisher(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+    ) -> Option<SessionDescription> {
+        if !self
+            .announced
+            .iter()
+            .any(|track| track.key.publisher == from)
+        {
+            return None;
+        }
+        let mut description = self.clone();
+        description
+            .announced
+            .iter_mut()
+            .filter(|track| track.key.publisher == from)
+            .for_each(|track| track.key.publisher = to);
+        Some(description)
+    }
+
+    /// Copy without the announced tracks of the given ssrcs.
+    pub fn without_tracks(&self, ssrcs: &[u32]) -> SessionDescription {
+        let mut description = self.clone();
+        description
+            .announced
+            .retain(|track| !ssrcs.contains(&track.ssrc));
+        description
+    }
+}
+
+pub struct SessionData {
+    pub group_id: usize,
+    pub password: String,
+    /// Origin session id of the client SDP, it is kept across ICE restarts.
+    pub remote_session_id: u64,
+    pub description: Arc<SessionDescription>,
+    pub local_description: SdpSession,
+    pub pending: Option<(SdpSession, Arc<SessionDescription>)>,
+    pub addr: Option<SocketAddr>,
+    /// Address of the session replaced by an ICE restart, until the new address is confirmed.
+    pub restarted_from: Option<SocketAddr>,
+    pub ttl: SystemTime,
 }
 
-pub struct SessionMessage(pub Session, pub usize);
+impl SessionData {
+    /// An offer of the same client restarts ICE, the client is recognized by the origin
+    /// session id of its SDP together with its DTLS certificate fingerprints.
+    pub fn is_restarted_by(
+        &self,
+        group_id: usize,
+        remote_session_id: u64,
+        fingerprints: &[Fingerprint],
+    ) -> bool {
+        let previous = &self.description.fingerprints;
+        self.group_id == group_id
+            && self.remote_session_id == remote_session_id
+            && previous.iter().all(|f| fingerprints.contains(f))
+            && fingerprints.iter().all(|f| previous.contains(f))
+    }
+}
+
+pub struct SessionMessage(pub Session, pub SessionData);
 
 impl Message for SessionMessage {
     type Result = bool;
 }
 
-pub type SessionsStorage = HashMap<Session, (usize, SystemTime)>;
+#[derive(Clone)]
+pub struct Negotiation {
+    pub session: Session,
+    pub addr: Option<SocketAddr>,
+    pub description: Arc<SessionDescription>,
+    pub local_description: SdpSession,
+    pub pending: Option<SdpSession>,
+}
+
+/// Looks up the session of a group by the client ufrag.
+pub struct NegotiationRequest(pub String, pub usize);
+
+impl Message for NegotiationRequest {
+    type Result = Option<Negotiation>;
+}
+
+pub struct NegotiationOffer(pub Session, pub SdpSession, pub Arc<SessionDescription>);
+
+impl Message for NegotiationOffer {
+    type Result = bool;
+}
+
+/// Applies the pending offer once the client answered it, with the m-lines the client
+/// rejected disabled and the ssrcs announced in them.
+pub struct NegotiationAnswer(pub Session, pub SdpSession, pub Vec<u32>);
+
+impl Message for NegotiationAnswer {
+    type Result = bool;
+}
+
+/// Looks up an established session of a group restarted by an offer with the origin session id
+/// and the fingerprints.
+pub struct RemoteSessionRequest(pub usize, pub u64, pub Vec<Fingerprint>);
+
+impl Message for RemoteSessionRequest {
+    type Result = Option<Negotiation>;
+}
+
+pub type SessionsStorage = HashMap<Session, SessionData>;
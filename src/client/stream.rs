@@ -63,8 +63,8 @@ impl ClientSslPackets {
 }
 
 impl AsyncRead for ClientSslPackets {
-    fn poll_read<'a>(
-        mut self: Pin<&'a mut Self>,
+    fn poll_read(
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
@@ -0,0 +1,611 @@
+use crate::rtp::{
+    codec::MediaKind,
+    packet::RtpPacket,
+    rtcp::{
+        Goodbye, PayloadFeedback, PayloadFeedbackMessage, RtcpPacket, SdesChunk, SourceDescription,
+        TransportFeedback, TransportFeedbackMessage,
+    },
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// A source that has not sent anything for this long gives up its slot to a new one.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Publisher and kind of media an outbound track forwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrackKey {
+    pub publisher: SocketAddr,
+    pub kind: MediaKind,
+}
+
+impl TrackKey {
+    pub fn new(publisher: SocketAddr, kind: MediaKind) -> Self {
+        TrackKey { publisher, kind }
+    }
+}
+
+/// Identifies an outbound track of the subscriber independently of the source streams
+/// forwarded into it, so the source may change without the subscriber noticing a new stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SlotId(usize);
+
+/// Stream of another group member declared to the subscriber in its SDP answer.
+#[derive(Debug, Clone)]
+pub struct AnnouncedTrack {
+    pub key: TrackKey,
+    pub ssrc: u32,
+    pub rtx_ssrc: Option<u32>,
+    pub cname: String,
+    pub stream_id: String,
+    pub track_id: String,
+}
+
+#[derive(Debug)]
+pub struct OutboundTrack {
+    pub ssrc: u32,
+    clock_rate: u32,
+    source: Option<u32>,
+    seq_offset: u16,
+    ts_offset: u32,
+    last_seq: u16,
+    last_ts: u32,
+    last_sent: Option<Instant>,
+}
+
+impl OutboundTrack {
+    pub fn new(ssrc: u32, clock_rate: u32) -> Self {
+        OutboundTrack {
+            ssrc,
+            clock_rate,
+            source: None,
+            seq_offset: 0,
+            ts_offset: 0,
+            last_seq: 0,
+            last_ts: 0,
+            last_sent: None,
+        }
+    }
+
+    pub fn rewrite<T: AsRef<[u8]> + AsMut<[u8]>>(&mut self, packet: &mut RtpPacket<T>) {
+        let (ssrc, seq, ts) = (packet.ssrc(), packet.sequence_number(), packet.timestamp());
+        if self.source != Some(ssrc) {
+            self.switch_source(ssrc, seq, ts);
+        }
+
+        let out_seq = self.outbound_sequence(seq);
+        let out_ts = self.outbound_timestamp(ts);
+        if self.last_sent.is_none() || is_newer(out_seq, self.last_seq) {
+            self.last_seq = out_seq;
+            self.last_ts = out_ts;
+            self.last_sent = Some(Instant::now());
+        }
+
+        packet.set_ssrc(self.ssrc);
+        packet.set_sequence_number(out_seq);
+        packet.set_timestamp(out_ts);
+    }
+
+    pub fn outbound_sequence(&self, seq: u16) -> u16 {
+        seq.wrapping_add(self.seq_offset)
+    }
+
+    pub fn outbound_timestamp(&self, ts: u32) -> u32 {
+        ts.wrapping_add(self.ts_offset)
+    }
+
+    pub fn original_sequence(&self, seq: u16) -> u16 {
+        seq.wrapping_sub(self.seq_offset)
+    }
+
+    /// Whether another source may be forwarded into the track.
+    fn is_free(&self) -> bool {
+        match (self.source, self.last_sent) {
+            (Some(_), Some(last_sent)) => last_sent.elapsed() > SOURCE_TIMEOUT,
+            _ => true,
+        }
+    }
+
+    fn switch_source(&mut self, ssrc: u32, seq: u16, ts: u32) {
+        if let Some(last_sent) = self.last_sent {
+            let elapsed =
+                last_sent.elapsed().as_micros() as u64 * self.clock_rate as u64 / 1_000_000;
+            let elapsed = (elapsed as u32).max(1);
+            self.seq_offset = self.last_seq.wrapping_add(1).wrapping_sub(seq);
+            self.ts_offset = self.last_ts.wrapping_add(elapsed).wrapping_sub(ts);
+        }
+        self.source = Some(ssrc);
+    }
+}
+
+/// Outbound track of the subscriber, its ssrcs stay the same while the forwarded source changes.
+#[derive(Debug)]
+struct TrackSlot {
+    key: TrackKey,
+    announced: bool,
+    media: OutboundTrack,
+    rtx: Option<OutboundTrack>,
+}
+
+/// Slot a source stream of a publisher is forwarded into.
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    slot: SlotId,
+    rtx: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Tracks {
+    slots: HashMap<SlotId, TrackSlot>,
+    routes: HashMap<(SocketAddr, u32), Route>,
+    next_slot: usize,
+}
+
+impl Tracks {
+    /// Rewrites a packet of `key.publisher` for the subscriber, `false` if it is not forwarded.
+    ///
+    /// Every source ssrc gets a slot of its own, the announced one of the publisher first.
+    /// Retransmissions go to the slot of `repaired`, the stream they carry packets of.
+    pub fn forward<T: AsRef<[u8]> + AsMut<[u8]>>(
+        &mut self,
+        key: TrackKey,
+        rtx: bool,
+        repaired: Option<u32>,
+        clock_rate: u32,
+        packet: &mut RtpPacket<T>,
+    ) -> bool {
+        let id = match self.route(key, packet.ssrc(), rtx, repaired) {
+            Some(id) => id,
+            None => return false,
+        };
+        let rtx_ssrc = match self.slots.get(&id) {
+            Some(slot) if rtx && slot.rtx.is_none() => self.unused_ssrc(),
+            Some(_) => 0,
+            None => return false,
+        };
+        let slot = self.slots.get_mut(&id).expect("slot checked above");
+
+        let track = if rtx {
+            // The payload starts with the original sequence number of the repaired packet
+            let payload = packet.payload_mut();
+            if payload.len() < 2 {
+                return false;
+            }
+            let original = NetworkEndian::read_u16(&payload[..2]);
+            NetworkEndian::write_u16(&mut payload[..2], slot.media.outbound_sequence(original));
+            slot.rtx
+                .get_or_insert_with(|| OutboundTrack::new(rtx_ssrc, clock_rate))
+        } else {
+            &mut slot.media
+        };
+        track.clock_rate = clock_rate;
+        track.rewrite(packet);
+        true
+    }
+
+    /// Binds the announced ssrcs to a slot, packets forwarded before keep their slot.
+    pub fn announce(&mut self, announced: &AnnouncedTrack) {
+        let existing = self
+            .slots
+            .iter()
+            .filter(|(_, slot)| {
+                slot.media.ssrc == announced.ssrc || (!slot.announced && slot.key == announced.key)
+            })
+            .min_by_key(|(id, slot)| (slot.media.ssrc != announced.ssrc, **id))
+            .map(|(id, _)| *id);
+        let id = existing.unwrap_or_else(|| self.insert_slot(announced.key, announced.ssrc));
+
+        let slot = self.slots.get_mut(&id).expect("slot inserted above");
+        slot.announced = true;
+        slot.media.ssrc = announced.ssrc;
+        if let Some(rtx_ssrc) = announced.rtx_ssrc {
+            slot.rtx
+                .get_or_insert_with(|| OutboundTrack::new(rtx_ssrc, 0))
+                .ssrc = rtx_ssrc;
+        }
+    }
+
+    pub fn by_ssrc(&self, ssrc: u32) -> Option<&OutboundTrack> {
+        self.slots
+            .values()
+            .flat_map(|slot| std::iter::once(&slot.media).chain(&slot.rtx))
+            .find(|track| track.ssrc == ssrc)
+    }
+
+    pub fn by_source(&self, publisher: SocketAddr, ssrc: u32) -> Option<&OutboundTrack> {
+        let route = self.routes.get(&(publisher, ssrc))?;
+        let slot = self.slots.get(&route.slot)?;
+        if route.rtx {
+            slot.rtx.as_ref()
+        } else {
+            Some(&slot.media)
+        }
+    }
+
+    /// Moves the slots and sources of a publisher to its new address after an ICE restart.
+    pub fn migrate_publisher(&mut self, from: SocketAddr, to: SocketAddr) {
+        self.slots
+            .values_mut()
+            .filter(|slot| slot.key.publisher == from)
+            .for_each(|slot| slot.key.publisher = to);
+        let routes: Vec<((SocketAddr, u32), Route)> = self
+            .routes
+            .iter()
+            .filter(|((publisher, _), _)| *publisher == from)
+            .map(|(source, route)| (*source, *route))
+            .collect();
+        for ((_, ssrc), route) in routes {
+            self.routes.remove(&(from, ssrc));
+            self.routes.insert((to, ssrc), route);
+        }
+    }
+
+    /// Removes the slots of `publisher`, returning their outbound ssrcs.
+    pub fn remove_publisher(&mut self, publisher: SocketAddr) -> Vec<u32> {
+        let mut removed = Vec::new();
+        self.slots.retain(|_, slot| {
+            if slot.key.publisher == publisher {
+                removed.push(slot.media.ssrc);
+                removed.extend(slot.rtx.as_ref().map(|rtx| rtx.ssrc));
+            }
+            slot.key.publisher != publisher
+        });
+        let slots = &self.slots;
+        self.routes.retain(|(source_publisher, _), route| {
+            *source_publisher != publisher && slots.contains_key(&route.slot)
+        });
+        removed
+    }
+
+    /// Rewrites a publisher report for the subscriber, `None` when nothing of it was forwarded yet.
+    pub fn translate_report(
+        &self,
+        publisher: SocketAddr,
+        packet: &RtcpPacket,
+    ) -> Option<RtcpPacket> {
+        let outbound = |ssrc: u32| self.by_source(publisher, ssrc);
+
+        match packet {
+            RtcpPacket::SenderReport(sr) => {
+                let track = outbound(sr.ssrc)?;
+                let mut sr = sr.clone();
+                sr.ssrc = track.ssrc;
+                sr.rtp_timestamp = track.outbound_timestamp(sr.rtp_timestamp);
+                Some(RtcpPacket::SenderReport(sr))
+            }
+            RtcpPacket::SourceDescription(sdes) => {
+                let chunks: Vec<SdesChunk> = sdes
+                    .chunks
+                    .iter()
+                    .filter_map(|chunk| {
+                        Some(SdesChunk {
+                            source: outbound(chunk.source)?.ssrc,
+                            items: chunk.items.clone(),
+                        })
+                    })
+                    .collect();
+                if chunks.is_empty() {
+                    return None;
+                }
+                Some(RtcpPacket::SourceDescription(SourceDescription { chunks }))
+            }
+            RtcpPacket::Goodbye(bye) => {
+                let sources: Vec<u32> = bye
+                    .sources
+                    .iter()
+                    .filter_map(|ssrc| Some(outbound(*ssrc)?.ssrc))
+                    .collect();
+                if sources.is_empty() {
+                    return None;
+                }
+                Some(RtcpPacket::Goodbye(Goodbye {
+                    sources,
+                    reason: bye.reason.clone(),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Rewrites feedback addressed to outbound ssrcs so it refers to the current source streams.
+    pub fn translate_feedback(&self, packet: &mut RtcpPacket) {
+        let source = |ssrc: &mut u32| {
+            if let Some(track) = self.by_ssrc(*ssrc) {
+                if let Some(source) = track.source {
+                    *ssrc = source;
+                }
+            }
+        };
+
+        match packet {
+            RtcpPacket::TransportFeedback(TransportFeedback {
+                media_ssrc,
+                message,
+                ..
+            }) => {
+                if let (TransportFeedbackMessage::Nack(nacks), Some(track)) =
+                    (message, self.by_ssrc(*media_ssrc))
+                {
+                    nacks
+                        .iter_mut()
+                        .for_each(|nack| nack.packet_id = track.original_sequence(nack.packet_id));
+                }
+                source(media_ssrc);
+            }
+            RtcpPacket::PayloadFeedback(PayloadFeedback {
+                media_ssrc,
+                message,
+                ..
+            }) => {
+                source(media_ssrc);
+                match message {
+                    PayloadFeedbackMessage::Fir(entries) => {
+                        entries.iter_mut().for_each(|entry| source(&mut entry.ssrc))
+                    }
+                    PayloadFeedbackMessage::Remb(remb) => remb.ssrcs.iter_mut().for_each(source),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn route(
+        &mut self,
+        key: TrackKey,
+        ssrc: u32,
+        rtx: bool,
+        repaired: Option<u32>,
+    ) -> Option<SlotId> {
+        if let Some(route) = self.routes.get(&(key.publisher, ssrc)) {
+            return Some(route.slot);
+        }
+        let id = if rtx {
+            self.rtx_slot(key, repaired)?
+        } else {
+            self.media_slot(key)
+        };
+        // A source taking over an idle slot replaces the one forwarded before
+        self.routes
+            .retain(|_, route| route.slot != id || route.rtx != rtx);
+        self.routes
+            .insert((key.publisher, ssrc), Route { slot: id, rtx });
+        Some(id)
+    }
+
+    fn media_slot(&mut self, key: TrackKey) -> SlotId {
+        let free = self
+            .slots
+            .iter()
+            .filter(|(_, slot)| slot.key == key && slot.media.is_free())
+            .min_by_key(|(id, slot)| (!slot.announced, **id))
+            .map(|(id, _)| *id);
+        match free {
+            Some(id) => id,
+            None => {
+                let ssrc = self.unused_ssrc();
+                self.insert_slot(key, ssrc)
+            }
+        }
+    }
+
+    /// Retransmissions of an undeclared stream go to the first slot of the publisher
+    /// which has media but no retransmissions forwarded yet.
+    fn rtx_slot(&self, key: TrackKey, repaired: Option<u32>) -> Option<SlotId> {
+        if let Some(repaired) = repaired {
+            return self
+                .routes
+                .get(&(key.publisher, repaired))
+                .map(|route| route.slot);
+        }
+        self.slots
+            .iter()
+            .filter(|(_, slot)| {
+                slot.key == key
+                    && !slot.media.is_free()
+                    && slot.rtx.iter().all(OutboundTrack::is_free)
+            })
+            .map(|(id, _)| *id)
+            .min()
+    }
+
+    fn insert_slot(&mut self, key: TrackKey, ssrc: u32) -> SlotId {
+        let id = SlotId(self.next_slot);
+        self.next_slot += 1;
+        self.slots.insert(
+            id,
+            TrackSlot {
+                key,
+                announced: false,
+                media: OutboundTrack::new(ssrc, 0),
+                rtx: None,
+            },
+        );
+        id
+    }
+
+    fn unused_ssrc(&self) -> u32 {
+        let mut rng = rand::thread_rng();
+        loop {
+            let ssrc = rng.gen_range(2, u32::MAX);
+            if self.by_ssrc(ssrc).is_none() {
+                return ssrc;
+            }
+        }
+    }
+}
+
+fn is_newer(seq: u16, previous: u16) -> bool {
+    seq != previous && seq.wrapping_sub(previous) < 0x8000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::rtcp::Nack;
+
+    const VIDEO_CLOCK: u32 = 90_000;
+
+    fn alice() -> SocketAddr {
+        "192.0.2.1:5000".parse().unwrap()
+    }
+
+    fn bob() -> SocketAddr {
+        "192.0.2.2:5000".parse().unwrap()
+    }
+
+    fn rtp(ssrc: u32, seq: u16, ts: u32, payload: &[u8]) -> RtpPacket<Vec<u8>> {
+        let mut buf = vec![0x80, 96];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(&ts.to_be_bytes());
+        buf.extend_from_slice(&ssrc.to_be_bytes());
+        buf.extend_from_slice(payload);
+        RtpPacket::new(buf).unwrap()
+    }
+
+    /// Forwards a media packet, returning its outbound ssrc and sequence number.
+    fn forward(
+        tracks: &mut Tracks,
+        publisher: SocketAddr,
+        ssrc: u32,
+        seq: u16,
+    ) -> Option<(u32, u16)> {
+        let mut packet = rtp(ssrc, seq, u32::from(seq) * 3000, &[0; 4]);
+        let key = TrackKey::new(publisher, MediaKind::Video);
+        if tracks.forward(key, false, None, VIDEO_CLOCK, &mut packet) {
+            Some((packet.ssrc(), packet.sequence_number()))
+        } else {
+            None
+        }
+    }
+
+    /// Lets every forwarded source go silent for longer than the source timeout.
+    fn idle(tracks: &mut Tracks) {
+        let silent_since = Instant::now() - SOURCE_TIMEOUT * 2;
+        tracks
+            .slots
+            .values_mut()
+            .flat_map(|slot| std::iter::once(&mut slot.media).chain(&mut slot.rtx))
+            .for_each(|track| track.last_sent = Some(silent_since));
+    }
+
+    #[test]
+    fn same_kind_sources_get_their_own_tracks() {
+        let mut tracks = Tracks::default();
+        let (camera, screen) = (
+            forward(&mut tracks, alice(), 1, 100),
+            forward(&mut tracks, alice(), 2, 5000),
+        );
+        let (camera, screen) = (camera.unwrap(), screen.unwrap());
+        assert_ne!(camera.0, screen.0);
+
+        for seq in 101..110 {
+            assert_eq!(
+                forward(&mut tracks, alice(), 1, seq).unwrap(),
+                (camera.0, seq)
+            );
+            assert_eq!(
+                forward(&mut tracks, alice(), 2, seq + 4900).unwrap(),
+                (screen.0, seq + 4900)
+            );
+        }
+        assert_eq!(tracks.by_source(alice(), 1).unwrap().ssrc, camera.0);
+        assert_eq!(tracks.by_source(alice(), 2).unwrap().ssrc, screen.0);
+    }
+
+    #[test]
+    fn announced_ssrcs_are_used_for_the_first_source() {
+        let mut tracks = Tracks::default();
+        tracks.announce(&AnnouncedTrack {
+            key: TrackKey::new(alice(), MediaKind::Video),
+            ssrc: 1111,
+            rtx_ssrc: Some(2222),
+            cname: "alice".to_string(),
+            stream_id: "stream".to_string(),
+            track_id: "track".to_string(),
+        });
+
+        assert_eq!(forward(&mut tracks, alice(), 7, 10), Some((1111, 10)));
+        let (second, _) = forward(&mut tracks, alice(), 8, 10).unwrap();
+        assert!(second != 1111 && second != 2222);
+    }
+
+    #[test]
+    fn new_source_takes_over_an_idle_track() {
+        let mut tracks = Tracks::default();
+        let (ssrc, _) = forward(&mut tracks, alice(), 1, 100).unwrap();
+        forward(&mut tracks, alice(), 1, 101).unwrap();
+
+        // A busy track is not taken over
+        let (other, _) = forward(&mut tracks, alice(), 5, 7).unwrap();
+        assert_ne!(other, ssrc);
+
+        idle(&mut tracks);
+        assert_eq!(forward(&mut tracks, alice(), 9, 40000), Some((ssrc, 102)));
+        assert_eq!(forward(&mut tracks, alice(), 9, 40001), Some((ssrc, 103)));
+        assert!(tracks.by_source(alice(), 1).is_none());
+        assert_eq!(tracks.by_source(alice(), 9).unwrap().ssrc, ssrc);
+
+        assert_eq!(tracks.remove_publisher(bob()), Vec::<u32>::new());
+        let mut removed = tracks.remove_publisher(alice());
+        removed.sort_unstable();
+        let mut expected = vec![ssrc, other];
+        expected.sort_unstable();
+        assert_eq!(removed, expected);
+        assert!(tracks.by_ssrc(ssrc).is_none());
+    }
+
+    #[test]
+    fn retransmissions_follow_the_repaired_stream() {
+        let mut tracks = Tracks::default();
+        let (ssrc, _) = forward(&mut tracks, alice(), 1, 100).unwrap();
+        forward(&mut tracks, alice(), 2, 100).unwrap();
+        idle(&mut tracks);
+        assert_eq!(forward(&mut tracks, alice(), 9, 500), Some((ssrc, 101)));
+
+        let key = TrackKey::new(alice(), MediaKind::Video);
+        let mut packet = rtp(19, 7, 0, &[0x01, 0xf4, 0xaa]);
+        assert!(tracks.forward(key, true, Some(9), VIDEO_CLOCK, &mut packet));
+        // Sequence number 500 went out as 101
+        assert_eq!(&packet.payload()[..2], &101u16.to_be_bytes());
+        let rtx = tracks.by_source(alice(), 19).unwrap().ssrc;
+        assert!(rtx != ssrc && tracks.by_ssrc(rtx).is_some());
+
+        let mut short = rtp(19, 8, 0, &[0x01]);
+        assert!(!tracks.forward(key, true, Some(9), VIDEO_CLOCK, &mut short));
+    }
+
+    #[test]
+    fn feedback_refers_to_the_source_stream() {
+        let mut tracks = Tracks::default();
+        let (ssrc, _) = forward(&mut tracks, alice(), 1, 100).unwrap();
+        idle(&mut tracks);
+        forward(&mut tracks, alice(), 9, 40000).unwrap();
+
+        let mut nack = RtcpPacket::TransportFeedback(TransportFeedback {
+            sender_ssrc: 5,
+            media_ssrc: ssrc,
+            message: TransportFeedbackMessage::Nack(vec![Nack {
+                packet_id: 101,
+                lost_bitmask: 0,
+            }]),
+        });
+        tracks.translate_feedback(&mut nack);
+        match nack {
+            RtcpPacket::TransportFeedback(TransportFeedback {
+                media_ssrc,
+                message: TransportFeedbackMessage::Nack(nacks),
+                ..
+            }) => {
+                assert_eq!(media_ssrc, 9);
+                assert_eq!(nacks[0].packet_id, 40000);
+            }
+            other => panic!("unexpected packet {:?}", other),
+        }
+    }
+}
@@ -3,23 +3,52 @@ use crate::{
     rtp::srtp::SrtpTransport,
 };
 use log::warn;
-use openssl::ssl::SslAcceptor;
+use openssl::ssl::{SslAcceptor, SslConnector};
 use std::sync::Arc;
 use tokio::time::{timeout, Duration};
-use tokio_openssl::accept;
+use tokio_openssl::{accept, connect as connect_ssl};
+
+/// Side of the DTLS handshake taken by the server, RFC 5763 section 5.
+///
+/// The server stays passive unless the offer asks it to connect with `a=setup:passive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtlsRole {
+    Active,
+    Passive,
+}
+
+// Deriving it needs `#[default]` on the variant, which is only stable since Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for DtlsRole {
+    fn default() -> Self {
+        DtlsRole::Passive
+    }
+}
 
 pub async fn connect(
     client: &mut Client,
     ssl_acceptor: Arc<SslAcceptor>,
+    ssl_connector: Arc<SslConnector>,
 ) -> Result<(), ClientError> {
-    let ssl_stream = match std::mem::replace(&mut client.state, ClientState::Shutdown) {
-        ClientState::New(stream) => timeout(Duration::from_secs(10), accept(&ssl_acceptor, stream))
-            .await
-            .map_err(|_| std::io::ErrorKind::TimedOut)?,
+    let stream = match std::mem::replace(&mut client.state, ClientState::Shutdown) {
+        ClientState::New(stream) => stream,
         ClientState::Connected(_, _) => return Err(ClientError::AlreadyConnected),
         ClientState::Shutdown => return Err(std::io::ErrorKind::WouldBlock.into()),
     };
 
+    let ssl_stream = match client.description.dtls_role {
+        DtlsRole::Passive => timeout(Duration::from_secs(10), accept(&ssl_acceptor, stream)).await,
+        DtlsRole::Active => {
+            // The peer is identified by its SDP fingerprint, there is no host name to check
+            let config = ssl_connector
+                .configure()?
+                .use_server_name_indication(false)
+                .verify_hostname(false);
+            timeout(Duration::from_secs(10), connect_ssl(config, "", stream)).await
+        }
+    }
+    .map_err(|_| std::io::ErrorKind::TimedOut)?;
+
     let ssl_stream = match ssl_stream {
         Ok(s) => s,
         Err(e) => {
@@ -28,6 +57,18 @@ pub async fn connect(
         }
     };
 
+    let verified = match ssl_stream.ssl().peer_certificate() {
+        Some(certificate) => client
+            .description
+            .fingerprints
+            .iter()
+            .any(|fingerprint| fingerprint.matches(&certificate)),
+        None => false,
+    };
+    if !verified {
+        return Err(ClientError::FingerprintMismatch);
+    }
+
     let srtp_transport = SrtpTransport::new(ssl_stream.ssl())?;
 
     client.state = ClientState::Connected(ssl_stream, srtp_transport);
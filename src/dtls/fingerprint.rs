@@ -0,0 +1,52 @@
+use openssl::{hash::MessageDigest, memcmp, x509::X509Ref};
+use webrtc_sdp::attribute_type::{SdpAttributeFingerprint, SdpAttributeFingerprintHashType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintHash {
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+/// Certificate fingerprint announced by a peer with `a=fingerprint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub hash: FingerprintHash,
+    pub value: Vec<u8>,
+}
+
+impl Fingerprint {
+    pub fn matches(&self, certificate: &X509Ref) -> bool {
+        let digest = match self.hash {
+            FingerprintHash::Sha1 => MessageDigest::sha1(),
+            FingerprintHash::Sha224 => MessageDigest::sha224(),
+            FingerprintHash::Sha256 => MessageDigest::sha256(),
+            FingerprintHash::Sha384 => MessageDigest::sha384(),
+            FingerprintHash::Sha512 => MessageDigest::sha512(),
+        };
+        match certificate.digest(digest) {
+            Ok(computed) => {
+                computed.len() == self.value.len() && memcmp::eq(&computed, &self.value)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl From<&SdpAttributeFingerprint> for Fingerprint {
+    fn from(fingerprint: &SdpAttributeFingerprint) -> Self {
+        let hash = match fingerprint.hash_algorithm {
+            SdpAttributeFingerprintHashType::Sha1 => FingerprintHash::Sha1,
+            SdpAttributeFingerprintHashType::Sha224 => FingerprintHash::Sha224,
+            SdpAttributeFingerprintHashType::Sha256 => FingerprintHash::Sha256,
+            SdpAttributeFingerprintHashType::Sha384 => FingerprintHash::Sha384,
+            SdpAttributeFingerprintHashType::Sha512 => FingerprintHash::Sha512,
+        };
+        Fingerprint {
+            hash,
+            value: fingerprint.fingerprint.clone(),
+        }
+    }
+}
@@ -3,31 +3,23 @@ use actix::Message;
 use std::net::SocketAddr;
 
 #[derive(Debug)]
-pub struct DtlsMessage(MessageType, Vec<u8>, SocketAddr);
+pub struct DtlsMessage(Vec<u8>, SocketAddr);
 impl DtlsMessage {
-    #[allow(dead_code)]
-    pub fn create_incoming(message: Vec<u8>, addr: SocketAddr) -> Self {
-        DtlsMessage(MessageType::Incoming, message, addr)
-    }
     pub fn create_outgoing(message: Vec<u8>, addr: SocketAddr) -> Self {
-        DtlsMessage(MessageType::Outgoing, message, addr)
+        DtlsMessage(message, addr)
     }
 
-    pub fn get_type(&self) -> &MessageType {
-        &self.0
+    pub fn get_addr(&self) -> SocketAddr {
+        self.1
+    }
+    pub fn redirect(self, addr: SocketAddr) -> Self {
+        DtlsMessage(self.0, addr)
     }
     pub fn into_webrtc(self) -> WebRtcRequest {
-        WebRtcRequest::Dtls(self.1, self.2)
+        WebRtcRequest::Dtls(self.0, self.1)
     }
 }
 
 impl Message for DtlsMessage {
     type Result = ();
 }
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum MessageType {
-    Incoming,
-    Outgoing,
-}
@@ -1,4 +1,5 @@
 pub mod connector;
+pub mod fingerprint;
 pub mod message;
 
 pub fn is_dtls(buf: &[u8]) -> bool {
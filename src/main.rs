@@ -1,13 +1,17 @@
 mod client;
 mod dtls;
 mod rtp;
+mod sctp;
 mod sdp;
 mod server;
 mod stun;
+mod turn;
 
 use crate::{
-    sdp::generate_streamer_response,
-    server::udp::{create_udp, UdpRecv},
+    client::group::GroupSettings,
+    sdp::{accept_streamer_answer, generate_streamer_offer, generate_streamer_response},
+    server::udp::{create_udp, ServerDataRequest, UdpRecv},
+    turn::rest_credentials,
 };
 use actix::Addr;
 use actix_files::NamedFile;
@@ -25,11 +29,14 @@ async fn main() -> std::io::Result<()> {
 
     let args: Vec<String> = std::env::args().collect();
 
-    let public_udp_addr: SocketAddr = args
+    // e.g. `0.0.0.0:3336,[::]:3336` listens on IPv4 and IPv6 sockets at once
+    let public_udp_addrs: Vec<SocketAddr> = args
         .get(1)
-        .map(|addr| addr.parse())
-        .unwrap_or_else(|| "127.0.0.1:3336".parse())
-        .expect("could not parse sdp addr");
+        .map(String::as_str)
+        .unwrap_or("127.0.0.1:3336")
+        .split(',')
+        .map(|addr| addr.trim().parse().expect("could not parse sdp addr"))
+        .collect();
 
     let session_listen_addr: SocketAddr = args
         .get(2)
@@ -37,14 +44,16 @@ async fn main() -> std::io::Result<()> {
         .unwrap_or_else(|| "127.0.0.1:3333".parse())
         .expect("could not parse session addr");
 
-    let (recv, _send) = create_udp(public_udp_addr).await;
+    let (recv, _send) = create_udp(&public_udp_addrs).await?;
 
     HttpServer::new(move || {
         App::new()
             .data(recv.clone())
-            .data(public_udp_addr)
             .service(index)
+            .service(ice_servers)
             .service(parse_sdp)
+            .service(renegotiate)
+            .service(answer)
     })
     .bind(session_listen_addr)?
     .run()
@@ -57,19 +66,120 @@ async fn index(req: HttpRequest) -> Result<NamedFile> {
     Ok(NamedFile::open("public/index.html")?)
 }
 
+/// Lists the embedded TURN relay for the example page, the relay also answers STUN binding requests.
+///
+/// Clients get ephemeral TURN REST credentials bound to their IP, only when a shared secret is
+/// configured. Static users and the secret are never sent out.
+#[get("/ice_servers/")]
+async fn ice_servers(req: HttpRequest, recv: Data<Addr<UdpRecv>>) -> Result<HttpResponse> {
+    let data = recv
+        .send(ServerDataRequest)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    let ice_servers = match (data.config.turn_port, data.candidates.first()) {
+        (Some(port), Some(candidate)) => {
+            let addr = SocketAddr::new(candidate.addr.ip(), port);
+            let mut ice_servers = vec![format!(r#"{{"urls":["stun:{}"]}}"#, addr)];
+            if let Some(secret) = &data.config.turn_secret {
+                let user = req
+                    .peer_addr()
+                    .map(|peer| peer.ip().to_string())
+                    .unwrap_or_else(|| "anonymous".into());
+                let (username, password) =
+                    rest_credentials(secret, &user, data.config.turn_credential_ttl)
+                        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+                ice_servers.push(format!(
+                    r#"{{"urls":["turn:{}?transport=udp"],"username":"{}","credential":"{}"}}"#,
+                    addr,
+                    json_escape(&username),
+                    json_escape(&password)
+                ));
+            }
+            ice_servers
+        }
+        _ => Vec::new(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(format!("[{}]", ice_servers.join(","))))
+}
+
+fn query_flag(req: &HttpRequest, name: &str) -> Result<Option<bool>> {
+    let value = req.query_string().split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), value) if key == name => Some(value.unwrap_or("1")),
+            _ => None,
+        }
+    });
+    match value {
+        None => Ok(None),
+        Some("1") | Some("true") | Some("yes") => Ok(Some(true)),
+        Some("0") | Some("false") | Some("no") => Ok(Some(false)),
+        Some(_) => Err(HttpResponse::BadRequest()
+            .body(format!("could not parse {}", name))
+            .into()),
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The offer that creates a group may set its options, e.g. `?single_codec=1` makes every member
+/// share one codec per media kind.
 #[post("/parse_sdp/{group_id}/")]
 async fn parse_sdp(
+    req: HttpRequest,
     body: Bytes,
     path_info: Path<(usize,)>,
     recv: Data<Addr<UdpRecv>>,
-    sdp_addr: Data<SocketAddr>,
 ) -> Result<HttpResponse> {
     let group_id = path_info.0;
     let body = String::from_utf8(body.to_vec()).map_err(|_| HttpResponse::BadRequest().finish())?;
+    let settings =
+        query_flag(&req, "single_codec")?.map(|single_codec| GroupSettings { single_codec });
 
-    let sdp = generate_streamer_response(&body, recv.into_inner(), group_id, **sdp_addr)
+    let sdp = generate_streamer_response(&body, recv.into_inner(), group_id, settings)
         .await
         .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
 
     Ok(sdp.to_string().replace("\r\n\r\n", "\r\n").into())
 }
+
+#[post("/renegotiate/{group_id}/")]
+async fn renegotiate(
+    body: Bytes,
+    path_info: Path<(usize,)>,
+    recv: Data<Addr<UdpRecv>>,
+) -> Result<HttpResponse> {
+    let group_id = path_info.0;
+    let body = String::from_utf8(body.to_vec()).map_err(|_| HttpResponse::BadRequest().finish())?;
+
+    let sdp = generate_streamer_offer(&body, recv.into_inner(), group_id)
+        .await
+        .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+
+    Ok(match sdp {
+        Some(sdp) => sdp.to_string().replace("\r\n\r\n", "\r\n").into(),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[post("/answer/{group_id}/")]
+async fn answer(
+    body: Bytes,
+    path_info: Path<(usize,)>,
+    recv: Data<Addr<UdpRecv>>,
+) -> Result<HttpResponse> {
+    let group_id = path_info.0;
+    let body = String::from_utf8(body.to_vec()).map_err(|_| HttpResponse::BadRequest().finish())?;
+
+    accept_streamer_answer(&body, recv.into_inner(), group_id)
+        .await
+        .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,587 @@
+use crate::rtp::srtp::ErrorParse;
+use byteorder::{ByteOrder, NetworkEndian};
+use openssl::symm::{decrypt_aead, encrypt, encrypt_aead, Cipher};
+use srtp::Error as ErrorSrtp;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+};
+
+pub const AEAD_SALT_LEN: usize = 12;
+const AEAD_TAG_LEN: usize = 16;
+const RTP_HEADER_LEN: usize = 12;
+const RTCP_HEADER_LEN: usize = 8;
+const SRTCP_INDEX_LEN: usize = 4;
+const SRTCP_E_FLAG: u32 = 0x8000_0000;
+const REPLAY_WINDOW_LEN: u64 = 64;
+
+const LABEL_RTP_ENCRYPTION: u8 = 0x00;
+const LABEL_RTP_SALT: u8 = 0x02;
+const LABEL_RTCP_ENCRYPTION: u8 = 0x03;
+const LABEL_RTCP_SALT: u8 = 0x05;
+
+/// AEAD_AES_128_GCM and AEAD_AES_256_GCM protection of RFC 7714 for one direction.
+pub struct AeadContext {
+    cipher: Cipher,
+    rtp_key: Vec<u8>,
+    rtp_salt: Vec<u8>,
+    rtcp_key: Vec<u8>,
+    rtcp_salt: Vec<u8>,
+    rtp_streams: HashMap<u32, RtpStream>,
+    rtcp_streams: HashMap<u32, RtcpStream>,
+}
+
+#[derive(Default)]
+struct RtpStream {
+    started: bool,
+    roc: u32,
+    highest_seq: u16,
+    replay: ReplayWindow,
+}
+
+#[derive(Default)]
+struct RtcpStream {
+    index: u32,
+    replay: ReplayWindow,
+}
+
+#[derive(Default)]
+struct ReplayWindow {
+    started: bool,
+    highest: u64,
+    bitmap: u64,
+}
+
+impl AeadContext {
+    /// `master_key` is 16 bytes for AES-128 and 32 bytes for AES-256, `master_salt` is 12 bytes.
+    pub fn new(master_key: &[u8], master_salt: &[u8]) -> Result<AeadContext, ErrorParse> {
+        let (cipher, kdf) = match master_key.len() {
+            16 => (Cipher::aes_128_gcm(), Cipher::aes_128_ctr()),
+            32 => (Cipher::aes_256_gcm(), Cipher::aes_256_ctr()),
+            _ => return Err(ErrorSrtp::BadParam.into()),
+        };
+        if master_salt.len() != AEAD_SALT_LEN {
+            return Err(ErrorSrtp::BadParam.into());
+        }
+
+        let derive = |label: u8, len: usize| derive_key(kdf, master_key, master_salt, label, len);
+        Ok(AeadContext::with_session_keys(
+            cipher,
+            derive(LABEL_RTP_ENCRYPTION, master_key.len())?,
+            derive(LABEL_RTP_SALT, AEAD_SALT_LEN)?,
+            derive(LABEL_RTCP_ENCRYPTION, master_key.len())?,
+            derive(LABEL_RTCP_SALT, AEAD_SALT_LEN)?,
+        ))
+    }
+
+    fn with_session_keys(
+        cipher: Cipher,
+        rtp_key: Vec<u8>,
+        rtp_salt: Vec<u8>,
+        rtcp_key: Vec<u8>,
+        rtcp_salt: Vec<u8>,
+    ) -> AeadContext {
+        AeadContext {
+            cipher,
+            rtp_key,
+            rtp_salt,
+            rtcp_key,
+            rtcp_salt,
+            rtp_streams: HashMap::new(),
+            rtcp_streams: HashMap::new(),
+        }
+    }
+
+    pub fn protect(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
+        let header_len = rtp_header_len(buf)?;
+        let ssrc = NetworkEndian::read_u32(&buf[8..12]);
+        let seq = NetworkEndian::read_u16(&buf[2..4]);
+
+        let stream = self.rtp_streams.entry(ssrc).or_default();
+        let roc = stream.estimate_roc(seq);
+        stream.update(roc, seq);
+
+        let iv = rtp_iv(&self.rtp_salt, ssrc, roc, seq);
+        let mut tag = [0; AEAD_TAG_LEN];
+        let ciphertext = encrypt_aead(
+            self.cipher,
+            &self.rtp_key,
+            Some(&iv),
+            &buf[..header_len],
+            &buf[header_len..],
+            &mut tag,
+        )?;
+
+        let mut out = Vec::with_capacity(buf.len() + AEAD_TAG_LEN);
+        out.extend_from_slice(&buf[..header_len]);
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    pub fn unprotect(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
+        let header_len = rtp_header_len(buf)?;
+        if buf.len() < header_len + AEAD_TAG_LEN {
+            return Err(ErrorParse::MalformedPacket("srtp packet is too short"));
+        }
+        let ssrc = NetworkEndian::read_u32(&buf[8..12]);
+        let seq = NetworkEndian::read_u16(&buf[2..4]);
+
+        // Streams are only created by authenticated packets, as libsrtp does
+        let new_stream = RtpStream::default();
+        let stream = self.rtp_streams.get(&ssrc).unwrap_or(&new_stream);
+        let roc = stream.estimate_roc(seq);
+        let index = (roc as u64) << 16 | seq as u64;
+        stream.replay.check(index)?;
+
+        let iv = rtp_iv(&self.rtp_salt, ssrc, roc, seq);
+        let (ciphertext, tag) = buf[header_len..].split_at(buf.len() - header_len - AEAD_TAG_LEN);
+        let payload = decrypt_aead(
+            self.cipher,
+            &self.rtp_key,
+            Some(&iv),
+            &buf[..header_len],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| ErrorSrtp::AuthFail)?;
+
+        let stream = self.rtp_streams.entry(ssrc).or_default();
+        stream.update(roc, seq);
+        stream.replay.accept(index);
+
+        let mut out = Vec::with_capacity(header_len + payload.len());
+        out.extend_from_slice(&buf[..header_len]);
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    pub fn protect_rtcp(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
+        if buf.len() < RTCP_HEADER_LEN {
+            return Err(ErrorParse::MalformedPacket("rtcp packet is too short"));
+        }
+        let ssrc = NetworkEndian::read_u32(&buf[4..8]);
+
+        let stream = self.rtcp_streams.entry(ssrc).or_default();
+        stream.index = (stream.index + 1) & !SRTCP_E_FLAG;
+        let trailer = (SRTCP_E_FLAG | stream.index).to_be_bytes();
+
+        let iv = rtcp_iv(&self.rtcp_salt, ssrc, stream.index);
+        let mut aad = buf[..RTCP_HEADER_LEN].to_vec();
+        aad.extend_from_slice(&trailer);
+        let mut tag = [0; AEAD_TAG_LEN];
+        let ciphertext = encrypt_aead(
+            self.cipher,
+            &self.rtcp_key,
+            Some(&iv),
+            &aad,
+            &buf[RTCP_HEADER_LEN..],
+            &mut tag,
+        )?;
+
+        let mut out = Vec::with_capacity(buf.len() + AEAD_TAG_LEN + SRTCP_INDEX_LEN);
+        out.extend_from_slice(&buf[..RTCP_HEADER_LEN]);
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        out.extend_from_slice(&trailer);
+        Ok(out)
+    }
+
+    pub fn unprotect_rtcp(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
+        if buf.len() < RTCP_HEADER_LEN + AEAD_TAG_LEN + SRTCP_INDEX_LEN {
+            return Err(ErrorParse::MalformedPacket("srtcp packet is too short"));
+        }
+        let ssrc = NetworkEndian::read_u32(&buf[4..8]);
+        let (packet, trailer) = buf.split_at(buf.len() - SRTCP_INDEX_LEN);
+        let trailer_value = NetworkEndian::read_u32(trailer);
+        if trailer_value & SRTCP_E_FLAG == 0 {
+            return Err(ErrorParse::UnsupportedRequest(
+                "unencrypted srtcp".to_string(),
+            ));
+        }
+        let index = trailer_value & !SRTCP_E_FLAG;
+
+        if let Some(stream) = self.rtcp_streams.get(&ssrc) {
+            stream.replay.check(index as u64)?;
+        }
+
+        let iv = rtcp_iv(&self.rtcp_salt, ssrc, index);
+        let mut aad = packet[..RTCP_HEADER_LEN].to_vec();
+        aad.extend_from_slice(trailer);
+        let (ciphertext, tag) =
+            packet[RTCP_HEADER_LEN..].split_at(packet.len() - RTCP_HEADER_LEN - AEAD_TAG_LEN);
+        let payload = decrypt_aead(
+            self.cipher,
+            &self.rtcp_key,
+            Some(&iv),
+            &aad,
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| ErrorSrtp::AuthFail)?;
+
+        let stream = self.rtcp_streams.entry(ssrc).or_default();
+        stream.replay.accept(index as u64);
+
+        let mut out = Vec::with_capacity(RTCP_HEADER_LEN + payload.len());
+        out.extend_from_slice(&packet[..RTCP_HEADER_LEN]);
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+}
+
+impl Debug for AeadContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AeadContext")
+            .field("key_len", &self.rtp_key.len())
+            .finish()
+    }
+}
+
+impl RtpStream {
+    /// Guesses the rollover counter of `seq` as in RFC 3711 appendix A.
+    fn estimate_roc(&self, seq: u16) -> u32 {
+        if !self.started {
+            return self.roc;
+        }
+        if self.highest_seq < 0x8000 {
+            if seq > self.highest_seq && seq - self.highest_seq > 0x8000 {
+                return self.roc.wrapping_sub(1);
+            }
+        } else if seq < self.highest_seq - 0x8000 {
+            return self.roc.wrapping_add(1);
+        }
+        self.roc
+    }
+
+    fn update(&mut self, roc: u32, seq: u16) {
+        if !self.started
+            || roc == self.roc.wrapping_add(1)
+            || (roc == self.roc && seq > self.highest_seq)
+        {
+            self.roc = roc;
+            self.highest_seq = seq;
+        }
+        self.started = true;
+    }
+}
+
+impl ReplayWindow {
+    fn check(&self, index: u64) -> Result<(), ErrorSrtp> {
+        if !self.started || index > self.highest {
+            return Ok(());
+        }
+        let delta = self.highest - index;
+        if delta >= REPLAY_WINDOW_LEN {
+            Err(ErrorSrtp::ReplayOld)
+        } else if self.bitmap & (1 << delta) != 0 {
+            Err(ErrorSrtp::ReplayFail)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn accept(&mut self, index: u64) {
+        if !self.started {
+            self.started = true;
+            self.highest = index;
+            self.bitmap = 1;
+        } else if index > self.highest {
+            let shift = index - self.highest;
+            self.bitmap = if shift < REPLAY_WINDOW_LEN {
+                self.bitmap << shift | 1
+            } else {
+                1
+            };
+            self.highest = index;
+        } else {
+            self.bitmap |= 1 << (self.highest - index);
+        }
+    }
+}
+
+/// AES-CM key derivation of RFC 3711 section 4.3 with the 96 bit salt padded with zeros.
+fn derive_key(
+    kdf: Cipher,
+    master_key: &[u8],
+    master_salt: &[u8],
+    label: u8,
+    len: usize,
+) -> Result<Vec<u8>, ErrorParse> {
+    let mut iv = [0; 16];
+    iv[..AEAD_SALT_LEN].copy_from_slice(master_salt);
+    iv[7] ^= label;
+    Ok(encrypt(kdf, master_key, Some(&iv), &vec![0; len])?)
+}
+
+fn rtp_header_len(buf: &[u8]) -> Result<usize, ErrorParse> {
+    const MALFORMED: ErrorParse = ErrorParse::MalformedPacket("rtp header is truncated");
+
+    if buf.len() < RTP_HEADER_LEN {
+        return Err(MALFORMED);
+    }
+    let mut len = RTP_HEADER_LEN + (buf[0] & 0x0f) as usize * 4;
+    if buf[0] & 0x10 != 0 {
+        if buf.len() < len + 4 {
+            return Err(MALFORMED);
+        }
+        len += 4 + NetworkEndian::read_u16(&buf[len + 2..len + 4]) as usize * 4;
+    }
+    if buf.len() < len {
+        return Err(MALFORMED);
+    }
+    Ok(len)
+}
+
+/// IV of RFC 7714 section 8.1, `00 00 || SSRC || ROC || SEQ` xored with the session salt.
+fn rtp_iv(salt: &[u8], ssrc: u32, roc: u32, seq: u16) -> [u8; AEAD_SALT_LEN] {
+    let mut iv = [0; AEAD_SALT_LEN];
+    NetworkEndian::write_u32(&mut iv[2..6], ssrc);
+    NetworkEndian::write_u32(&mut iv[6..10], roc);
+    NetworkEndian::write_u16(&mut iv[10..12], seq);
+    iv.iter_mut().zip(salt).for_each(|(iv, salt)| *iv ^= salt);
+    iv
+}
+
+/// IV of RFC 7714 section 9.1, `00 00 || SSRC || 00 00 || SRTCP index` xored with the salt.
+fn rtcp_iv(salt: &[u8], ssrc: u32, index: u32) -> [u8; AEAD_SALT_LEN] {
+    let mut iv = [0; AEAD_SALT_LEN];
+    NetworkEndian::write_u32(&mut iv[2..6], ssrc);
+    NetworkEndian::write_u32(&mut iv[8..12], index & !SRTCP_E_FLAG);
+    iv.iter_mut().zip(salt).for_each(|(iv, salt)| *iv ^= salt);
+    iv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Session keys and packets of RFC 7714 sections 16 and 17, both use the same key and
+    /// salt for RTP and RTCP.
+    fn rfc7714_context(key_len: usize) -> AeadContext {
+        let (cipher, key) = match key_len {
+            16 => (Cipher::aes_128_gcm(), (0..16).collect::<Vec<u8>>()),
+            _ => (Cipher::aes_256_gcm(), (0..32).collect::<Vec<u8>>()),
+        };
+        let salt = hex("517569642070726f2071756f");
+        AeadContext::with_session_keys(cipher, key.clone(), salt.clone(), key, salt)
+    }
+
+    fn rfc7714_rtp() -> Vec<u8> {
+        let mut packet = hex("8040f17b 8041f8d3 5501a0b2");
+        packet.extend_from_slice(b"Gallia est omnis divisa in partes tres");
+        packet
+    }
+
+    fn rfc7714_rtcp() -> Vec<u8> {
+        hex(
+            "81c8000d 4d617273 4e545031 4e545032 52545020 0000042a 0000e930 4c756e61
+             deadbeef deadbeef deadbeef deadbeef deadbeef",
+        )
+    }
+
+    const RFC7714_SRTCP_INDEX: u32 = 0x05d4;
+
+    const RFC7714_SRTP_128: &str = "8040f17b 8041f8d3 5501a0b2 f24de3a3 fb34de6c acba861c
+        9d7e4bca be633bd5 0d294e6f 42a5f47a 51c7d19b 36de3adf 8833899d 7f27beb1 6a9152cf
+        765ee439 0cce";
+    const RFC7714_SRTCP_128: &str = "81c8000d 4d617273 63e94885 dcdab67c a727d766 2f6b7e99
+        7ff5c0f7 6c06f32d c676a5f1 730d6fda 4ce09b46 86303ded 0bb9275b c84aa458 96cf4d2f
+        c5abf872 45d9eade 800005d4";
+    const RFC7714_SRTP_256: &str = "8040f17b 8041f8d3 5501a0b2 32b1de78 a822fe12 ef9f78fa
+        332e33aa b1801238 9a58e2f3 b50b2a02 76ffae0f 1ba63799 b87b7aa3 db36dfff d6b0f9bb
+        7878d7a7 6c13";
+    const RFC7714_SRTCP_256: &str = "81c8000d 4d617273 d50ae4d1 f5ce5d30 4ba297e4 7d470c28
+        2c3ece5d bffe0a50 a2eaa5c1 110555be 8415f658 c61de047 6f1b6fad 1d1eb30c 4446839f
+        57ff6f6c b26ac3be 800005d4";
+
+    fn check_rtp_vector(key_len: usize, expected: &str) {
+        let expected = hex(expected);
+        assert_eq!(
+            rfc7714_context(key_len).protect(&rfc7714_rtp()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            rfc7714_context(key_len).unprotect(&expected).unwrap(),
+            rfc7714_rtp()
+        );
+    }
+
+    fn check_rtcp_vector(key_len: usize, expected: &str) {
+        let expected = hex(expected);
+        let mut sender = rfc7714_context(key_len);
+        sender.rtcp_streams.insert(
+            0x4d61_7273,
+            RtcpStream {
+                index: RFC7714_SRTCP_INDEX - 1,
+                ..RtcpStream::default()
+            },
+        );
+        assert_eq!(sender.protect_rtcp(&rfc7714_rtcp()).unwrap(), expected);
+        assert_eq!(
+            rfc7714_context(key_len).unprotect_rtcp(&expected).unwrap(),
+            rfc7714_rtcp()
+        );
+    }
+
+    #[test]
+    fn rfc7714_aes_128_gcm_rtp() {
+        check_rtp_vector(16, RFC7714_SRTP_128);
+    }
+
+    #[test]
+    fn rfc7714_aes_128_gcm_rtcp() {
+        check_rtcp_vector(16, RFC7714_SRTCP_128);
+    }
+
+    #[test]
+    fn rfc7714_aes_256_gcm_rtp() {
+        check_rtp_vector(32, RFC7714_SRTP_256);
+    }
+
+    #[test]
+    fn rfc7714_aes_256_gcm_rtcp() {
+        check_rtcp_vector(32, RFC7714_SRTCP_256);
+    }
+
+    #[test]
+    fn session_keys_are_derived_from_the_master_key() {
+        let key: Vec<u8> = (0..16).collect();
+        let salt: Vec<u8> = (0xa0..0xac).collect();
+        let mut context = AeadContext::new(&key, &salt).unwrap();
+
+        let mut rtp = hex("800f1234 decafbad cafebabe");
+        rtp.extend_from_slice(&[0xab; 16]);
+        assert_eq!(
+            context.protect(&rtp).unwrap(),
+            hex(
+                "800f1234 decafbad cafebabe c5002ede 04cfdd2e b91159e0 880aa06e
+                 d2976826 f796b201 df3131a1 27e8a392"
+            )
+        );
+
+        let mut rtcp = hex("81c8000b cafebabe");
+        rtcp.extend_from_slice(&[0xab; 16]);
+        assert_eq!(
+            context.protect_rtcp(&rtcp).unwrap(),
+            hex(
+                "81c8000b cafebabe c98b8b5d f0392a55 852b6c21 ac8e7025 c52c6fbe
+                 a2b3b446 ea31123b a88ce61e 80000001"
+            )
+        );
+
+        assert!(AeadContext::new(&key[..15], &salt).is_err());
+        assert!(AeadContext::new(&key, &salt[..11]).is_err());
+    }
+
+    #[test]
+    fn replayed_and_tampered_packets_are_rejected() {
+        let expected = hex(RFC7714_SRTP_128);
+        let mut receiver = rfc7714_context(16);
+
+        let mut tampered = expected.clone();
+        tampered[20] ^= 1;
+        assert!(matches!(
+            receiver.unprotect(&tampered),
+            Err(ErrorParse::Srtp(ErrorSrtp::AuthFail))
+        ));
+        // A forged packet must not take the place of the real one in the replay window
+        assert!(receiver.unprotect(&expected).is_ok());
+        assert!(matches!(
+            receiver.unprotect(&expected),
+            Err(ErrorParse::Srtp(ErrorSrtp::ReplayFail))
+        ));
+
+        let expected = hex(RFC7714_SRTCP_128);
+        assert!(receiver.unprotect_rtcp(&expected).is_ok());
+        assert!(matches!(
+            receiver.unprotect_rtcp(&expected),
+            Err(ErrorParse::Srtp(ErrorSrtp::ReplayFail))
+        ));
+        let mut unencrypted = expected;
+        let last = unencrypted.len() - 4;
+        unencrypted[last] &= 0x7f;
+        assert!(receiver.unprotect_rtcp(&unencrypted).is_err());
+    }
+
+    #[test]
+    fn forged_packets_do_not_create_streams() {
+        let mut receiver = rfc7714_context(16);
+        for ssrc in 1..=16u32 {
+            let mut rtp = hex(RFC7714_SRTP_128);
+            rtp[8..12].copy_from_slice(&ssrc.to_be_bytes());
+            assert!(receiver.unprotect(&rtp).is_err());
+
+            let mut rtcp = hex(RFC7714_SRTCP_128);
+            rtcp[4..8].copy_from_slice(&ssrc.to_be_bytes());
+            assert!(receiver.unprotect_rtcp(&rtcp).is_err());
+        }
+        assert!(receiver.rtp_streams.is_empty());
+        assert!(receiver.rtcp_streams.is_empty());
+
+        assert!(receiver.unprotect(&hex(RFC7714_SRTP_128)).is_ok());
+        assert!(receiver.unprotect_rtcp(&hex(RFC7714_SRTCP_128)).is_ok());
+        assert_eq!(receiver.rtp_streams.len(), 1);
+        assert_eq!(receiver.rtcp_streams.len(), 1);
+    }
+
+    #[test]
+    fn replay_window_accepts_each_index_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check(1000).is_ok());
+        window.accept(1000);
+        assert!(matches!(window.check(1000), Err(ErrorSrtp::ReplayFail)));
+
+        for index in &[1001, 999, 1003] {
+            assert!(window.check(*index).is_ok());
+            window.accept(*index);
+        }
+        assert!(window.check(1002).is_ok());
+        for index in &[999, 1000, 1001, 1003] {
+            assert!(matches!(window.check(*index), Err(ErrorSrtp::ReplayFail)));
+        }
+    }
+
+    #[test]
+    fn replay_window_forgets_old_indexes() {
+        let mut window = ReplayWindow::default();
+        window.accept(100);
+        window.accept(100 + REPLAY_WINDOW_LEN - 1);
+
+        // 100 is the oldest index still in the window
+        assert!(matches!(window.check(100), Err(ErrorSrtp::ReplayFail)));
+        assert!(window.check(101).is_ok());
+        assert!(matches!(window.check(99), Err(ErrorSrtp::ReplayOld)));
+
+        window.accept(100 + REPLAY_WINDOW_LEN);
+        assert!(matches!(window.check(100), Err(ErrorSrtp::ReplayOld)));
+        assert!(window.check(101).is_ok());
+
+        // A jump past the window clears it
+        window.accept(10_000);
+        assert!(matches!(
+            window.check(10_000 - REPLAY_WINDOW_LEN),
+            Err(ErrorSrtp::ReplayOld)
+        ));
+        assert!(window.check(10_000 - 1).is_ok());
+    }
+
+    #[test]
+    fn rollover_counter_follows_the_sequence_number() {
+        let mut stream = RtpStream::default();
+        assert_eq!(stream.estimate_roc(0xfffe), 0);
+        stream.update(0, 0xfffe);
+        assert_eq!(stream.estimate_roc(0x0001), 1);
+        stream.update(1, 0x0001);
+        assert_eq!(stream.estimate_roc(0xffff), 0);
+        assert_eq!(stream.estimate_roc(0x0002), 1);
+    }
+}
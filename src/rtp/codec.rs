@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec {
+    pub payload_type: u8,
+    pub kind: MediaKind,
+    pub name: String,
+    pub clock_rate: u32,
+    pub channels: Option<u32>,
+    pub packetization_mode: u32,
+    pub apt: Option<u8>,
+}
+
+impl Codec {
+    pub fn same_format(&self, other: &Codec) -> bool {
+        self.kind == other.kind
+            && self.name.eq_ignore_ascii_case(&other.name)
+            && self.clock_rate == other.clock_rate
+            && self.channels.unwrap_or(1) == other.channels.unwrap_or(1)
+            && self.packetization_mode == other.packetization_mode
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Codecs(HashMap<u8, Codec>);
+
+impl Codecs {
+    pub fn insert(&mut self, codec: Codec) {
+        self.0.insert(codec.payload_type, codec);
+    }
+
+    pub fn get(&self, payload_type: u8) -> Option<&Codec> {
+        self.0.get(&payload_type)
+    }
+
+    pub fn find_equivalent(&self, codec: &Codec, origin: &Codecs) -> Option<&Codec> {
+        let associated = codec.apt.and_then(|apt| origin.get(apt));
+        let is_equivalent = |candidate: &&Codec| {
+            let candidate_associated = candidate.apt.and_then(|apt| self.get(apt));
+            candidate.same_format(codec)
+                && match (associated, candidate_associated) {
+                    (None, None) => true,
+                    (Some(associated), Some(candidate_associated)) => {
+                        associated.same_format(candidate_associated)
+                    }
+                    _ => false,
+                }
+        };
+
+        self.get(codec.payload_type)
+            .filter(is_equivalent)
+            .or_else(|| self.0.values().find(is_equivalent))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Codec> {
+        self.0.values()
+    }
+
+    pub fn has_kind(&self, kind: MediaKind) -> bool {
+        self.0.values().any(|codec| codec.kind == kind)
+    }
+
+    pub fn has_rtx(&self, kind: MediaKind) -> bool {
+        self.0
+            .values()
+            .any(|codec| codec.kind == kind && codec.apt.is_some())
+    }
+}
@@ -1,2 +1,6 @@
+pub mod aead;
+pub mod codec;
 pub mod core;
+pub mod packet;
+pub mod rtcp;
 pub mod srtp;
@@ -0,0 +1,428 @@
+use crate::rtp::srtp::ErrorParse;
+use byteorder::{ByteOrder, NetworkEndian};
+use std::ops::Range;
+
+pub const RTP_VERSION: u8 = 2;
+pub const RTP_HEADER_LEN: usize = 12;
+
+const ONE_BYTE_EXTENSION_PROFILE: u16 = 0xbede;
+const TWO_BYTE_EXTENSION_PROFILE_MASK: u16 = 0xfff0;
+const TWO_BYTE_EXTENSION_PROFILE: u16 = 0x1000;
+
+/// RTP packet view over any byte buffer.
+///
+/// Borrowed slices give a zero-copy reader, mutable slices and vectors additionally
+/// allow rewriting header fields and extensions in place.
+#[derive(Debug, Clone)]
+pub struct RtpPacket<T> {
+    buf: T,
+    csrc_end: usize,
+    extension: Option<Range<usize>>,
+}
+
+impl<T: AsRef<[u8]>> RtpPacket<T> {
+    pub fn new(buf: T) -> Result<RtpPacket<T>, ErrorParse> {
+        let bytes = buf.as_ref();
+        if bytes.len() < RTP_HEADER_LEN {
+            return Err(ErrorParse::MalformedPacket("rtp packet is too short"));
+        }
+        if bytes[0] >> 6 != RTP_VERSION {
+            return Err(ErrorParse::UnsupportedFormat);
+        }
+
+        let csrc_end = RTP_HEADER_LEN + (bytes[0] & 0x0f) as usize * 4;
+        if bytes.len() < csrc_end {
+            return Err(ErrorParse::MalformedPacket("rtp csrc list is truncated"));
+        }
+
+        let extension = if bytes[0] & 0x10 != 0 {
+            if bytes.len() < csrc_end + 4 {
+                return Err(ErrorParse::MalformedPacket("rtp extension is truncated"));
+            }
+            let len = NetworkEndian::read_u16(&bytes[csrc_end + 2..csrc_end + 4]) as usize * 4;
+            let range = csrc_end + 4..csrc_end + 4 + len;
+            if bytes.len() < range.end {
+                return Err(ErrorParse::MalformedPacket("rtp extension is truncated"));
+            }
+            Some(range)
+        } else {
+            None
+        };
+
+        Ok(RtpPacket {
+            buf,
+            csrc_end,
+            extension,
+        })
+    }
+
+    pub fn padding(&self) -> bool {
+        self.bytes()[0] & 0x20 != 0
+    }
+
+    pub fn payload_type(&self) -> u8 {
+        self.bytes()[1] & 0x7f
+    }
+
+    pub fn sequence_number(&self) -> u16 {
+        NetworkEndian::read_u16(&self.bytes()[2..4])
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        NetworkEndian::read_u32(&self.bytes()[4..8])
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        NetworkEndian::read_u32(&self.bytes()[8..12])
+    }
+
+    pub fn header_len(&self) -> usize {
+        self.extension
+            .as_ref()
+            .map_or(self.csrc_end, |extension| extension.end)
+    }
+
+    pub fn padding_len(&self) -> usize {
+        if !self.padding() {
+            return 0;
+        }
+        let bytes = self.bytes();
+        let padding = bytes[bytes.len() - 1] as usize;
+        if padding > bytes.len() - self.header_len() {
+            return 0;
+        }
+        padding
+    }
+
+    #[cfg(test)]
+    pub fn payload(&self) -> &[u8] {
+        let bytes = self.bytes();
+        &bytes[self.header_len()..bytes.len() - self.padding_len()]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes()
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> RtpPacket<T> {
+    pub fn set_payload_type(&mut self, payload_type: u8) {
+        let bytes = self.bytes_mut();
+        bytes[1] = (bytes[1] & 0x80) | (payload_type & 0x7f);
+    }
+
+    pub fn set_sequence_number(&mut self, sequence_number: u16) {
+        NetworkEndian::write_u16(&mut self.bytes_mut()[2..4], sequence_number);
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        NetworkEndian::write_u32(&mut self.bytes_mut()[4..8], timestamp);
+    }
+
+    pub fn set_ssrc(&mut self, ssrc: u32) {
+        NetworkEndian::write_u32(&mut self.bytes_mut()[8..12], ssrc);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let start = self.header_len();
+        let end = self.bytes().len() - self.padding_len();
+        &mut self.bytes_mut()[start..end]
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self.buf.as_mut()
+    }
+}
+
+// Header extensions are not inspected by the forwarding path yet
+#[allow(dead_code)]
+impl<T: AsRef<[u8]>> RtpPacket<T> {
+    pub fn extension_profile(&self) -> Option<u16> {
+        let extension = self.extension.as_ref()?;
+        Some(NetworkEndian::read_u16(
+            &self.bytes()[extension.start - 4..extension.start - 2],
+        ))
+    }
+
+    pub fn extensions(&self) -> HeaderExtensions<'_> {
+        let kind = self
+            .extension_profile()
+            .and_then(ExtensionKind::from_profile);
+        let data = match (&self.extension, kind) {
+            (Some(extension), Some(_)) => &self.bytes()[extension.clone()],
+            _ => &[],
+        };
+        HeaderExtensions {
+            data,
+            kind: kind.unwrap_or(ExtensionKind::OneByte),
+            offset: 0,
+        }
+    }
+
+    pub fn extension(&self, id: u8) -> Option<&[u8]> {
+        self.extensions()
+            .find(|extension| extension.id == id)
+            .map(|extension| extension.data)
+    }
+
+    fn extension_range(&self, id: u8) -> Option<Range<usize>> {
+        let start = self.extension.as_ref()?.start;
+        let mut extensions = self.extensions();
+        while let Some(extension) = extensions.next() {
+            if extension.id == id {
+                let end = start + extensions.offset;
+                return Some(end - extension.data.len()..end);
+            }
+        }
+        None
+    }
+}
+
+#[allow(dead_code)]
+impl<T: AsRef<[u8]> + AsMut<[u8]>> RtpPacket<T> {
+    pub fn extension_mut(&mut self, id: u8) -> Option<&mut [u8]> {
+        let range = self.extension_range(id)?;
+        Some(&mut self.bytes_mut()[range])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtensionKind {
+    OneByte,
+    TwoByte,
+}
+
+impl ExtensionKind {
+    fn from_profile(profile: u16) -> Option<ExtensionKind> {
+        match profile {
+            ONE_BYTE_EXTENSION_PROFILE => Some(ExtensionKind::OneByte),
+            p if p & TWO_BYTE_EXTENSION_PROFILE_MASK == TWO_BYTE_EXTENSION_PROFILE => {
+                Some(ExtensionKind::TwoByte)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderExtension<'a> {
+    pub id: u8,
+    pub data: &'a [u8],
+}
+
+/// Iterator over RFC 8285 one-byte and two-byte header extension elements.
+pub struct HeaderExtensions<'a> {
+    data: &'a [u8],
+    kind: ExtensionKind,
+    offset: usize,
+}
+
+impl<'a> Iterator for HeaderExtensions<'a> {
+    type Item = HeaderExtension<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = *self.data.get(self.offset)?;
+            if header == 0 {
+                self.offset += 1;
+                continue;
+            }
+
+            let (id, len, header_len) = match self.kind {
+                ExtensionKind::OneByte => {
+                    if header >> 4 == 15 {
+                        self.offset = self.data.len();
+                        return None;
+                    }
+                    (header >> 4, (header & 0x0f) as usize + 1, 1)
+                }
+                ExtensionKind::TwoByte => {
+                    let len = *self.data.get(self.offset + 1)? as usize;
+                    (header, len, 2)
+                }
+            };
+
+            let start = self.offset + header_len;
+            let data = self.data.get(start..start + len)?;
+            self.offset = start + len;
+            return Some(HeaderExtension { id, data });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 12] = [
+        0x80, 0xe0, 0x12, 0x34, 0x00, 0x00, 0x00, 0x64, 0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    fn packet_with_extension(profile: u16, extension: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut buf = HEADER.to_vec();
+        buf[0] |= 0x10;
+        buf.extend_from_slice(&profile.to_be_bytes());
+        buf.extend_from_slice(&((extension.len() / 4) as u16).to_be_bytes());
+        buf.extend_from_slice(extension);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn parses_header() {
+        let mut buf = HEADER.to_vec();
+        buf[0] |= 0x02;
+        buf.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        buf.extend_from_slice(b"payload");
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert!(!packet.padding());
+        assert_eq!(packet.extension_profile(), None);
+        assert_eq!(packet.payload_type(), 96);
+        assert_eq!(packet.sequence_number(), 0x1234);
+        assert_eq!(packet.timestamp(), 100);
+        assert_eq!(packet.ssrc(), 0xdead_beef);
+        assert_eq!(packet.header_len(), 20);
+        assert_eq!(packet.payload(), b"payload");
+    }
+
+    #[test]
+    fn reads_one_byte_extensions() {
+        // id 1 with two bytes, a padding byte, id 2 with one byte
+        let extension = [0x11, 0xaa, 0xbb, 0x00, 0x20, 0xcc, 0x00, 0x00];
+        let buf = packet_with_extension(ONE_BYTE_EXTENSION_PROFILE, &extension, b"data");
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.extension_profile(), Some(ONE_BYTE_EXTENSION_PROFILE));
+        assert_eq!(
+            packet.extensions().collect::<Vec<_>>(),
+            vec![
+                HeaderExtension {
+                    id: 1,
+                    data: &[0xaa, 0xbb],
+                },
+                HeaderExtension {
+                    id: 2,
+                    data: &[0xcc],
+                },
+            ]
+        );
+        assert_eq!(packet.extension(3), None);
+        assert_eq!(packet.payload(), b"data");
+    }
+
+    #[test]
+    fn reads_two_byte_extensions() {
+        let extension = [0x01, 0x00, 0x02, 0x03, 0xaa, 0xbb, 0xcc, 0x00];
+        let buf = packet_with_extension(TWO_BYTE_EXTENSION_PROFILE, &extension, &[]);
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.extension(1), Some(&[][..]));
+        assert_eq!(packet.extension(2), Some(&[0xaa, 0xbb, 0xcc][..]));
+        assert!(packet.payload().is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_extension_profile() {
+        let buf = packet_with_extension(0x1234, &[0x10, 0xaa, 0, 0], b"data");
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.extensions().count(), 0);
+        assert_eq!(packet.payload(), b"data");
+    }
+
+    #[test]
+    fn rewrites_in_place() {
+        let extension = [0x10, 0xaa, 0x21, 0xbb, 0xcc, 0x00, 0x00, 0x00];
+        let buf = packet_with_extension(ONE_BYTE_EXTENSION_PROFILE, &extension, b"data");
+
+        let mut packet = RtpPacket::new(buf).unwrap();
+        packet.set_payload_type(111);
+        packet.set_sequence_number(7);
+        packet.set_timestamp(8);
+        packet.set_ssrc(9);
+        packet.extension_mut(2).unwrap().copy_from_slice(&[1, 2]);
+        packet.payload_mut()[0] = b'D';
+
+        let packet = RtpPacket::new(packet.into_inner()).unwrap();
+        assert_eq!(packet.payload_type(), 111);
+        assert_eq!(packet.sequence_number(), 7);
+        assert_eq!(packet.timestamp(), 8);
+        assert_eq!(packet.ssrc(), 9);
+        assert_eq!(packet.extension(1), Some(&[0xaa][..]));
+        assert_eq!(packet.extension(2), Some(&[1, 2][..]));
+        assert_eq!(packet.payload(), b"Data");
+    }
+
+    #[test]
+    fn strips_padding() {
+        let mut buf = HEADER.to_vec();
+        buf[0] |= 0x20;
+        buf.extend_from_slice(&[1, 2, 3, 0, 0, 3]);
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.padding_len(), 3);
+        assert_eq!(packet.payload(), &[1, 2, 3]);
+
+        // Padding longer than the payload is ignored
+        let last = buf.len() - 1;
+        buf[last] = 10;
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.padding_len(), 0);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(matches!(
+            RtpPacket::new(&HEADER[..11]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+
+        let mut buf = HEADER.to_vec();
+        buf[0] = 0x40;
+        assert!(matches!(
+            RtpPacket::new(buf.as_slice()),
+            Err(ErrorParse::UnsupportedFormat)
+        ));
+
+        let mut buf = HEADER.to_vec();
+        buf[0] |= 0x01;
+        assert!(matches!(
+            RtpPacket::new(buf.as_slice()),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+
+        let mut buf = HEADER.to_vec();
+        buf[0] |= 0x10;
+        buf.extend_from_slice(&[0xbe, 0xde]);
+        assert!(matches!(
+            RtpPacket::new(buf.as_slice()),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+
+        let mut buf = packet_with_extension(ONE_BYTE_EXTENSION_PROFILE, &[0x10, 0xaa, 0, 0], &[]);
+        buf.truncate(buf.len() - 1);
+        assert!(matches!(
+            RtpPacket::new(buf.as_slice()),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn stops_at_truncated_extension_element() {
+        // id 1 claims four bytes but only three follow
+        let buf = packet_with_extension(ONE_BYTE_EXTENSION_PROFILE, &[0x13, 0xaa, 0xbb, 0xcc], &[]);
+
+        let packet = RtpPacket::new(buf.as_slice()).unwrap();
+        assert_eq!(packet.extensions().count(), 0);
+    }
+}
@@ -0,0 +1,1120 @@
+use crate::rtp::srtp::ErrorParse;
+use byteorder::{ByteOrder, NetworkEndian};
+
+pub const RTCP_SR: u8 = 200;
+pub const RTCP_RR: u8 = 201;
+pub const RTCP_SDES: u8 = 202;
+pub const RTCP_BYE: u8 = 203;
+pub const RTCP_APP: u8 = 204;
+pub const RTCP_RTPFB: u8 = 205;
+pub const RTCP_PSFB: u8 = 206;
+
+const RTCP_VERSION: u8 = 2;
+const RTCP_HEADER_LEN: usize = 4;
+/// Report, chunk and source counts are 5 bit fields of the header.
+const RTCP_MAX_COUNT: usize = 31;
+const SDES_MAX_ITEM_LEN: usize = 255;
+const BYE_MAX_REASON_LEN: usize = 255;
+
+const RTPFB_NACK: u8 = 1;
+const RTPFB_TRANSPORT_CC: u8 = 15;
+const PSFB_PLI: u8 = 1;
+const PSFB_FIR: u8 = 4;
+const PSFB_AFB: u8 = 15;
+const REMB_IDENTIFIER: &[u8; 4] = b"REMB";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RtcpPacket {
+    SenderReport(SenderReport),
+    ReceiverReport(ReceiverReport),
+    SourceDescription(SourceDescription),
+    Goodbye(Goodbye),
+    App(App),
+    TransportFeedback(TransportFeedback),
+    PayloadFeedback(PayloadFeedback),
+    Unknown(UnknownPacket),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    pub fraction_lost: u8,
+    pub cumulative_lost: u32,
+    pub highest_sequence: u32,
+    pub jitter: u32,
+    pub last_sender_report: u32,
+    pub delay_since_last_sender_report: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    pub ntp_timestamp: u64,
+    pub rtp_timestamp: u32,
+    pub packet_count: u32,
+    pub octet_count: u32,
+    pub reports: Vec<ReportBlock>,
+    pub extension: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiverReport {
+    pub ssrc: u32,
+    pub reports: Vec<ReportBlock>,
+    pub extension: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdesItem {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdesChunk {
+    pub source: u32,
+    pub items: Vec<SdesItem>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceDescription {
+    pub chunks: Vec<SdesChunk>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Goodbye {
+    pub sources: Vec<u32>,
+    pub reason: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct App {
+    pub subtype: u8,
+    pub ssrc: u32,
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportFeedback {
+    pub sender_ssrc: u32,
+    pub media_ssrc: u32,
+    pub message: TransportFeedbackMessage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportFeedbackMessage {
+    Nack(Vec<Nack>),
+    TransportCc(TransportCc),
+    Other(u8, Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nack {
+    pub packet_id: u16,
+    pub lost_bitmask: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportCc {
+    pub base_sequence: u16,
+    pub reference_time: i32,
+    pub feedback_count: u8,
+    pub packets: Vec<PacketStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketStatus {
+    NotReceived,
+    SmallDelta(u8),
+    LargeDelta(i16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadFeedback {
+    pub sender_ssrc: u32,
+    pub media_ssrc: u32,
+    pub message: PayloadFeedbackMessage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadFeedbackMessage {
+    Pli,
+    Fir(Vec<FirEntry>),
+    Remb(Remb),
+    Other(u8, Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirEntry {
+    pub ssrc: u32,
+    pub sequence: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remb {
+    pub bitrate: u64,
+    pub ssrcs: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownPacket {
+    pub packet_type: u8,
+    pub count: u8,
+    pub payload: Vec<u8>,
+}
+
+impl RtcpPacket {
+    /// Parses every packet of a compound packet.
+    ///
+    /// A malformed packet is skipped by its length field, parsing stops at the first header
+    /// that can not be trusted. Fails only if no packet could be parsed.
+    pub fn parse_compound(buf: &[u8]) -> Result<Vec<RtcpPacket>, ErrorParse> {
+        let mut packets = Vec::new();
+        let mut error = None;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let rest = &buf[offset..];
+            match RtcpPacket::parse(rest) {
+                Ok((packet, len)) => {
+                    packets.push(packet);
+                    offset += len;
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                    match packet_len(rest) {
+                        Some(len) => offset += len,
+                        None => break,
+                    }
+                }
+            }
+        }
+        if packets.is_empty() {
+            return Err(
+                error.unwrap_or(ErrorParse::MalformedPacket("rtcp compound packet is empty"))
+            );
+        }
+        Ok(packets)
+    }
+
+    pub fn write_compound(packets: &[RtcpPacket]) -> Vec<u8> {
+        let mut out = Vec::new();
+        packets.iter().for_each(|packet| packet.write(&mut out));
+        out
+    }
+
+    /// Parses the first packet of `buf`, returning it along with its size in bytes.
+    pub fn parse(buf: &[u8]) -> Result<(RtcpPacket, usize), ErrorParse> {
+        if buf.len() < RTCP_HEADER_LEN {
+            return Err(ErrorParse::MalformedPacket("rtcp packet is too short"));
+        }
+        if buf[0] >> 6 != RTCP_VERSION {
+            return Err(ErrorParse::UnsupportedFormat);
+        }
+
+        let padding = buf[0] & 0x20 != 0;
+        let count = buf[0] & 0x1f;
+        let packet_type = buf[1];
+        let len = (NetworkEndian::read_u16(&buf[2..4]) as usize + 1) * 4;
+        if buf.len() < len {
+            return Err(ErrorParse::MalformedPacket("rtcp packet is truncated"));
+        }
+
+        let mut body = &buf[RTCP_HEADER_LEN..len];
+        if padding {
+            let padding_len = *body.last().unwrap_or(&0) as usize;
+            if padding_len == 0 || padding_len > body.len() {
+                return Err(ErrorParse::MalformedPacket("rtcp padding is invalid"));
+            }
+            body = &body[..body.len() - padding_len];
+        }
+
+        let mut reader = Reader::new(body);
+        let packet = match packet_type {
+            RTCP_SR => RtcpPacket::SenderReport(SenderReport {
+                ssrc: reader.u32()?,
+                ntp_timestamp: reader.u64()?,
+                rtp_timestamp: reader.u32()?,
+                packet_count: reader.u32()?,
+                octet_count: reader.u32()?,
+                reports: read_report_blocks(&mut reader, count)?,
+                extension: reader.rest().to_vec(),
+            }),
+            RTCP_RR => RtcpPacket::ReceiverReport(ReceiverReport {
+                ssrc: reader.u32()?,
+                reports: read_report_blocks(&mut reader, count)?,
+                extension: reader.rest().to_vec(),
+            }),
+            RTCP_SDES => RtcpPacket::SourceDescription(SourceDescription {
+                chunks: (0..count)
+                    .map(|_| read_sdes_chunk(&mut reader))
+                    .collect::<Result<_, _>>()?,
+            }),
+            RTCP_BYE => {
+                let sources = (0..count).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+                let reason = if reader.is_empty() {
+                    None
+                } else {
+                    let len = reader.u8()? as usize;
+                    Some(reader.bytes(len)?.to_vec())
+                };
+                RtcpPacket::Goodbye(Goodbye { sources, reason })
+            }
+            RTCP_APP => {
+                let ssrc = reader.u32()?;
+                let mut name = [0; 4];
+                name.copy_from_slice(reader.bytes(4)?);
+                RtcpPacket::App(App {
+                    subtype: count,
+                    ssrc,
+                    name,
+                    data: reader.rest().to_vec(),
+                })
+            }
+            RTCP_RTPFB => {
+                let sender_ssrc = reader.u32()?;
+                let media_ssrc = reader.u32()?;
+                let message = match count {
+                    RTPFB_NACK => TransportFeedbackMessage::Nack(
+                        reader
+                            .rest()
+                            .chunks_exact(4)
+                            .map(|fci| Nack {
+                                packet_id: NetworkEndian::read_u16(&fci[0..2]),
+                                lost_bitmask: NetworkEndian::read_u16(&fci[2..4]),
+                            })
+                            .collect(),
+                    ),
+                    RTPFB_TRANSPORT_CC => {
+                        TransportFeedbackMessage::TransportCc(read_transport_cc(&mut reader)?)
+                    }
+                    fmt => TransportFeedbackMessage::Other(fmt, reader.rest().to_vec()),
+                };
+                RtcpPacket::TransportFeedback(TransportFeedback {
+                    sender_ssrc,
+                    media_ssrc,
+                    message,
+                })
+            }
+            RTCP_PSFB => {
+                let sender_ssrc = reader.u32()?;
+                let media_ssrc = reader.u32()?;
+                let message = match count {
+                    PSFB_PLI => PayloadFeedbackMessage::Pli,
+                    PSFB_FIR => PayloadFeedbackMessage::Fir(
+                        reader
+                            .rest()
+                            .chunks_exact(8)
+                            .map(|fci| FirEntry {
+                                ssrc: NetworkEndian::read_u32(&fci[0..4]),
+                                sequence: fci[4],
+                            })
+                            .collect(),
+                    ),
+                    PSFB_AFB if reader.rest().starts_with(REMB_IDENTIFIER) => {
+                        PayloadFeedbackMessage::Remb(read_remb(&mut reader)?)
+                    }
+                    fmt => PayloadFeedbackMessage::Other(fmt, reader.rest().to_vec()),
+                };
+                RtcpPacket::PayloadFeedback(PayloadFeedback {
+                    sender_ssrc,
+                    media_ssrc,
+                    message,
+                })
+            }
+            packet_type => RtcpPacket::Unknown(UnknownPacket {
+                packet_type,
+                count,
+                payload: body.to_vec(),
+            }),
+        };
+
+        Ok((packet, len))
+    }
+
+    /// Writes the packet, lists longer than the 5 bit count allows are split into several packets.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self.split() {
+            Some(packets) => packets.iter().for_each(|packet| packet.write_single(out)),
+            None => self.write_single(out),
+        }
+    }
+
+    fn split(&self) -> Option<Vec<RtcpPacket>> {
+        match self {
+            // Report blocks which do not fit into the sender report follow in receiver reports
+            RtcpPacket::SenderReport(sr) if sr.reports.len() > RTCP_MAX_COUNT => {
+                let mut reports = sr.reports.chunks(RTCP_MAX_COUNT);
+                let first = RtcpPacket::SenderReport(SenderReport {
+                    reports: reports.next()?.to_vec(),
+                    ..sr.clone()
+                });
+                Some(
+                    std::iter::once(first)
+                        .chain(reports.map(|reports| {
+                            RtcpPacket::ReceiverReport(ReceiverReport {
+                                ssrc: sr.ssrc,
+                                reports: reports.to_vec(),
+                                extension: Vec::new(),
+                            })
+                        }))
+                        .collect(),
+                )
+            }
+            RtcpPacket::ReceiverReport(rr) if rr.reports.len() > RTCP_MAX_COUNT => Some(
+                rr.reports
+                    .chunks(RTCP_MAX_COUNT)
+                    .enumerate()
+                    .map(|(i, reports)| {
+                        RtcpPacket::ReceiverReport(ReceiverReport {
+                            ssrc: rr.ssrc,
+                            reports: reports.to_vec(),
+                            extension: if i == 0 {
+                                rr.extension.clone()
+                            } else {
+                                Vec::new()
+                            },
+                        })
+                    })
+                    .collect(),
+            ),
+            RtcpPacket::SourceDescription(sdes) if sdes.chunks.len() > RTCP_MAX_COUNT => Some(
+                sdes.chunks
+                    .chunks(RTCP_MAX_COUNT)
+                    .map(|chunks| {
+                        RtcpPacket::SourceDescription(SourceDescription {
+                            chunks: chunks.to_vec(),
+                        })
+                    })
+                    .collect(),
+            ),
+            // The reason goes with the last packet
+            RtcpPacket::Goodbye(bye) if bye.sources.len() > RTCP_MAX_COUNT => {
+                let count = bye.sources.chunks(RTCP_MAX_COUNT).len();
+                Some(
+                    bye.sources
+                        .chunks(RTCP_MAX_COUNT)
+                        .enumerate()
+                        .map(|(i, sources)| {
+                            RtcpPacket::Goodbye(Goodbye {
+                                sources: sources.to_vec(),
+                                reason: bye.reason.clone().filter(|_| i + 1 == count),
+                            })
+                        })
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    fn write_single(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; RTCP_HEADER_LEN]);
+
+        let (count, packet_type) = match self {
+            RtcpPacket::SenderReport(sr) => {
+                write_u32(out, sr.ssrc);
+                write_u32(out, (sr.ntp_timestamp >> 32) as u32);
+                write_u32(out, sr.ntp_timestamp as u32);
+                write_u32(out, sr.rtp_timestamp);
+                write_u32(out, sr.packet_count);
+                write_u32(out, sr.octet_count);
+                write_report_blocks(out, &sr.reports);
+                out.extend_from_slice(&sr.extension);
+                (sr.reports.len() as u8, RTCP_SR)
+            }
+            RtcpPacket::ReceiverReport(rr) => {
+                write_u32(out, rr.ssrc);
+                write_report_blocks(out, &rr.reports);
+                out.extend_from_slice(&rr.extension);
+                (rr.reports.len() as u8, RTCP_RR)
+            }
+            RtcpPacket::SourceDescription(sdes) => {
+                sdes.chunks.iter().for_each(|chunk| {
+                    let chunk_start = out.len();
+                    write_u32(out, chunk.source);
+                    chunk.items.iter().for_each(|item| {
+                        let value = &item.value[..item.value.len().min(SDES_MAX_ITEM_LEN)];
+                        out.push(item.kind);
+                        out.push(value.len() as u8);
+                        out.extend_from_slice(value);
+                    });
+                    out.push(0);
+                    pad(out, chunk_start);
+                });
+                (sdes.chunks.len() as u8, RTCP_SDES)
+            }
+            RtcpPacket::Goodbye(bye) => {
+                bye.sources
+                    .iter()
+                    .for_each(|source| write_u32(out, *source));
+                if let Some(reason) = &bye.reason {
+                    let reason = &reason[..reason.len().min(BYE_MAX_REASON_LEN)];
+                    let reason_start = out.len();
+                    out.push(reason.len() as u8);
+                    out.extend_from_slice(reason);
+                    pad(out, reason_start);
+                }
+                (bye.sources.len() as u8, RTCP_BYE)
+            }
+            RtcpPacket::App(app) => {
+                write_u32(out, app.ssrc);
+                out.extend_from_slice(&app.name);
+                out.extend_from_slice(&app.data);
+                (app.subtype, RTCP_APP)
+            }
+            RtcpPacket::TransportFeedback(feedback) => {
+                write_u32(out, feedback.sender_ssrc);
+                write_u32(out, feedback.media_ssrc);
+                let fmt = match &feedback.message {
+                    TransportFeedbackMessage::Nack(nacks) => {
+                        nacks.iter().for_each(|nack| {
+                            write_u16(out, nack.packet_id);
+                            write_u16(out, nack.lost_bitmask);
+                        });
+                        RTPFB_NACK
+                    }
+                    TransportFeedbackMessage::TransportCc(transport_cc) => {
+                        write_transport_cc(out, transport_cc);
+                        RTPFB_TRANSPORT_CC
+                    }
+                    TransportFeedbackMessage::Other(fmt, fci) => {
+                        out.extend_from_slice(fci);
+                        *fmt
+                    }
+                };
+                (fmt, RTCP_RTPFB)
+            }
+            RtcpPacket::PayloadFeedback(feedback) => {
+                write_u32(out, feedback.sender_ssrc);
+                write_u32(out, feedback.media_ssrc);
+                let fmt = match &feedback.message {
+                    PayloadFeedbackMessage::Pli => PSFB_PLI,
+                    PayloadFeedbackMessage::Fir(entries) => {
+                        entries.iter().for_each(|entry| {
+                            write_u32(out, entry.ssrc);
+                            out.extend_from_slice(&[entry.sequence, 0, 0, 0]);
+                        });
+                        PSFB_FIR
+                    }
+                    PayloadFeedbackMessage::Remb(remb) => {
+                        write_remb(out, remb);
+                        PSFB_AFB
+                    }
+                    PayloadFeedbackMessage::Other(fmt, fci) => {
+                        out.extend_from_slice(fci);
+                        *fmt
+                    }
+                };
+                (fmt, RTCP_PSFB)
+            }
+            RtcpPacket::Unknown(unknown) => {
+                out.extend_from_slice(&unknown.payload);
+                (unknown.count, unknown.packet_type)
+            }
+        };
+
+        pad(out, start);
+        let words = (out.len() - start) / 4 - 1;
+        out[start] = (RTCP_VERSION << 6) | (count & RTCP_MAX_COUNT as u8);
+        out[start + 1] = packet_type;
+        NetworkEndian::write_u16(&mut out[start + 2..start + 4], words as u16);
+    }
+
+    pub fn media_ssrcs(&self) -> Vec<u32> {
+        match self {
+            RtcpPacket::TransportFeedback(feedback) => vec![feedback.media_ssrc],
+            RtcpPacket::PayloadFeedback(PayloadFeedback {
+                message: PayloadFeedbackMessage::Fir(entries),
+                ..
+            }) => entries.iter().map(|entry| entry.ssrc).collect(),
+            RtcpPacket::PayloadFeedback(PayloadFeedback {
+                message: PayloadFeedbackMessage::Remb(remb),
+                ..
+            }) => remb.ssrcs.clone(),
+            RtcpPacket::PayloadFeedback(feedback) => vec![feedback.media_ssrc],
+            RtcpPacket::ReceiverReport(rr) => rr.reports.iter().map(|r| r.ssrc).collect(),
+            RtcpPacket::SenderReport(sr) => sr.reports.iter().map(|r| r.ssrc).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Nack {
+    pub fn sequence_numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        std::iter::once(self.packet_id).chain(
+            (0..16)
+                .filter(move |bit| self.lost_bitmask & (1 << bit) != 0)
+                .map(move |bit| self.packet_id.wrapping_add(bit + 1)),
+        )
+    }
+}
+
+/// Length of the first packet of `buf` if its header is valid.
+fn packet_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < RTCP_HEADER_LEN || buf[0] >> 6 != RTCP_VERSION {
+        return None;
+    }
+    let len = (NetworkEndian::read_u16(&buf[2..4]) as usize + 1) * 4;
+    if buf.len() < len {
+        return None;
+    }
+    Some(len)
+}
+
+fn read_report_blocks(reader: &mut Reader, count: u8) -> Result<Vec<ReportBlock>, ErrorParse> {
+    (0..count)
+        .map(|_| {
+            Ok(ReportBlock {
+                ssrc: reader.u32()?,
+                fraction_lost: reader.u8()?,
+                cumulative_lost: reader.u24()?,
+                highest_sequence: reader.u32()?,
+                jitter: reader.u32()?,
+                last_sender_report: reader.u32()?,
+                delay_since_last_sender_report: reader.u32()?,
+            })
+        })
+        .collect()
+}
+
+fn write_report_blocks(out: &mut Vec<u8>, reports: &[ReportBlock]) {
+    reports.iter().for_each(|report| {
+        write_u32(out, report.ssrc);
+        write_u32(
+            out,
+            (u32::from(report.fraction_lost) << 24) | (report.cumulative_lost & 0x00ff_ffff),
+        );
+        write_u32(out, report.highest_sequence);
+        write_u32(out, report.jitter);
+        write_u32(out, report.last_sender_report);
+        write_u32(out, report.delay_since_last_sender_report);
+    });
+}
+
+fn read_sdes_chunk(reader: &mut Reader) -> Result<SdesChunk, ErrorParse> {
+    let chunk_start = reader.offset;
+    let source = reader.u32()?;
+    let mut items = Vec::new();
+    loop {
+        let kind = reader.u8()?;
+        if kind == 0 {
+            break;
+        }
+        let len = reader.u8()? as usize;
+        items.push(SdesItem {
+            kind,
+            value: reader.bytes(len)?.to_vec(),
+        });
+    }
+    let padding = (4 - (reader.offset - chunk_start) % 4) % 4;
+    reader.skip(padding.min(reader.rest().len()));
+    Ok(SdesChunk { source, items })
+}
+
+fn read_transport_cc(reader: &mut Reader) -> Result<TransportCc, ErrorParse> {
+    let base_sequence = reader.u16()?;
+    let status_count = reader.u16()? as usize;
+    let reference_time = reader.u24()?;
+    let reference_time = ((reference_time << 8) as i32) >> 8;
+    let feedback_count = reader.u8()?;
+
+    let mut symbols = Vec::with_capacity(status_count);
+    while symbols.len() < status_count {
+        let chunk = reader.u16()?;
+        if chunk & 0x8000 == 0 {
+            let symbol = ((chunk >> 13) & 0x03) as u8;
+            let run_length = (chunk & 0x1fff) as usize;
+            symbols.resize(symbols.len() + run_length, symbol);
+        } else if chunk & 0x4000 == 0 {
+            symbols.extend((0..14).rev().map(|bit| ((chunk >> bit) & 0x01) as u8));
+        } else {
+            symbols.extend((0..7).rev().map(|i| ((chunk >> (i * 2)) & 0x03) as u8));
+        }
+    }
+    symbols.truncate(status_count);
+
+    let packets = symbols
+        .into_iter()
+        .map(|symbol| match symbol {
+            1 => Ok(PacketStatus::SmallDelta(reader.u8()?)),
+            2 => Ok(PacketStatus::LargeDelta(reader.u16()? as i16)),
+            _ => Ok(PacketStatus::NotReceived),
+        })
+        .collect::<Result<_, ErrorParse>>()?;
+
+    Ok(TransportCc {
+        base_sequence,
+        reference_time,
+        feedback_count,
+        packets,
+    })
+}
+
+fn write_transport_cc(out: &mut Vec<u8>, transport_cc: &TransportCc) {
+    let symbols: Vec<u8> = transport_cc
+        .packets
+        .iter()
+        .map(|packet| match packet {
+            PacketStatus::NotReceived => 0,
+            PacketStatus::SmallDelta(_) => 1,
+            PacketStatus::LargeDelta(_) => 2,
+        })
+        .collect();
+
+    write_u16(out, transport_cc.base_sequence);
+    write_u16(out, symbols.len() as u16);
+    write_u32(
+        out,
+        ((transport_cc.reference_time as u32) << 8) | u32::from(transport_cc.feedback_count),
+    );
+
+    let mut offset = 0;
+    while offset < symbols.len() {
+        let symbol = symbols[offset];
+        let run_length = symbols[offset..]
+            .iter()
+            .take(0x1fff)
+            .take_while(|s| **s == symbol)
+            .count();
+        if run_length >= 7 {
+            write_u16(out, (u16::from(symbol) << 13) | run_length as u16);
+            offset += run_length;
+        } else {
+            let chunk = symbols[offset..]
+                .iter()
+                .chain(std::iter::repeat(&0))
+                .take(7)
+                .fold(0, |chunk, symbol| (chunk << 2) | u16::from(*symbol));
+            write_u16(out, 0xc000 | chunk);
+            offset += 7;
+        }
+    }
+
+    transport_cc.packets.iter().for_each(|packet| match packet {
+        PacketStatus::NotReceived => {}
+        PacketStatus::SmallDelta(delta) => out.push(*delta),
+        PacketStatus::LargeDelta(delta) => write_u16(out, *delta as u16),
+    });
+}
+
+fn read_remb(reader: &mut Reader) -> Result<Remb, ErrorParse> {
+    reader.skip(REMB_IDENTIFIER.len());
+    let ssrc_count = reader.u8()?;
+    let bitrate = reader.u24()?;
+    let exponent = bitrate >> 18;
+    let mantissa = u64::from(bitrate & 0x0003_ffff);
+    let ssrcs = (0..ssrc_count)
+        .map(|_| reader.u32())
+        .collect::<Result<_, _>>()?;
+    Ok(Remb {
+        bitrate: mantissa << exponent,
+        ssrcs,
+    })
+}
+
+fn write_remb(out: &mut Vec<u8>, remb: &Remb) {
+    let mut exponent = 0;
+    let mut mantissa = remb.bitrate;
+    while mantissa > 0x0003_ffff {
+        mantissa >>= 1;
+        exponent += 1;
+    }
+    out.extend_from_slice(REMB_IDENTIFIER);
+    write_u32(
+        out,
+        (remb.ssrcs.len() as u32) << 24 | exponent << 18 | mantissa as u32,
+    );
+    remb.ssrcs.iter().for_each(|ssrc| write_u32(out, *ssrc));
+}
+
+fn pad(out: &mut Vec<u8>, start: usize) {
+    let padding = (4 - (out.len() - start) % 4) % 4;
+    out.resize(out.len() + padding, 0);
+}
+
+fn write_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ErrorParse> {
+        let bytes = self
+            .buf
+            .get(self.offset..self.offset + len)
+            .ok_or(ErrorParse::MalformedPacket("rtcp packet is truncated"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ErrorParse> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ErrorParse> {
+        Ok(NetworkEndian::read_u16(self.bytes(2)?))
+    }
+
+    fn u24(&mut self) -> Result<u32, ErrorParse> {
+        Ok(NetworkEndian::read_u24(self.bytes(3)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, ErrorParse> {
+        Ok(NetworkEndian::read_u32(self.bytes(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ErrorParse> {
+        Ok(NetworkEndian::read_u64(self.bytes(8)?))
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.offset = (self.offset + len).min(self.buf.len());
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.offset..]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(ssrc: u32) -> ReportBlock {
+        ReportBlock {
+            ssrc,
+            fraction_lost: 12,
+            cumulative_lost: 0x01_0203,
+            highest_sequence: 70000,
+            jitter: 31,
+            last_sender_report: 0xdead_beef,
+            delay_since_last_sender_report: 655,
+        }
+    }
+
+    fn round_trip(packet: RtcpPacket) {
+        let buf = RtcpPacket::write_compound(std::slice::from_ref(&packet));
+        assert_eq!(buf.len() % 4, 0);
+        let (parsed, len) = RtcpPacket::parse(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(parsed, packet);
+    }
+
+    #[test]
+    fn sender_report_round_trip() {
+        round_trip(RtcpPacket::SenderReport(SenderReport {
+            ssrc: 1,
+            ntp_timestamp: 0x0102_0304_0506_0708,
+            rtp_timestamp: 90000,
+            packet_count: 10,
+            octet_count: 12000,
+            reports: vec![report(2), report(3)],
+            extension: vec![1, 2, 3, 4],
+        }));
+    }
+
+    #[test]
+    fn receiver_report_round_trip() {
+        round_trip(RtcpPacket::ReceiverReport(ReceiverReport {
+            ssrc: 1,
+            reports: vec![report(2)],
+            extension: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn source_description_round_trip() {
+        round_trip(RtcpPacket::SourceDescription(SourceDescription {
+            chunks: vec![
+                SdesChunk {
+                    source: 1,
+                    items: vec![SdesItem {
+                        kind: 1,
+                        value: b"cname".to_vec(),
+                    }],
+                },
+                SdesChunk {
+                    source: 2,
+                    items: vec![
+                        SdesItem {
+                            kind: 1,
+                            value: b"abc".to_vec(),
+                        },
+                        SdesItem {
+                            kind: 6,
+                            value: b"tool".to_vec(),
+                        },
+                    ],
+                },
+            ],
+        }));
+    }
+
+    #[test]
+    fn goodbye_round_trip() {
+        round_trip(RtcpPacket::Goodbye(Goodbye {
+            sources: vec![1, 2],
+            reason: Some(b"left".to_vec()),
+        }));
+        round_trip(RtcpPacket::Goodbye(Goodbye {
+            sources: vec![1],
+            reason: None,
+        }));
+    }
+
+    #[test]
+    fn app_round_trip() {
+        round_trip(RtcpPacket::App(App {
+            subtype: 3,
+            ssrc: 1,
+            name: *b"TEST",
+            data: vec![1, 2, 3, 4],
+        }));
+    }
+
+    #[test]
+    fn transport_feedback_round_trip() {
+        round_trip(RtcpPacket::TransportFeedback(TransportFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 2,
+            message: TransportFeedbackMessage::Nack(vec![Nack {
+                packet_id: 100,
+                lost_bitmask: 0b101,
+            }]),
+        }));
+
+        let mut packets = vec![PacketStatus::SmallDelta(4); 10];
+        packets.extend_from_slice(&[
+            PacketStatus::NotReceived,
+            PacketStatus::LargeDelta(-300),
+            PacketStatus::SmallDelta(0),
+        ]);
+        round_trip(RtcpPacket::TransportFeedback(TransportFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 2,
+            message: TransportFeedbackMessage::TransportCc(TransportCc {
+                base_sequence: 65530,
+                reference_time: -2,
+                feedback_count: 7,
+                packets,
+            }),
+        }));
+    }
+
+    #[test]
+    fn payload_feedback_round_trip() {
+        round_trip(RtcpPacket::PayloadFeedback(PayloadFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 2,
+            message: PayloadFeedbackMessage::Pli,
+        }));
+        round_trip(RtcpPacket::PayloadFeedback(PayloadFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 0,
+            message: PayloadFeedbackMessage::Fir(vec![FirEntry {
+                ssrc: 2,
+                sequence: 5,
+            }]),
+        }));
+        round_trip(RtcpPacket::PayloadFeedback(PayloadFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 0,
+            message: PayloadFeedbackMessage::Remb(Remb {
+                bitrate: 1_000_000,
+                ssrcs: vec![2, 3],
+            }),
+        }));
+    }
+
+    #[test]
+    fn parses_picture_loss_indication() {
+        let buf = [
+            0x81, 0xce, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+        ];
+        let packets = RtcpPacket::parse_compound(&buf).unwrap();
+        assert_eq!(
+            packets,
+            vec![RtcpPacket::PayloadFeedback(PayloadFeedback {
+                sender_ssrc: 1,
+                media_ssrc: 2,
+                message: PayloadFeedbackMessage::Pli,
+            })]
+        );
+        assert_eq!(packets[0].media_ssrcs(), vec![2]);
+    }
+
+    #[test]
+    fn nack_sequence_numbers() {
+        let nack = Nack {
+            packet_id: 65535,
+            lost_bitmask: 0b1000_0000_0000_0011,
+        };
+        assert_eq!(
+            nack.sequence_numbers().collect::<Vec<_>>(),
+            vec![65535, 0, 1, 15]
+        );
+    }
+
+    #[test]
+    fn splits_sender_report_blocks() {
+        let reports: Vec<ReportBlock> = (0..40).map(report).collect();
+        let buf = RtcpPacket::write_compound(&[RtcpPacket::SenderReport(SenderReport {
+            ssrc: 1,
+            ntp_timestamp: 2,
+            rtp_timestamp: 3,
+            packet_count: 4,
+            octet_count: 5,
+            reports: reports.clone(),
+            extension: Vec::new(),
+        })]);
+        let packets = RtcpPacket::parse_compound(&buf).unwrap();
+        assert_eq!(packets.len(), 2);
+        match (&packets[0], &packets[1]) {
+            (RtcpPacket::SenderReport(sr), RtcpPacket::ReceiverReport(rr)) => {
+                assert_eq!(sr.reports[..], reports[..31]);
+                assert_eq!(rr.ssrc, 1);
+                assert_eq!(rr.reports[..], reports[31..]);
+            }
+            packets => panic!("unexpected packets {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn splits_receiver_reports_and_goodbyes() {
+        let reports: Vec<ReportBlock> = (0..62).map(report).collect();
+        let buf = RtcpPacket::write_compound(&[
+            RtcpPacket::ReceiverReport(ReceiverReport {
+                ssrc: 1,
+                reports: reports.clone(),
+                extension: Vec::new(),
+            }),
+            RtcpPacket::Goodbye(Goodbye {
+                sources: (0..40).collect(),
+                reason: Some(b"bye".to_vec()),
+            }),
+        ]);
+        let packets = RtcpPacket::parse_compound(&buf).unwrap();
+        assert_eq!(packets.len(), 4);
+        let parsed: Vec<ReportBlock> = packets[..2]
+            .iter()
+            .flat_map(|packet| match packet {
+                RtcpPacket::ReceiverReport(rr) => rr.reports.clone(),
+                packet => panic!("unexpected packet {:?}", packet),
+            })
+            .collect();
+        assert_eq!(parsed, reports);
+        assert_eq!(
+            packets[2..],
+            [
+                RtcpPacket::Goodbye(Goodbye {
+                    sources: (0..31).collect(),
+                    reason: None,
+                }),
+                RtcpPacket::Goodbye(Goodbye {
+                    sources: (31..40).collect(),
+                    reason: Some(b"bye".to_vec()),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_malformed_packet_in_compound() {
+        let pli = RtcpPacket::PayloadFeedback(PayloadFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 2,
+            message: PayloadFeedbackMessage::Pli,
+        });
+        let mut buf = RtcpPacket::write_compound(std::slice::from_ref(&pli));
+        // A sender report with a body too short for its sender info
+        buf.extend_from_slice(&[0x80, RTCP_SR, 0x00, 0x01, 0, 0, 0, 1]);
+        pli.write(&mut buf);
+
+        assert_eq!(
+            RtcpPacket::parse_compound(&buf).unwrap(),
+            vec![pli.clone(), pli]
+        );
+    }
+
+    #[test]
+    fn stops_at_untrusted_header() {
+        let pli = RtcpPacket::PayloadFeedback(PayloadFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 2,
+            message: PayloadFeedbackMessage::Pli,
+        });
+        let mut buf = RtcpPacket::write_compound(std::slice::from_ref(&pli));
+        // Length points past the end of the buffer
+        buf.extend_from_slice(&[0x81, RTCP_PSFB, 0x00, 0x10, 0, 0, 0, 1]);
+
+        assert_eq!(RtcpPacket::parse_compound(&buf).unwrap(), vec![pli]);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(
+            RtcpPacket::parse_compound(&[]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+        assert!(matches!(
+            RtcpPacket::parse(&[0x80, RTCP_RR]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+        assert!(matches!(
+            RtcpPacket::parse(&[0x40, RTCP_RR, 0x00, 0x00]),
+            Err(ErrorParse::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            RtcpPacket::parse(&[0x80, RTCP_RR, 0x00, 0x01, 0, 0]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+        // Padding longer than the packet body
+        assert!(matches!(
+            RtcpPacket::parse(&[0xa0, RTCP_RR, 0x00, 0x01, 0, 0, 0, 9]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+        // Report count larger than the packet
+        assert!(matches!(
+            RtcpPacket::parse_compound(&[0x81, RTCP_RR, 0x00, 0x01, 0, 0, 0, 1]),
+            Err(ErrorParse::MalformedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn truncates_long_sdes_items() {
+        let buf = RtcpPacket::write_compound(&[RtcpPacket::SourceDescription(SourceDescription {
+            chunks: vec![SdesChunk {
+                source: 1,
+                items: vec![SdesItem {
+                    kind: 1,
+                    value: vec![b'a'; 300],
+                }],
+            }],
+        })]);
+        match RtcpPacket::parse_compound(&buf).unwrap().as_slice() {
+            [RtcpPacket::SourceDescription(sdes)] => {
+                assert_eq!(sdes.chunks[0].items[0].value, vec![b'a'; 255])
+            }
+            packets => panic!("unexpected packets {:?}", packets),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use bitreader::BitReaderError;
+use crate::rtp::aead::{AeadContext, AEAD_SALT_LEN};
 use bytes::BytesMut;
 use openssl::{error::ErrorStack, ssl::SslRef};
 use srtp::{CryptoPolicy, Error as ErrorSrtp, Srtp, SsrcType};
@@ -9,60 +9,130 @@ use std::{
 
 #[derive(Debug)]
 pub struct SrtpTransport {
-    client: Srtp,
-    server: Srtp,
+    incoming: SrtpContext,
+    outgoing: SrtpContext,
+}
+
+/// AES-CM profiles are served by libsrtp, AEAD GCM profiles by `AeadContext`.
+#[derive(Debug)]
+enum SrtpContext {
+    Libsrtp(Srtp),
+    Aead(Box<AeadContext>),
 }
 
 impl SrtpTransport {
     pub fn new(ssl: &SslRef) -> Result<SrtpTransport, ErrorParse> {
-        let (rtp_policy, rtcp_policy) = match ssl.selected_srtp_profile() {
-            Some(profile) if profile.name() == "SRTP_AES128_CM_SHA1_80" => (
+        let profile = match ssl.selected_srtp_profile() {
+            Some(profile) => profile.name(),
+            None => return Err(ErrorParse::UnsupportedProfile("empty".to_string())),
+        };
+
+        match profile {
+            "SRTP_AES128_CM_SHA1_80" => SrtpTransport::libsrtp(
+                ssl,
                 CryptoPolicy::AesCm128HmacSha1Bit80,
                 CryptoPolicy::AesCm128HmacSha1Bit80,
             ),
-            Some(profile) => {
-                return Err(ErrorParse::UnsupportedProfile(profile.name().to_string()))
-            }
-            None => return Err(ErrorParse::UnsupportedProfile("empty".to_string())),
-        };
+            // RFC 5764 keeps the 80 bit tag for SRTCP
+            "SRTP_AES128_CM_SHA1_32" => SrtpTransport::libsrtp(
+                ssl,
+                CryptoPolicy::AesCm128HmacSha1Bit32,
+                CryptoPolicy::AesCm128HmacSha1Bit80,
+            ),
+            "SRTP_AEAD_AES_128_GCM" => SrtpTransport::aead(ssl, 16),
+            "SRTP_AEAD_AES_256_GCM" => SrtpTransport::aead(ssl, 32),
+            profile => Err(ErrorParse::UnsupportedProfile(profile.to_string())),
+        }
+    }
 
+    fn libsrtp(
+        ssl: &SslRef,
+        rtp_policy: CryptoPolicy,
+        rtcp_policy: CryptoPolicy,
+    ) -> Result<SrtpTransport, ErrorParse> {
         let mut dtls_buf = vec![0; rtp_policy.master_len() * 2];
         ssl.export_keying_material(dtls_buf.as_mut_slice(), "EXTRACTOR-dtls_srtp", None)?;
 
         let pair = rtp_policy.extract_keying_material(dtls_buf.as_mut_slice());
+        let (incoming, outgoing) = if ssl.is_server() {
+            (pair.client, pair.server)
+        } else {
+            (pair.server, pair.client)
+        };
 
-        let srtp_incoming = Srtp::new(SsrcType::AnyInbound, rtp_policy, rtcp_policy, pair.client)?;
-        let srtp_outcoming =
-            Srtp::new(SsrcType::AnyOutbound, rtp_policy, rtcp_policy, pair.server)?;
+        let srtp_incoming = Srtp::new(SsrcType::AnyInbound, rtp_policy, rtcp_policy, incoming)?;
+        let srtp_outcoming = Srtp::new(SsrcType::AnyOutbound, rtp_policy, rtcp_policy, outgoing)?;
 
         Ok(SrtpTransport {
-            client: srtp_incoming,
-            server: srtp_outcoming,
+            incoming: SrtpContext::Libsrtp(srtp_incoming),
+            outgoing: SrtpContext::Libsrtp(srtp_outcoming),
+        })
+    }
+
+    /// Keying material is laid out as client key, server key, client salt and server salt.
+    fn aead(ssl: &SslRef, key_len: usize) -> Result<SrtpTransport, ErrorParse> {
+        let mut dtls_buf = vec![0; (key_len + AEAD_SALT_LEN) * 2];
+        ssl.export_keying_material(dtls_buf.as_mut_slice(), "EXTRACTOR-dtls_srtp", None)?;
+
+        let (keys, salts) = dtls_buf.split_at(key_len * 2);
+        let (client_key, server_key) = keys.split_at(key_len);
+        let (client_salt, server_salt) = salts.split_at(AEAD_SALT_LEN);
+        let client = AeadContext::new(client_key, client_salt)?;
+        let server = AeadContext::new(server_key, server_salt)?;
+        let (incoming, outgoing) = if ssl.is_server() {
+            (client, server)
+        } else {
+            (server, client)
+        };
+
+        Ok(SrtpTransport {
+            incoming: SrtpContext::Aead(Box::new(incoming)),
+            outgoing: SrtpContext::Aead(Box::new(outgoing)),
         })
     }
 
     pub fn protect(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
-        let mut buf = BytesMut::from(buf);
-        self.server.protect(&mut buf)?;
-        Ok(buf.to_vec())
+        match &mut self.outgoing {
+            SrtpContext::Libsrtp(srtp) => {
+                let mut buf = BytesMut::from(buf);
+                srtp.protect(&mut buf)?;
+                Ok(buf.to_vec())
+            }
+            SrtpContext::Aead(aead) => aead.protect(buf),
+        }
     }
 
     pub fn protect_rtcp(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
-        let mut buf = BytesMut::from(buf);
-        self.server.protect_rtcp(&mut buf)?;
-        Ok(buf.to_vec())
+        match &mut self.outgoing {
+            SrtpContext::Libsrtp(srtp) => {
+                let mut buf = BytesMut::from(buf);
+                srtp.protect_rtcp(&mut buf)?;
+                Ok(buf.to_vec())
+            }
+            SrtpContext::Aead(aead) => aead.protect_rtcp(buf),
+        }
     }
 
     pub fn unprotect(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
-        let mut buf = BytesMut::from(buf);
-        self.client.unprotect(&mut buf)?;
-        Ok(buf.to_vec())
+        match &mut self.incoming {
+            SrtpContext::Libsrtp(srtp) => {
+                let mut buf = BytesMut::from(buf);
+                srtp.unprotect(&mut buf)?;
+                Ok(buf.to_vec())
+            }
+            SrtpContext::Aead(aead) => aead.unprotect(buf),
+        }
     }
 
     pub fn unprotect_rctp(&mut self, buf: &[u8]) -> Result<Vec<u8>, ErrorParse> {
-        let mut buf = BytesMut::from(buf);
-        self.client.unprotect_rtcp(&mut buf)?;
-        Ok(buf.to_vec())
+        match &mut self.incoming {
+            SrtpContext::Libsrtp(srtp) => {
+                let mut buf = BytesMut::from(buf);
+                srtp.unprotect_rtcp(&mut buf)?;
+                Ok(buf.to_vec())
+            }
+            SrtpContext::Aead(aead) => aead.unprotect_rtcp(buf),
+        }
     }
 }
 
@@ -73,6 +143,7 @@ pub enum ErrorParse {
     UnsupportedProfile(String),
     UnsupportedRequest(String),
     UnsupportedFormat,
+    MalformedPacket(&'static str),
 }
 
 impl ErrorParse {
@@ -89,6 +160,7 @@ impl Display for ErrorParse {
             ErrorParse::UnsupportedProfile(e) => write!(f, "Unsupported profile: {}", e),
             ErrorParse::UnsupportedRequest(e) => write!(f, "Unsupported request: {}", e),
             ErrorParse::UnsupportedFormat => write!(f, "Unsupported format: its ok"),
+            ErrorParse::MalformedPacket(e) => write!(f, "Malformed packet: {}", e),
         }
     }
 }
@@ -106,9 +178,3 @@ impl From<ErrorSrtp> for ErrorParse {
         ErrorParse::Srtp(e)
     }
 }
-
-impl From<BitReaderError> for ErrorParse {
-    fn from(_e: BitReaderError) -> Self {
-        ErrorParse::UnsupportedFormat
-    }
-}
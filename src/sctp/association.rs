@@ -0,0 +1,1232 @@
+use crate::sctp::{
+    packet::{
+        write_packet, Chunk, DataChunk, ForwardTsnChunk, InitChunk, Parameter, SackChunk,
+        SctpPacket, CAUSE_INVALID_STREAM, CHUNK_FORWARD_TSN, CHUNK_RECONFIG, COMMON_HEADER_LEN,
+        DATA_CHUNK_HEADER_LEN, PARAM_FORWARD_TSN_SUPPORTED, PARAM_OUTGOING_RESET_REQUEST,
+        PARAM_RECONFIG_RESPONSE, PARAM_STATE_COOKIE, PARAM_SUPPORTED_EXTENSIONS,
+    },
+    SctpError,
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// SCTP packets are carried in DTLS records and have to fit the path MTU, RFC 8261 section 5.
+const MAX_PACKET_LEN: usize = 1200;
+const MAX_FRAGMENT_LEN: usize = MAX_PACKET_LEN - COMMON_HEADER_LEN - DATA_CHUNK_HEADER_LEN;
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// Ordered messages waiting for a missing predecessor count against the window, a peer
+/// sending more of them than it has been offered aborts the association.
+const RECEIVE_WINDOW: u32 = 1024 * 1024;
+/// Chunks received further ahead of the cumulative TSN are dropped.
+const MAX_RECEIVE_GAP: u64 = 4096;
+const MAX_STREAMS: u16 = u16::MAX;
+
+const RTO_INITIAL: Duration = Duration::from_secs(1);
+const RTO_MAX: Duration = Duration::from_secs(10);
+const FAST_RETRANSMIT_MISSES: u8 = 3;
+/// Association.Max.Retrans of RFC 4960, a reliable chunk retransmitted more often closes the association.
+const MAX_RETRANSMITS: u32 = 10;
+
+const RECONFIG_RESULT_PERFORMED: u32 = 1;
+const RECONFIG_RESULT_BAD_SEQUENCE: u32 = 5;
+
+/// Extended TSNs start above zero, so that the TSN preceding the initial one is still positive.
+const TSN_OFFSET: u64 = 1 << 32;
+
+/// Partial reliability policies of RFC 3758, the data channel type picks one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reliability {
+    Reliable,
+    MaxRetransmits(u32),
+    MaxLifetime(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SctpMessage {
+    pub stream_id: u16,
+    pub ppid: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SctpEvent {
+    Message(SctpMessage),
+    /// The peer has reset its outgoing stream, the data channel on it is closed.
+    StreamReset(u16),
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    /// INIT ACK is sent, the association is established by a COOKIE ECHO with its cookie.
+    CookieSent,
+    Established,
+}
+
+#[derive(Debug)]
+struct Outstanding {
+    tsn: u64,
+    chunk: DataChunk,
+    message: u64,
+    reliability: Reliability,
+    queued_at: Instant,
+    sent_at: Option<Instant>,
+    retransmits: u32,
+    misses: u8,
+    retransmit: bool,
+    acked: bool,
+    abandoned: bool,
+}
+
+#[derive(Debug)]
+struct ResetRequest {
+    sequence: u32,
+    last_tsn: u32,
+    streams: Vec<u16>,
+    sent_at: Option<Instant>,
+}
+
+/// Server side of an SCTP association over DTLS, the peer always sends the INIT.
+///
+/// The association is driven by `receive` and `handle_timeout`, the packets to send are taken
+/// with `poll_transmit` and the delivered messages with `take_events`.
+#[derive(Debug)]
+pub struct Association {
+    state: State,
+    local_port: u16,
+    remote_port: u16,
+    local_tag: u32,
+    remote_tag: u32,
+    cookie: Vec<u8>,
+    inbound_streams: u16,
+
+    remote_cumulative_tsn: u64,
+    received: BTreeSet<u64>,
+    duplicates: Vec<u32>,
+    fragments: BTreeMap<u64, DataChunk>,
+    next_ssn: HashMap<u16, u16>,
+    ordered: HashMap<(u16, u16), SctpMessage>,
+    ordered_bytes: usize,
+    sack_needed: bool,
+
+    next_tsn: u64,
+    cumulative_tsn_ack: u64,
+    next_message: u64,
+    outgoing_ssn: HashMap<u16, u16>,
+    outstanding: VecDeque<Outstanding>,
+    peer_rwnd: u32,
+    rto: Duration,
+    retransmit_deadline: Option<Instant>,
+    forward_tsn_needed: bool,
+
+    reconfig_sequence: u32,
+    peer_reconfig_sequence: u32,
+    reset_queue: Vec<u16>,
+    reset_request: Option<ResetRequest>,
+
+    /// Association offered to a peer that sent an INIT while established, it replaces this one
+    /// when the peer echoes its cookie.
+    restart: Option<Box<Association>>,
+
+    control: Vec<Chunk>,
+    events: Vec<SctpEvent>,
+}
+
+impl Default for Association {
+    fn default() -> Self {
+        Association {
+            state: State::Closed,
+            local_port: 0,
+            remote_port: 0,
+            local_tag: 0,
+            remote_tag: 0,
+            cookie: Vec::new(),
+            inbound_streams: 0,
+            remote_cumulative_tsn: TSN_OFFSET,
+            received: BTreeSet::new(),
+            duplicates: Vec::new(),
+            fragments: BTreeMap::new(),
+            next_ssn: HashMap::new(),
+            ordered: HashMap::new(),
+            ordered_bytes: 0,
+            sack_needed: false,
+            next_tsn: TSN_OFFSET,
+            cumulative_tsn_ack: TSN_OFFSET,
+            next_message: 0,
+            outgoing_ssn: HashMap::new(),
+            outstanding: VecDeque::new(),
+            peer_rwnd: 0,
+            rto: RTO_INITIAL,
+            retransmit_deadline: None,
+            forward_tsn_needed: false,
+            reconfig_sequence: 0,
+            peer_reconfig_sequence: 0,
+            reset_queue: Vec::new(),
+            reset_request: None,
+            restart: None,
+            control: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Association {
+    pub fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    pub fn take_events(&mut self) -> Vec<SctpEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn receive(&mut self, buf: &[u8], now: Instant) -> Result<(), SctpError> {
+        let packet = SctpPacket::parse(buf)?;
+        let is_init = packet.chunks.iter().any(|c| matches!(c, Chunk::Init(_)));
+        if !is_init
+            && matches!(&self.restart, Some(restart) if restart.local_tag == packet.verification_tag)
+        {
+            self.restart(&packet.chunks);
+        }
+        let expected_tag = if is_init { 0 } else { self.local_tag };
+        if packet.verification_tag != expected_tag || (!is_init && self.state == State::Closed) {
+            return Ok(());
+        }
+
+        for chunk in packet.chunks {
+            match chunk {
+                Chunk::Init(init) => {
+                    self.handle_init(packet.source_port, packet.destination_port, init)
+                }
+                Chunk::CookieEcho(cookie) if cookie == self.cookie => {
+                    self.state = State::Established;
+                    self.control.push(Chunk::CookieAck);
+                }
+                Chunk::Data(data) if self.state == State::Established => self.handle_data(data),
+                Chunk::Sack(sack) if self.state == State::Established => {
+                    self.handle_sack(sack, now)
+                }
+                Chunk::ForwardTsn(forward) if self.state == State::Established => {
+                    self.handle_forward_tsn(forward)
+                }
+                Chunk::Reconfig(parameters) if self.state == State::Established => {
+                    self.handle_reconfig(parameters)
+                }
+                Chunk::Heartbeat(info) => self.control.push(Chunk::HeartbeatAck(info)),
+                Chunk::Abort => {
+                    self.close();
+                    return Ok(());
+                }
+                Chunk::Shutdown => {
+                    self.control.push(Chunk::ShutdownAck);
+                    self.close();
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a message, it is split into DATA chunks that fit into a packet.
+    pub fn send(
+        &mut self,
+        stream_id: u16,
+        ppid: u32,
+        payload: &[u8],
+        unordered: bool,
+        reliability: Reliability,
+        now: Instant,
+    ) -> Result<(), SctpError> {
+        if self.state != State::Established {
+            return Err(SctpError::NotEstablished);
+        }
+        if payload.is_empty() {
+            return Err(SctpError::MalformedMessage("message is empty"));
+        }
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(SctpError::MessageTooLarge(payload.len()));
+        }
+
+        let ssn = if unordered {
+            0
+        } else {
+            let ssn = self.outgoing_ssn.entry(stream_id).or_insert(0);
+            let current = *ssn;
+            *ssn = ssn.wrapping_add(1);
+            current
+        };
+        let message = self.next_message;
+        self.next_message += 1;
+
+        let fragments = payload.chunks(MAX_FRAGMENT_LEN).len();
+        for (i, fragment) in payload.chunks(MAX_FRAGMENT_LEN).enumerate() {
+            let tsn = self.next_tsn;
+            self.next_tsn += 1;
+            self.outstanding.push_back(Outstanding {
+                tsn,
+                chunk: DataChunk {
+                    tsn: tsn as u32,
+                    stream_id,
+                    ssn,
+                    ppid,
+                    unordered,
+                    beginning: i == 0,
+                    ending: i + 1 == fragments,
+                    payload: fragment.to_vec(),
+                },
+                message,
+                reliability,
+                queued_at: now,
+                sent_at: None,
+                retransmits: 0,
+                misses: 0,
+                retransmit: false,
+                acked: false,
+                abandoned: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// Resets the outgoing stream, the peer closes the data channel on it, RFC 8831 section 6.7.
+    pub fn reset_stream(&mut self, stream_id: u16) {
+        if self.state == State::Established && !self.reset_queue.contains(&stream_id) {
+            self.reset_queue.push(stream_id);
+        }
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.state != State::Established {
+            return;
+        }
+
+        let expired: Vec<u64> = self
+            .outstanding
+            .iter()
+            .filter(|o| !o.acked && !o.abandoned)
+            .filter(|o| matches!(o.reliability, Reliability::MaxLifetime(lifetime) if o.queued_at + lifetime <= now))
+            .map(|o| o.message)
+            .collect();
+        expired
+            .into_iter()
+            .for_each(|message| self.abandon(message));
+
+        if let Some(request) = &mut self.reset_request {
+            if matches!(request.sent_at, Some(sent_at) if sent_at + self.rto <= now) {
+                request.sent_at = None;
+            }
+        }
+
+        match self.retransmit_deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        let mut in_flight = false;
+        for o in self.outstanding.iter_mut() {
+            if o.sent_at.is_some() && !o.acked && !o.abandoned {
+                o.retransmit = true;
+                in_flight = true;
+            }
+        }
+        if matches!(self.outstanding.front(), Some(o) if o.abandoned) {
+            self.forward_tsn_needed = true;
+        }
+        if in_flight || self.forward_tsn_needed {
+            self.rto = (self.rto * 2).min(RTO_MAX);
+            self.retransmit_deadline = Some(now + self.rto);
+        } else {
+            self.retransmit_deadline = None;
+        }
+    }
+
+    /// Collects the chunks waiting to be sent and bundles them into packets.
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut chunks = std::mem::take(&mut self.control);
+        if self.sack_needed {
+            self.sack_needed = false;
+            chunks.push(self.sack());
+        }
+        if self.state == State::Established {
+            self.transmit_data(&mut chunks, now);
+            self.transmit_reset(&mut chunks, now);
+        }
+        self.bundle(chunks)
+    }
+
+    fn handle_init(&mut self, source_port: u16, destination_port: u16, init: InitChunk) {
+        if init.initiate_tag == 0 {
+            return;
+        }
+
+        if self.state != State::Established {
+            let association = Association::initiated(source_port, destination_port, init);
+            *self = Association {
+                events: std::mem::take(&mut self.events),
+                ..association
+            };
+            return;
+        }
+        // A restarted peer comes with a new tag, RFC 4960 section 5.2.4, so an INIT with the
+        // current one cannot replace the association
+        if init.initiate_tag == self.remote_tag {
+            return;
+        }
+        // The INIT ACK has a new tag and the association keeps running until the peer proves
+        // it has received it, RFC 4960 section 5.2.2
+        let mut association = Association::initiated(source_port, destination_port, init);
+        self.control.append(&mut association.control);
+        self.restart = Some(Box::new(association));
+    }
+
+    /// Switches to the offered association when the packet echoes its cookie, the data channels
+    /// of the replaced one are closed.
+    fn restart(&mut self, chunks: &[Chunk]) {
+        let restart = match self.restart.take() {
+            Some(restart)
+                if chunks.iter().any(
+                    |c| matches!(c, Chunk::CookieEcho(cookie) if *cookie == restart.cookie),
+                ) =>
+            {
+                restart
+            }
+            restart => {
+                self.restart = restart;
+                return;
+            }
+        };
+        self.close();
+        *self = Association {
+            events: std::mem::take(&mut self.events),
+            ..*restart
+        };
+    }
+
+    /// Answers the INIT with an INIT ACK, the association is established by the COOKIE ECHO.
+    fn initiated(source_port: u16, destination_port: u16, init: InitChunk) -> Association {
+        let mut rng = rand::thread_rng();
+        let local_tag = loop {
+            let tag: u32 = rng.gen();
+            if tag != 0 {
+                break tag;
+            }
+        };
+        let initial_tsn: u32 = rng.gen();
+        let cookie: [u8; 32] = rng.gen();
+
+        let init_ack = Chunk::InitAck(InitChunk {
+            initiate_tag: local_tag,
+            a_rwnd: RECEIVE_WINDOW,
+            outbound_streams: init.inbound_streams,
+            inbound_streams: MAX_STREAMS,
+            initial_tsn,
+            parameters: vec![
+                Parameter::new(PARAM_STATE_COOKIE, cookie.to_vec()),
+                Parameter::new(
+                    PARAM_SUPPORTED_EXTENSIONS,
+                    vec![CHUNK_RECONFIG, CHUNK_FORWARD_TSN],
+                ),
+                Parameter::new(PARAM_FORWARD_TSN_SUPPORTED, Vec::new()),
+            ],
+        });
+        Association {
+            state: State::CookieSent,
+            local_port: destination_port,
+            remote_port: source_port,
+            local_tag,
+            remote_tag: init.initiate_tag,
+            cookie: cookie.to_vec(),
+            inbound_streams: init.outbound_streams,
+            remote_cumulative_tsn: TSN_OFFSET + init.initial_tsn as u64 - 1,
+            next_tsn: TSN_OFFSET + initial_tsn as u64,
+            cumulative_tsn_ack: TSN_OFFSET + initial_tsn as u64 - 1,
+            peer_rwnd: init.a_rwnd,
+            reconfig_sequence: initial_tsn,
+            peer_reconfig_sequence: init.initial_tsn.wrapping_sub(1),
+            control: vec![init_ack],
+            ..Association::default()
+        }
+    }
+
+    fn handle_data(&mut self, data: DataChunk) {
+        self.sack_needed = true;
+        let tsn = unwrap_tsn(self.remote_cumulative_tsn, data.tsn);
+        if tsn <= self.remote_cumulative_tsn || self.received.contains(&tsn) {
+            self.duplicates.push(data.tsn);
+            return;
+        }
+        if tsn - self.remote_cumulative_tsn > MAX_RECEIVE_GAP {
+            return;
+        }
+        self.received.insert(tsn);
+        self.advance_cumulative_tsn();
+
+        // Chunks on streams the peer has not negotiated are acknowledged but discarded, RFC 4960 section 6.5
+        if data.stream_id >= self.inbound_streams {
+            let mut cause = data.stream_id.to_be_bytes().to_vec();
+            cause.extend_from_slice(&[0; 2]);
+            self.control.push(Chunk::Error(vec![Parameter::new(
+                CAUSE_INVALID_STREAM,
+                cause,
+            )]));
+            return;
+        }
+
+        if data.beginning && data.ending {
+            self.deliver(
+                data.stream_id,
+                data.ssn,
+                data.unordered,
+                data.ppid,
+                data.payload,
+            );
+        } else {
+            self.fragments.insert(tsn, data);
+            self.reassemble(tsn);
+        }
+    }
+
+    /// Fragments of a message have consecutive TSNs, from the one flagged as the beginning
+    /// to the one flagged as the end.
+    fn reassemble(&mut self, tsn: u64) {
+        let (stream_id, unordered) = match self.fragments.get(&tsn) {
+            Some(chunk) => (chunk.stream_id, chunk.unordered),
+            None => return,
+        };
+        let same_message =
+            |chunk: &DataChunk| chunk.stream_id == stream_id && chunk.unordered == unordered;
+
+        let mut first = tsn;
+        while !self.fragments[&first].beginning {
+            match self.fragments.get(&(first - 1)) {
+                Some(chunk) if same_message(chunk) && !chunk.ending => first -= 1,
+                _ => return,
+            }
+        }
+        let mut last = tsn;
+        while !self.fragments[&last].ending {
+            match self.fragments.get(&(last + 1)) {
+                Some(chunk) if same_message(chunk) && !chunk.beginning => last += 1,
+                _ => return,
+            }
+        }
+
+        let chunks: Vec<DataChunk> = (first..=last)
+            .filter_map(|tsn| self.fragments.remove(&tsn))
+            .collect();
+        let len: usize = chunks.iter().map(|chunk| chunk.payload.len()).sum();
+        if len > MAX_MESSAGE_SIZE {
+            return;
+        }
+        let (ssn, ppid) = (chunks[0].ssn, chunks[0].ppid);
+        let payload = chunks.into_iter().flat_map(|chunk| chunk.payload).collect();
+        self.deliver(stream_id, ssn, unordered, ppid, payload);
+    }
+
+    fn deliver(&mut self, stream_id: u16, ssn: u16, unordered: bool, ppid: u32, payload: Vec<u8>) {
+        let message = SctpMessage {
+            stream_id,
+            ppid,
+            payload,
+        };
+        if unordered {
+            self.events.push(SctpEvent::Message(message));
+            return;
+        }
+        let next = *self.next_ssn.get(&stream_id).unwrap_or(&0);
+        if (ssn.wrapping_sub(next) as i16) < 0 {
+            return;
+        }
+        self.ordered_bytes += message.payload.len();
+        if let Some(replaced) = self.ordered.insert((stream_id, ssn), message) {
+            self.ordered_bytes -= replaced.payload.len();
+        }
+        self.deliver_ordered(stream_id);
+        if self.ordered_bytes > RECEIVE_WINDOW as usize {
+            self.abort();
+        }
+    }
+
+    fn deliver_ordered(&mut self, stream_id: u16) {
+        let next = self.next_ssn.entry(stream_id).or_insert(0);
+        while let Some(message) = self.ordered.remove(&(stream_id, *next)) {
+            self.ordered_bytes -= message.payload.len();
+            self.events.push(SctpEvent::Message(message));
+            *next = next.wrapping_add(1);
+        }
+    }
+
+    fn discard_ordered<F: Fn(u16, u16) -> bool>(&mut self, discard: F) {
+        self.ordered
+            .retain(|(stream_id, ssn), _| !discard(*stream_id, *ssn));
+        self.ordered_bytes = self.ordered.values().map(|m| m.payload.len()).sum();
+    }
+
+    fn advance_cumulative_tsn(&mut self) {
+        while self.received.remove(&(self.remote_cumulative_tsn + 1)) {
+            self.remote_cumulative_tsn += 1;
+        }
+    }
+
+    fn sack(&mut self) -> Chunk {
+        let mut gap_blocks: Vec<(u64, u64)> = Vec::new();
+        for tsn in &self.received {
+            match gap_blocks.last_mut() {
+                Some((_, end)) if *end + 1 == *tsn => *end = *tsn,
+                _ => gap_blocks.push((*tsn, *tsn)),
+            }
+        }
+        let cumulative = self.remote_cumulative_tsn;
+        Chunk::Sack(SackChunk {
+            cumulative_tsn_ack: cumulative as u32,
+            a_rwnd: RECEIVE_WINDOW.saturating_sub(self.ordered_bytes as u32),
+            gap_blocks: gap_blocks
+                .into_iter()
+                .map(|(start, end)| ((start - cumulative) as u16, (end - cumulative) as u16))
+                .collect(),
+            duplicates: std::mem::take(&mut self.duplicates),
+        })
+    }
+
+    fn handle_sack(&mut self, sack: SackChunk, now: Instant) {
+        let cumulative = unwrap_tsn(self.cumulative_tsn_ack, sack.cumulative_tsn_ack);
+        if cumulative < self.cumulative_tsn_ack || cumulative >= self.next_tsn {
+            return;
+        }
+        let advanced = cumulative > self.cumulative_tsn_ack;
+        self.cumulative_tsn_ack = cumulative;
+        self.peer_rwnd = sack.a_rwnd;
+
+        while matches!(self.outstanding.front(), Some(o) if o.tsn <= cumulative) {
+            self.outstanding.pop_front();
+        }
+
+        let blocks: Vec<(u64, u64)> = sack
+            .gap_blocks
+            .iter()
+            .map(|(start, end)| (cumulative + *start as u64, cumulative + *end as u64))
+            .collect();
+        let highest_acked = blocks.iter().map(|(_, end)| *end).max();
+        for o in self.outstanding.iter_mut() {
+            if blocks
+                .iter()
+                .any(|(start, end)| *start <= o.tsn && o.tsn <= *end)
+            {
+                o.acked = true;
+                o.retransmit = false;
+            } else if matches!(highest_acked, Some(highest) if o.tsn < highest)
+                && o.sent_at.is_some()
+                && !o.acked
+            {
+                o.misses += 1;
+                if o.misses == FAST_RETRANSMIT_MISSES {
+                    o.retransmit = true;
+                }
+            }
+        }
+
+        if advanced {
+            self.rto = RTO_INITIAL;
+            self.retransmit_deadline = if self.outstanding.iter().any(|o| o.sent_at.is_some()) {
+                Some(now + self.rto)
+            } else {
+                None
+            };
+        }
+    }
+
+    fn handle_forward_tsn(&mut self, forward: ForwardTsnChunk) {
+        self.sack_needed = true;
+        let cumulative = unwrap_tsn(self.remote_cumulative_tsn, forward.new_cumulative_tsn);
+        if cumulative <= self.remote_cumulative_tsn {
+            return;
+        }
+        self.remote_cumulative_tsn = cumulative;
+        self.received = self.received.split_off(&(cumulative + 1));
+        self.fragments = self.fragments.split_off(&(cumulative + 1));
+        self.advance_cumulative_tsn();
+
+        for (stream_id, ssn) in forward.streams {
+            let next = self.next_ssn.entry(stream_id).or_insert(0);
+            if (ssn.wrapping_sub(*next) as i16) >= 0 {
+                *next = ssn.wrapping_add(1);
+            }
+            let next = *next;
+            self.discard_ordered(|stream, pending| {
+                stream == stream_id && (pending.wrapping_sub(next) as i16) < 0
+            });
+            self.deliver_ordered(stream_id);
+        }
+    }
+
+    fn handle_reconfig(&mut self, parameters: Vec<Parameter>) {
+        for parameter in parameters {
+            match parameter.kind {
+                PARAM_OUTGOING_RESET_REQUEST if parameter.value.len() >= 12 => {
+                    let sequence = NetworkEndian::read_u32(&parameter.value[0..4]);
+                    let result = if sequence == self.peer_reconfig_sequence.wrapping_add(1) {
+                        self.peer_reconfig_sequence = sequence;
+                        for stream in parameter.value[12..].chunks_exact(2) {
+                            let stream_id = NetworkEndian::read_u16(stream);
+                            self.next_ssn.remove(&stream_id);
+                            self.discard_ordered(|stream, _| stream == stream_id);
+                            self.events.push(SctpEvent::StreamReset(stream_id));
+                        }
+                        RECONFIG_RESULT_PERFORMED
+                    } else if sequence == self.peer_reconfig_sequence {
+                        RECONFIG_RESULT_PERFORMED
+                    } else {
+                        RECONFIG_RESULT_BAD_SEQUENCE
+                    };
+                    let mut value = sequence.to_be_bytes().to_vec();
+                    value.extend_from_slice(&result.to_be_bytes());
+                    self.control.push(Chunk::Reconfig(vec![Parameter::new(
+                        PARAM_RECONFIG_RESPONSE,
+                        value,
+                    )]));
+                }
+                PARAM_RECONFIG_RESPONSE if parameter.value.len() >= 8 => {
+                    let sequence = NetworkEndian::read_u32(&parameter.value[0..4]);
+                    if matches!(&self.reset_request, Some(request) if request.sequence == sequence)
+                    {
+                        self.reset_request = None;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds retransmissions, a FORWARD TSN for abandoned chunks and new chunks
+    /// that fit into the receiver window.
+    fn transmit_data(&mut self, chunks: &mut Vec<Chunk>, now: Instant) {
+        let exhausted: Vec<u64> = self
+            .outstanding
+            .iter()
+            .filter(|o| o.retransmit && !o.acked && !o.abandoned)
+            .filter(|o| matches!(o.reliability, Reliability::MaxRetransmits(max) if o.retransmits >= max))
+            .map(|o| o.message)
+            .collect();
+        exhausted
+            .into_iter()
+            .for_each(|message| self.abandon(message));
+
+        let mut failed = false;
+        for o in self.outstanding.iter_mut() {
+            if !o.retransmit || o.acked || o.abandoned {
+                continue;
+            }
+            o.retransmit = false;
+            o.misses = 0;
+            o.retransmits += 1;
+            o.sent_at = Some(now);
+            failed |= o.reliability == Reliability::Reliable && o.retransmits > MAX_RETRANSMITS;
+            chunks.push(Chunk::Data(o.chunk.clone()));
+        }
+        if failed {
+            self.close();
+            return;
+        }
+
+        if self.forward_tsn_needed {
+            self.forward_tsn_needed = false;
+            if let Some(forward) = self.forward_tsn() {
+                chunks.push(forward);
+                self.retransmit_deadline.get_or_insert(now + self.rto);
+            }
+        }
+
+        let mut flight: usize = self
+            .outstanding
+            .iter()
+            .filter(|o| o.sent_at.is_some() && !o.acked && !o.abandoned)
+            .map(|o| o.chunk.payload.len())
+            .sum();
+        let mut sent = false;
+        for o in self.outstanding.iter_mut() {
+            if o.sent_at.is_some() || o.abandoned {
+                continue;
+            }
+            let len = o.chunk.payload.len();
+            if flight > 0 && flight + len > self.peer_rwnd as usize {
+                break;
+            }
+            flight += len;
+            o.sent_at = Some(now);
+            sent = true;
+            chunks.push(Chunk::Data(o.chunk.clone()));
+        }
+        if sent && self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(now + self.rto);
+        }
+    }
+
+    fn transmit_reset(&mut self, chunks: &mut Vec<Chunk>, now: Instant) {
+        if self.reset_request.is_none() && !self.reset_queue.is_empty() {
+            let streams = std::mem::take(&mut self.reset_queue);
+            streams.iter().for_each(|stream_id| {
+                self.outgoing_ssn.remove(stream_id);
+            });
+            self.reset_request = Some(ResetRequest {
+                sequence: self.reconfig_sequence,
+                last_tsn: (self.next_tsn - 1) as u32,
+                streams,
+                sent_at: None,
+            });
+            self.reconfig_sequence = self.reconfig_sequence.wrapping_add(1);
+        }
+
+        let request = match &mut self.reset_request {
+            Some(request) if request.sent_at.is_none() => request,
+            _ => return,
+        };
+        request.sent_at = Some(now);
+        let mut value = Vec::with_capacity(12 + request.streams.len() * 2);
+        value.extend_from_slice(&request.sequence.to_be_bytes());
+        value.extend_from_slice(&self.peer_reconfig_sequence.to_be_bytes());
+        value.extend_from_slice(&request.last_tsn.to_be_bytes());
+        for stream_id in &request.streams {
+            value.extend_from_slice(&stream_id.to_be_bytes());
+        }
+        chunks.push(Chunk::Reconfig(vec![Parameter::new(
+            PARAM_OUTGOING_RESET_REQUEST,
+            value,
+        )]));
+    }
+
+    /// Abandons every chunk of the message, the receiver skips them after a FORWARD TSN.
+    fn abandon(&mut self, message: u64) {
+        self.outstanding
+            .iter_mut()
+            .filter(|o| o.message == message && !o.acked)
+            .for_each(|o| {
+                o.abandoned = true;
+                o.retransmit = false;
+            });
+        self.forward_tsn_needed = true;
+    }
+
+    /// Advances the peer ack point over the leading abandoned chunks, RFC 3758 section 3.5.
+    fn forward_tsn(&self) -> Option<Chunk> {
+        let mut new_cumulative_tsn = self.cumulative_tsn_ack;
+        let mut streams: Vec<(u16, u16)> = Vec::new();
+        for o in self.outstanding.iter() {
+            if !o.abandoned && !o.acked {
+                break;
+            }
+            new_cumulative_tsn = o.tsn;
+            if o.abandoned && !o.chunk.unordered {
+                match streams
+                    .iter_mut()
+                    .find(|(stream_id, _)| *stream_id == o.chunk.stream_id)
+                {
+                    Some((_, ssn)) => *ssn = o.chunk.ssn,
+                    None => streams.push((o.chunk.stream_id, o.chunk.ssn)),
+                }
+            }
+        }
+        if new_cumulative_tsn == self.cumulative_tsn_ack {
+            return None;
+        }
+        Some(Chunk::ForwardTsn(ForwardTsnChunk {
+            new_cumulative_tsn: new_cumulative_tsn as u32,
+            streams,
+        }))
+    }
+
+    fn bundle(&self, chunks: Vec<Chunk>) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut current = Vec::new();
+        for chunk in chunks {
+            let mut encoded = Vec::new();
+            chunk.write(&mut encoded);
+            if !current.is_empty()
+                && COMMON_HEADER_LEN + current.len() + encoded.len() > MAX_PACKET_LEN
+            {
+                packets.push(self.packet(&current));
+                current.clear();
+            }
+            current.extend_from_slice(&encoded);
+        }
+        if !current.is_empty() {
+            packets.push(self.packet(&current));
+        }
+        packets
+    }
+
+    fn packet(&self, chunks: &[u8]) -> Vec<u8> {
+        write_packet(self.local_port, self.remote_port, self.remote_tag, chunks)
+    }
+
+    fn abort(&mut self) {
+        self.sack_needed = false;
+        self.control.push(Chunk::Abort);
+        self.close();
+    }
+
+    fn close(&mut self) {
+        if self.state != State::Closed {
+            self.state = State::Closed;
+            self.events.push(SctpEvent::Closed);
+        }
+    }
+}
+
+/// Maps a 32 bit TSN to the extended TSN nearest to `reference`.
+fn unwrap_tsn(reference: u64, tsn: u32) -> u64 {
+    let delta = tsn.wrapping_sub(reference as u32) as i32;
+    (reference as i64 + delta as i64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_TAG: u32 = 0x1234_5678;
+    const PEER_TSN: u32 = 100;
+
+    fn packet(tag: u32, chunks: &[Chunk]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        chunks.iter().for_each(|chunk| chunk.write(&mut encoded));
+        write_packet(5000, 5000, tag, &encoded)
+    }
+
+    fn transmitted(association: &mut Association, now: Instant) -> Vec<Chunk> {
+        association
+            .poll_transmit(now)
+            .iter()
+            .map(|buf| SctpPacket::parse(buf).unwrap())
+            .inspect(|packet| assert_eq!(packet.verification_tag, PEER_TAG))
+            .flat_map(|packet| packet.chunks)
+            .collect()
+    }
+
+    fn init(initiate_tag: u32) -> Chunk {
+        Chunk::Init(InitChunk {
+            initiate_tag,
+            a_rwnd: RECEIVE_WINDOW,
+            outbound_streams: 16,
+            inbound_streams: 16,
+            initial_tsn: PEER_TSN,
+            parameters: Vec::new(),
+        })
+    }
+
+    fn data(tsn: u32, ssn: u16, beginning: bool, ending: bool, payload: &[u8]) -> Chunk {
+        Chunk::Data(DataChunk {
+            tsn,
+            stream_id: 1,
+            ssn,
+            ppid: 51,
+            unordered: false,
+            beginning,
+            ending,
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn message(payload: &[u8]) -> SctpEvent {
+        SctpEvent::Message(SctpMessage {
+            stream_id: 1,
+            ppid: 51,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Returns the association and the verification tag the peer has to use.
+    fn established(now: Instant) -> (Association, u32) {
+        let mut association = Association::default();
+        association
+            .receive(&packet(0, &[init(PEER_TAG)]), now)
+            .unwrap();
+        let (tag, cookie) = match transmitted(&mut association, now).as_slice() {
+            [Chunk::InitAck(ack)] => (ack.initiate_tag, ack.parameters[0].value.clone()),
+            chunks => panic!("unexpected {:?}", chunks),
+        };
+        assert!(!association.is_established());
+
+        association
+            .receive(&packet(tag, &[Chunk::CookieEcho(cookie)]), now)
+            .unwrap();
+        assert!(association.is_established());
+        assert_eq!(transmitted(&mut association, now), vec![Chunk::CookieAck]);
+        (association, tag)
+    }
+
+    fn sent_tsns(chunks: &[Chunk]) -> Vec<u32> {
+        chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Data(data) => Some(data.tsn),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_with_a_wrong_tag_are_ignored() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        let chunks = [data(PEER_TSN, 0, true, true, b"a")];
+        association
+            .receive(&packet(tag.wrapping_add(1), &chunks), now)
+            .unwrap();
+        assert!(association.take_events().is_empty());
+        assert!(transmitted(&mut association, now).is_empty());
+    }
+
+    #[test]
+    fn init_on_an_established_association_restarts_it_after_the_cookie() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        let restarted_tag = PEER_TAG + 1;
+
+        association
+            .receive(&packet(0, &[init(restarted_tag)]), now)
+            .unwrap();
+        assert!(association.is_established());
+        assert!(association.take_events().is_empty());
+        let (new_tag, cookie) = match transmitted(&mut association, now).as_slice() {
+            [Chunk::InitAck(ack)] => (ack.initiate_tag, ack.parameters[0].value.clone()),
+            chunks => panic!("unexpected {:?}", chunks),
+        };
+        assert_ne!(new_tag, tag);
+
+        // The current association keeps working until the peer echoes the new cookie
+        association
+            .receive(&packet(tag, &[data(PEER_TSN, 0, true, true, b"a")]), now)
+            .unwrap();
+        assert_eq!(association.take_events(), vec![message(b"a")]);
+        transmitted(&mut association, now);
+        let echo = Chunk::CookieEcho(cookie);
+        association
+            .receive(&packet(tag, std::slice::from_ref(&echo)), now)
+            .unwrap();
+        assert!(association.take_events().is_empty());
+        assert!(transmitted(&mut association, now).is_empty());
+
+        association.receive(&packet(new_tag, &[echo]), now).unwrap();
+        assert!(association.is_established());
+        assert_eq!(association.take_events(), vec![SctpEvent::Closed]);
+        let acks: Vec<SctpPacket> = association
+            .poll_transmit(now)
+            .iter()
+            .map(|buf| SctpPacket::parse(buf).unwrap())
+            .collect();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].verification_tag, restarted_tag);
+        assert_eq!(acks[0].chunks, vec![Chunk::CookieAck]);
+
+        // The restarted association starts over from the new initial TSN
+        association
+            .receive(
+                &packet(tag, &[data(PEER_TSN + 1, 1, true, true, b"b")]),
+                now,
+            )
+            .unwrap();
+        assert!(association.take_events().is_empty());
+        association
+            .receive(
+                &packet(new_tag, &[data(PEER_TSN, 0, true, true, b"c")]),
+                now,
+            )
+            .unwrap();
+        assert_eq!(association.take_events(), vec![message(b"c")]);
+    }
+
+    #[test]
+    fn init_with_the_current_peer_tag_is_ignored() {
+        let now = Instant::now();
+        let (mut association, _) = established(now);
+        association
+            .receive(&packet(0, &[init(PEER_TAG)]), now)
+            .unwrap();
+        assert!(association.is_established());
+        assert!(association.take_events().is_empty());
+        assert!(transmitted(&mut association, now).is_empty());
+    }
+
+    #[test]
+    fn fragments_are_reassembled_out_of_order() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        for chunk in [
+            data(PEER_TSN + 2, 0, false, true, b"ghi"),
+            data(PEER_TSN, 0, true, false, b"abc"),
+        ] {
+            association.receive(&packet(tag, &[chunk]), now).unwrap();
+        }
+        assert!(association.take_events().is_empty());
+
+        association
+            .receive(
+                &packet(tag, &[data(PEER_TSN + 1, 0, false, false, b"def")]),
+                now,
+            )
+            .unwrap();
+        assert_eq!(association.take_events(), vec![message(b"abcdefghi")]);
+    }
+
+    #[test]
+    fn ordered_messages_wait_for_their_predecessors() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        association
+            .receive(
+                &packet(tag, &[data(PEER_TSN + 1, 1, true, true, b"second")]),
+                now,
+            )
+            .unwrap();
+        assert!(association.take_events().is_empty());
+
+        association
+            .receive(
+                &packet(tag, &[data(PEER_TSN, 0, true, true, b"first")]),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            association.take_events(),
+            vec![message(b"first"), message(b"second")]
+        );
+    }
+
+    #[test]
+    fn sack_reports_gaps_and_duplicates() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        let chunks = [
+            data(PEER_TSN, 0, true, true, b"a"),
+            data(PEER_TSN + 2, 2, true, true, b"c"),
+            data(PEER_TSN + 3, 3, true, true, b"d"),
+            data(PEER_TSN + 5, 5, true, true, b"f"),
+            data(PEER_TSN + 2, 2, true, true, b"c"),
+        ];
+        association.receive(&packet(tag, &chunks), now).unwrap();
+        assert_eq!(
+            transmitted(&mut association, now),
+            vec![Chunk::Sack(SackChunk {
+                cumulative_tsn_ack: PEER_TSN,
+                // Three messages wait for the one with stream sequence number 1
+                a_rwnd: RECEIVE_WINDOW - 3,
+                gap_blocks: vec![(2, 3), (5, 5)],
+                duplicates: vec![PEER_TSN + 2],
+            })]
+        );
+    }
+
+    #[test]
+    fn data_on_streams_the_peer_did_not_open_is_discarded() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        let mut chunk = data(PEER_TSN, 0, true, true, b"a");
+        if let Chunk::Data(data) = &mut chunk {
+            data.stream_id = 16;
+        }
+        association.receive(&packet(tag, &[chunk]), now).unwrap();
+        assert!(association.take_events().is_empty());
+        assert_eq!(
+            transmitted(&mut association, now),
+            vec![
+                Chunk::Error(vec![Parameter::new(
+                    CAUSE_INVALID_STREAM,
+                    vec![0, 16, 0, 0]
+                )]),
+                Chunk::Sack(SackChunk {
+                    cumulative_tsn_ack: PEER_TSN,
+                    a_rwnd: RECEIVE_WINDOW,
+                    gap_blocks: Vec::new(),
+                    duplicates: Vec::new(),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn buffering_beyond_the_window_aborts() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        let payload = vec![0; 60_000];
+        let buffered = RECEIVE_WINDOW as usize / payload.len();
+        for ssn in 1..=buffered as u16 {
+            let chunk = data(PEER_TSN + ssn as u32, ssn, true, true, &payload);
+            association.receive(&packet(tag, &[chunk]), now).unwrap();
+        }
+        match transmitted(&mut association, now).last() {
+            Some(Chunk::Sack(sack)) => assert_eq!(
+                sack.a_rwnd as usize,
+                RECEIVE_WINDOW as usize - buffered * payload.len()
+            ),
+            chunks => panic!("unexpected {:?}", chunks),
+        }
+        assert!(association.is_established());
+
+        let ssn = buffered as u16 + 1;
+        let chunk = data(PEER_TSN + ssn as u32, ssn, true, true, &payload);
+        association.receive(&packet(tag, &[chunk]), now).unwrap();
+        assert!(!association.is_established());
+        assert_eq!(association.take_events(), vec![SctpEvent::Closed]);
+        assert_eq!(transmitted(&mut association, now), vec![Chunk::Abort]);
+    }
+
+    #[test]
+    fn chunks_acked_by_gap_blocks_are_not_retransmitted() {
+        let now = Instant::now();
+        let (mut association, tag) = established(now);
+        for payload in &[b"a", b"b", b"c"] {
+            association
+                .send(1, 51, *payload, false, Reliability::Reliable, now)
+                .unwrap();
+        }
+        let tsns = sent_tsns(&transmitted(&mut association, now));
+        assert_eq!(tsns.len(), 3);
+
+        let sack = Chunk::Sack(SackChunk {
+            cumulative_tsn_ack: tsns[0],
+            a_rwnd: RECEIVE_WINDOW,
+            gap_blocks: vec![(2, 2)],
+            duplicates: Vec::new(),
+        });
+        association.receive(&packet(tag, &[sack]), now).unwrap();
+        association.handle_timeout(now + RTO_INITIAL / 2);
+        assert!(transmitted(&mut association, now + RTO_INITIAL / 2).is_empty());
+
+        // T3-rtx expiry retransmits the chunk that is still missing and doubles the timeout
+        association.handle_timeout(now + RTO_INITIAL);
+        let later = now + RTO_INITIAL;
+        assert_eq!(
+            sent_tsns(&transmitted(&mut association, later)),
+            vec![tsns[1]]
+        );
+        association.handle_timeout(later + RTO_INITIAL);
+        assert!(transmitted(&mut association, later + RTO_INITIAL).is_empty());
+        association.handle_timeout(later + RTO_INITIAL * 2);
+        assert_eq!(
+            sent_tsns(&transmitted(&mut association, later + RTO_INITIAL * 2)),
+            vec![tsns[1]]
+        );
+    }
+
+    #[test]
+    fn unreliable_messages_are_skipped_with_forward_tsn() {
+        let now = Instant::now();
+        let (mut association, _) = established(now);
+        association
+            .send(1, 51, b"a", false, Reliability::MaxRetransmits(0), now)
+            .unwrap();
+        let tsns = sent_tsns(&transmitted(&mut association, now));
+
+        association.handle_timeout(now + RTO_INITIAL);
+        assert_eq!(
+            transmitted(&mut association, now + RTO_INITIAL),
+            vec![Chunk::ForwardTsn(ForwardTsnChunk {
+                new_cumulative_tsn: tsns[0],
+                streams: vec![(1, 0)],
+            })]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use crate::sctp::{
+    association::{Association, Reliability, SctpEvent, SctpMessage},
+    SctpError,
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use log::info;
+use std::{collections::HashMap, time::Duration, time::Instant};
+
+/// Payload protocol identifiers of RFC 8831 section 8.
+const PPID_DCEP: u32 = 50;
+const PPID_STRING: u32 = 51;
+const PPID_BINARY: u32 = 53;
+const PPID_STRING_EMPTY: u32 = 56;
+const PPID_BINARY_EMPTY: u32 = 57;
+
+const DCEP_ACK: u8 = 0x02;
+const DCEP_OPEN: u8 = 0x03;
+const DCEP_OPEN_HEADER_LEN: usize = 12;
+
+const CHANNEL_UNORDERED: u8 = 0x80;
+const CHANNEL_PARTIAL_RELIABLE_REXMIT: u8 = 0x01;
+const CHANNEL_PARTIAL_RELIABLE_TIMED: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq)]
+struct DataChannel {
+    label: String,
+    unordered: bool,
+    reliability: Reliability,
+}
+
+/// Message of a data channel, empty messages are sent as a single zero byte on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMessage {
+    pub label: String,
+    pub binary: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Data channels opened by the peer over DCEP, RFC 8832, keyed by their SCTP stream.
+#[derive(Debug, Default)]
+pub struct DataChannels {
+    association: Association,
+    channels: HashMap<u16, DataChannel>,
+}
+
+impl DataChannels {
+    pub fn is_established(&self) -> bool {
+        self.association.is_established()
+    }
+
+    /// Handles an SCTP packet and returns the messages received on open channels.
+    pub fn receive(&mut self, buf: &[u8], now: Instant) -> Result<Vec<ChannelMessage>, SctpError> {
+        self.association.receive(buf, now)?;
+
+        let mut messages = Vec::new();
+        for event in self.association.take_events() {
+            match event {
+                SctpEvent::Message(message) if message.ppid == PPID_DCEP => {
+                    self.handle_dcep(message, now)?
+                }
+                SctpEvent::Message(message) => {
+                    let channel = match self.channels.get(&message.stream_id) {
+                        Some(channel) => channel,
+                        None => continue,
+                    };
+                    let (binary, payload) = match message.ppid {
+                        PPID_STRING => (false, message.payload),
+                        PPID_BINARY => (true, message.payload),
+                        PPID_STRING_EMPTY => (false, Vec::new()),
+                        PPID_BINARY_EMPTY => (true, Vec::new()),
+                        _ => continue,
+                    };
+                    messages.push(ChannelMessage {
+                        label: channel.label.clone(),
+                        binary,
+                        payload,
+                    });
+                }
+                SctpEvent::StreamReset(stream_id) => {
+                    if let Some(channel) = self.channels.remove(&stream_id) {
+                        info!("data channel {} closed", channel.label);
+                        self.association.reset_stream(stream_id);
+                    }
+                }
+                SctpEvent::Closed => self.channels.clear(),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Sends the message on every channel with its label, returns whether there was one.
+    pub fn send(&mut self, message: &ChannelMessage, now: Instant) -> Result<bool, SctpError> {
+        let (ppid, payload) = match (message.binary, message.payload.is_empty()) {
+            (false, false) => (PPID_STRING, message.payload.as_slice()),
+            (true, false) => (PPID_BINARY, message.payload.as_slice()),
+            (false, true) => (PPID_STRING_EMPTY, &[0][..]),
+            (true, true) => (PPID_BINARY_EMPTY, &[0][..]),
+        };
+
+        let mut sent = false;
+        for (stream_id, channel) in &self.channels {
+            if channel.label == message.label {
+                self.association.send(
+                    *stream_id,
+                    ppid,
+                    payload,
+                    channel.unordered,
+                    channel.reliability,
+                    now,
+                )?;
+                sent = true;
+            }
+        }
+        Ok(sent)
+    }
+
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        self.association.poll_transmit(now)
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.association.handle_timeout(now)
+    }
+
+    fn handle_dcep(&mut self, message: SctpMessage, now: Instant) -> Result<(), SctpError> {
+        match message.payload.first() {
+            Some(&DCEP_OPEN) => {
+                let channel = parse_open(&message.payload)?;
+                info!(
+                    "data channel {} opened on stream {}",
+                    channel.label, message.stream_id
+                );
+                self.channels.insert(message.stream_id, channel);
+                self.association.send(
+                    message.stream_id,
+                    PPID_DCEP,
+                    &[DCEP_ACK],
+                    false,
+                    Reliability::Reliable,
+                    now,
+                )
+            }
+            // The server does not open channels itself, so there is nothing to acknowledge
+            Some(&DCEP_ACK) => Ok(()),
+            _ => Err(SctpError::MalformedMessage("unknown DCEP message")),
+        }
+    }
+}
+
+fn parse_open(buf: &[u8]) -> Result<DataChannel, SctpError> {
+    const MALFORMED: SctpError = SctpError::MalformedMessage("DATA_CHANNEL_OPEN is truncated");
+    if buf.len() < DCEP_OPEN_HEADER_LEN {
+        return Err(MALFORMED);
+    }
+    let channel_type = buf[1];
+    let reliability_parameter = NetworkEndian::read_u32(&buf[4..8]);
+    let label_len = NetworkEndian::read_u16(&buf[8..10]) as usize;
+    let protocol_len = NetworkEndian::read_u16(&buf[10..12]) as usize;
+    let label = buf
+        .get(DCEP_OPEN_HEADER_LEN..DCEP_OPEN_HEADER_LEN + label_len)
+        .ok_or(MALFORMED)?;
+    // The subprotocol is up to the application, channels are matched by label only
+    if buf.len() < DCEP_OPEN_HEADER_LEN + label_len + protocol_len {
+        return Err(MALFORMED);
+    }
+
+    let reliability = match channel_type & !CHANNEL_UNORDERED {
+        CHANNEL_PARTIAL_RELIABLE_REXMIT => Reliability::MaxRetransmits(reliability_parameter),
+        CHANNEL_PARTIAL_RELIABLE_TIMED => {
+            Reliability::MaxLifetime(Duration::from_millis(reliability_parameter as u64))
+        }
+        _ => Reliability::Reliable,
+    };
+
+    Ok(DataChannel {
+        label: String::from_utf8_lossy(label).into_owned(),
+        unordered: channel_type & CHANNEL_UNORDERED != 0,
+        reliability,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sctp::packet::{write_packet, Chunk, DataChunk, InitChunk, SctpPacket};
+
+    const PEER_TAG: u32 = 0x0bad_cafe;
+
+    fn packet(tag: u32, chunks: &[Chunk]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        chunks.iter().for_each(|chunk| chunk.write(&mut encoded));
+        write_packet(5000, 5000, tag, &encoded)
+    }
+
+    fn transmitted(channels: &mut DataChannels, now: Instant) -> Vec<Chunk> {
+        channels
+            .poll_transmit(now)
+            .iter()
+            .flat_map(|buf| SctpPacket::parse(buf).unwrap().chunks)
+            .collect()
+    }
+
+    fn data(tsn: u32, ppid: u32, payload: &[u8]) -> Chunk {
+        Chunk::Data(DataChunk {
+            tsn,
+            stream_id: 3,
+            ssn: tsn as u16,
+            ppid,
+            unordered: false,
+            beginning: true,
+            ending: true,
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn established(now: Instant) -> (DataChannels, u32) {
+        let mut channels = DataChannels::default();
+        let init = Chunk::Init(InitChunk {
+            initiate_tag: PEER_TAG,
+            a_rwnd: 1 << 20,
+            outbound_streams: 16,
+            inbound_streams: 16,
+            initial_tsn: 0,
+            parameters: Vec::new(),
+        });
+        channels.receive(&packet(0, &[init]), now).unwrap();
+        let (tag, cookie) = match transmitted(&mut channels, now).as_slice() {
+            [Chunk::InitAck(ack)] => (ack.initiate_tag, ack.parameters[0].value.clone()),
+            chunks => panic!("unexpected {:?}", chunks),
+        };
+        channels
+            .receive(&packet(tag, &[Chunk::CookieEcho(cookie)]), now)
+            .unwrap();
+        assert!(channels.is_established());
+        transmitted(&mut channels, now);
+        (channels, tag)
+    }
+
+    fn open(channel_type: u8, reliability_parameter: u32, label: &str) -> Vec<u8> {
+        let mut open = vec![DCEP_OPEN, channel_type, 0, 0];
+        open.extend_from_slice(&reliability_parameter.to_be_bytes());
+        open.extend_from_slice(&(label.len() as u16).to_be_bytes());
+        open.extend_from_slice(&4u16.to_be_bytes());
+        open.extend_from_slice(label.as_bytes());
+        open.extend_from_slice(b"json");
+        open
+    }
+
+    #[test]
+    fn open_is_acknowledged_and_messages_carry_the_label() {
+        let now = Instant::now();
+        let (mut channels, tag) = established(now);
+
+        let open = data(0, PPID_DCEP, &open(0, 0, "chat"));
+        assert!(channels
+            .receive(&packet(tag, &[open]), now)
+            .unwrap()
+            .is_empty());
+        let ack = transmitted(&mut channels, now)
+            .into_iter()
+            .find_map(|chunk| match chunk {
+                Chunk::Data(data) => Some(data),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            (ack.stream_id, ack.ppid, ack.payload, ack.unordered),
+            (3, PPID_DCEP, vec![DCEP_ACK], false)
+        );
+
+        let text = data(1, PPID_STRING, b"hello");
+        let empty = data(2, PPID_BINARY_EMPTY, &[0]);
+        assert_eq!(
+            channels.receive(&packet(tag, &[text, empty]), now).unwrap(),
+            vec![
+                ChannelMessage {
+                    label: "chat".to_string(),
+                    binary: false,
+                    payload: b"hello".to_vec(),
+                },
+                ChannelMessage {
+                    label: "chat".to_string(),
+                    binary: true,
+                    payload: Vec::new(),
+                },
+            ]
+        );
+
+        let reply = ChannelMessage {
+            label: "chat".to_string(),
+            binary: false,
+            payload: b"hi".to_vec(),
+        };
+        assert!(channels.send(&reply, now).unwrap());
+        let sent: Vec<(u16, u32, Vec<u8>)> = transmitted(&mut channels, now)
+            .into_iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Data(data) => Some((data.stream_id, data.ppid, data.payload)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![(3, PPID_STRING, b"hi".to_vec())]);
+
+        let other = ChannelMessage {
+            label: "other".to_string(),
+            ..reply
+        };
+        assert!(!channels.send(&other, now).unwrap());
+    }
+
+    #[test]
+    fn open_parameters_pick_the_reliability() {
+        let channel = parse_open(&open(
+            CHANNEL_UNORDERED | CHANNEL_PARTIAL_RELIABLE_REXMIT,
+            3,
+            "a",
+        ))
+        .unwrap();
+        assert!(channel.unordered);
+        assert_eq!(channel.reliability, Reliability::MaxRetransmits(3));
+
+        let channel = parse_open(&open(CHANNEL_PARTIAL_RELIABLE_TIMED, 150, "b")).unwrap();
+        assert!(!channel.unordered);
+        assert_eq!(
+            channel.reliability,
+            Reliability::MaxLifetime(Duration::from_millis(150))
+        );
+
+        assert!(parse_open(&open(0, 0, "c")[..14]).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+pub mod association;
+pub mod datachannel;
+pub mod packet;
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+};
+
+/// Reflected CRC32c (Castagnoli) table, SCTP checksums use it instead of CRC32, RFC 4960 appendix B.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32c(parts: &[&[u8]]) -> u32 {
+    let crc = parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(!0, |crc, byte| {
+            CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+    !crc
+}
+
+#[derive(Debug)]
+pub enum SctpError {
+    MalformedPacket(&'static str),
+    MalformedMessage(&'static str),
+    BadChecksum,
+    NotEstablished,
+    MessageTooLarge(usize),
+}
+
+impl Display for SctpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SctpError::MalformedPacket(e) => write!(f, "Malformed packet: {}", e),
+            SctpError::MalformedMessage(e) => write!(f, "Malformed data channel message: {}", e),
+            SctpError::BadChecksum => write!(f, "SCTP checksum mismatch"),
+            SctpError::NotEstablished => write!(f, "SCTP association is not established"),
+            SctpError::MessageTooLarge(len) => write!(f, "Message of {} bytes is too large", len),
+        }
+    }
+}
+
+impl Error for SctpError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_matches_rfc3720_vectors() {
+        let increasing: Vec<u8> = (0..32).collect();
+        let decreasing: Vec<u8> = (0..32).rev().collect();
+        assert_eq!(crc32c(&[&[0; 32]]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[&[0xff; 32]]), 0x62a8_ab43);
+        assert_eq!(crc32c(&[&increasing]), 0x46dd_794e);
+        assert_eq!(crc32c(&[&decreasing]), 0x113f_db5c);
+
+        let read_pdu = [
+            0x01, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x14,
+            0x00, 0x00, 0x00, 0x18, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(crc32c(&[&read_pdu]), 0xd996_3a56);
+        assert_eq!(crc32c(&[&read_pdu[..5], &read_pdu[5..]]), 0xd996_3a56);
+    }
+}
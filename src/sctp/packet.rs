@@ -0,0 +1,537 @@
+use crate::sctp::{crc32c, SctpError};
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian};
+
+pub const COMMON_HEADER_LEN: usize = 12;
+pub const DATA_CHUNK_HEADER_LEN: usize = 16;
+const CHUNK_HEADER_LEN: usize = 4;
+const INIT_HEADER_LEN: usize = 20;
+const PARAMETER_HEADER_LEN: usize = 4;
+
+const CHUNK_DATA: u8 = 0;
+const CHUNK_INIT: u8 = 1;
+const CHUNK_INIT_ACK: u8 = 2;
+const CHUNK_SACK: u8 = 3;
+const CHUNK_HEARTBEAT: u8 = 4;
+const CHUNK_HEARTBEAT_ACK: u8 = 5;
+const CHUNK_ABORT: u8 = 6;
+const CHUNK_SHUTDOWN: u8 = 7;
+const CHUNK_SHUTDOWN_ACK: u8 = 8;
+const CHUNK_ERROR: u8 = 9;
+const CHUNK_COOKIE_ECHO: u8 = 10;
+const CHUNK_COOKIE_ACK: u8 = 11;
+const CHUNK_SHUTDOWN_COMPLETE: u8 = 14;
+pub const CHUNK_RECONFIG: u8 = 130;
+pub const CHUNK_FORWARD_TSN: u8 = 192;
+
+pub const PARAM_STATE_COOKIE: u16 = 7;
+pub const PARAM_OUTGOING_RESET_REQUEST: u16 = 13;
+pub const PARAM_RECONFIG_RESPONSE: u16 = 16;
+pub const PARAM_SUPPORTED_EXTENSIONS: u16 = 0x8008;
+pub const PARAM_FORWARD_TSN_SUPPORTED: u16 = 0xc000;
+
+pub const CAUSE_INVALID_STREAM: u16 = 1;
+
+const DATA_FLAG_END: u8 = 0b001;
+const DATA_FLAG_BEGINNING: u8 = 0b010;
+const DATA_FLAG_UNORDERED: u8 = 0b100;
+
+/// Chunk types with the highest bit unset stop the processing of the packet, RFC 4960 section 3.2.
+const CHUNK_TYPE_SKIP: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SctpPacket {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub verification_tag: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chunk {
+    Data(DataChunk),
+    Init(InitChunk),
+    InitAck(InitChunk),
+    Sack(SackChunk),
+    /// Heartbeat information is echoed back as is.
+    Heartbeat(Vec<u8>),
+    HeartbeatAck(Vec<u8>),
+    Abort,
+    Shutdown,
+    ShutdownAck,
+    /// Error causes share the layout of parameters.
+    Error(Vec<Parameter>),
+    CookieEcho(Vec<u8>),
+    CookieAck,
+    ShutdownComplete,
+    ForwardTsn(ForwardTsnChunk),
+    Reconfig(Vec<Parameter>),
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataChunk {
+    pub tsn: u32,
+    pub stream_id: u16,
+    pub ssn: u16,
+    pub ppid: u32,
+    pub unordered: bool,
+    pub beginning: bool,
+    pub ending: bool,
+    pub payload: Vec<u8>,
+}
+
+/// INIT and INIT ACK share the layout, the state cookie is one of the INIT ACK parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitChunk {
+    pub initiate_tag: u32,
+    pub a_rwnd: u32,
+    pub outbound_streams: u16,
+    pub inbound_streams: u16,
+    pub initial_tsn: u32,
+    pub parameters: Vec<Parameter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SackChunk {
+    pub cumulative_tsn_ack: u32,
+    pub a_rwnd: u32,
+    /// Start and end offsets from the cumulative TSN.
+    pub gap_blocks: Vec<(u16, u16)>,
+    pub duplicates: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardTsnChunk {
+    pub new_cumulative_tsn: u32,
+    /// Stream identifier and the last skipped stream sequence number of ordered streams.
+    pub streams: Vec<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+impl SctpPacket {
+    pub fn parse(buf: &[u8]) -> Result<SctpPacket, SctpError> {
+        if buf.len() < COMMON_HEADER_LEN {
+            return Err(SctpError::MalformedPacket("sctp packet is too short"));
+        }
+        let checksum = LittleEndian::read_u32(&buf[8..12]);
+        let mut header = [0; COMMON_HEADER_LEN];
+        header.copy_from_slice(&buf[..COMMON_HEADER_LEN]);
+        header[8..12].copy_from_slice(&[0; 4]);
+        if crc32c(&[&header, &buf[COMMON_HEADER_LEN..]]) != checksum {
+            return Err(SctpError::BadChecksum);
+        }
+
+        let mut chunks = Vec::new();
+        let mut offset = COMMON_HEADER_LEN;
+        while offset + CHUNK_HEADER_LEN <= buf.len() {
+            let (chunk, len) = Chunk::parse(&buf[offset..])?;
+            offset += len;
+            let stop = matches!(chunk, Chunk::Unknown(kind) if kind & CHUNK_TYPE_SKIP == 0);
+            chunks.push(chunk);
+            if stop {
+                break;
+            }
+        }
+
+        Ok(SctpPacket {
+            source_port: NetworkEndian::read_u16(&buf[0..2]),
+            destination_port: NetworkEndian::read_u16(&buf[2..4]),
+            verification_tag: NetworkEndian::read_u32(&buf[4..8]),
+            chunks,
+        })
+    }
+}
+
+/// Builds a packet of already encoded chunks and fills in the CRC32c checksum.
+pub fn write_packet(
+    source_port: u16,
+    destination_port: u16,
+    verification_tag: u32,
+    chunks: &[u8],
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(COMMON_HEADER_LEN + chunks.len());
+    out.extend_from_slice(&source_port.to_be_bytes());
+    out.extend_from_slice(&destination_port.to_be_bytes());
+    out.extend_from_slice(&verification_tag.to_be_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(chunks);
+    let checksum = crc32c(&[&out]);
+    LittleEndian::write_u32(&mut out[8..12], checksum);
+    out
+}
+
+impl Chunk {
+    /// Returns the chunk and its padded length.
+    pub fn parse(buf: &[u8]) -> Result<(Chunk, usize), SctpError> {
+        if buf.len() < CHUNK_HEADER_LEN {
+            return Err(SctpError::MalformedPacket("chunk header is truncated"));
+        }
+        let kind = buf[0];
+        let flags = buf[1];
+        let len = NetworkEndian::read_u16(&buf[2..4]) as usize;
+        if len < CHUNK_HEADER_LEN || len > buf.len() {
+            return Err(SctpError::MalformedPacket("chunk length is invalid"));
+        }
+        let value = &buf[CHUNK_HEADER_LEN..len];
+
+        let chunk = match kind {
+            CHUNK_DATA => Chunk::Data(DataChunk::parse(flags, value)?),
+            CHUNK_INIT => Chunk::Init(InitChunk::parse(value)?),
+            CHUNK_INIT_ACK => Chunk::InitAck(InitChunk::parse(value)?),
+            CHUNK_SACK => Chunk::Sack(SackChunk::parse(value)?),
+            CHUNK_HEARTBEAT => Chunk::Heartbeat(value.to_vec()),
+            CHUNK_HEARTBEAT_ACK => Chunk::HeartbeatAck(value.to_vec()),
+            CHUNK_ABORT => Chunk::Abort,
+            CHUNK_SHUTDOWN => Chunk::Shutdown,
+            CHUNK_SHUTDOWN_ACK => Chunk::ShutdownAck,
+            CHUNK_ERROR => Chunk::Error(Parameter::parse_all(value)?),
+            CHUNK_COOKIE_ECHO => Chunk::CookieEcho(value.to_vec()),
+            CHUNK_COOKIE_ACK => Chunk::CookieAck,
+            CHUNK_SHUTDOWN_COMPLETE => Chunk::ShutdownComplete,
+            CHUNK_FORWARD_TSN => Chunk::ForwardTsn(ForwardTsnChunk::parse(value)?),
+            CHUNK_RECONFIG => Chunk::Reconfig(Parameter::parse_all(value)?),
+            kind => Chunk::Unknown(kind),
+        };
+
+        Ok((chunk, padded(len).min(buf.len())))
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        let (kind, flags) = match self {
+            Chunk::Data(data) => (CHUNK_DATA, data.flags()),
+            Chunk::Init(_) => (CHUNK_INIT, 0),
+            Chunk::InitAck(_) => (CHUNK_INIT_ACK, 0),
+            Chunk::Sack(_) => (CHUNK_SACK, 0),
+            Chunk::Heartbeat(_) => (CHUNK_HEARTBEAT, 0),
+            Chunk::HeartbeatAck(_) => (CHUNK_HEARTBEAT_ACK, 0),
+            Chunk::Abort => (CHUNK_ABORT, 0),
+            Chunk::Shutdown => (CHUNK_SHUTDOWN, 0),
+            Chunk::ShutdownAck => (CHUNK_SHUTDOWN_ACK, 0),
+            Chunk::Error(_) => (CHUNK_ERROR, 0),
+            Chunk::CookieEcho(_) => (CHUNK_COOKIE_ECHO, 0),
+            Chunk::CookieAck => (CHUNK_COOKIE_ACK, 0),
+            Chunk::ShutdownComplete => (CHUNK_SHUTDOWN_COMPLETE, 0),
+            Chunk::ForwardTsn(_) => (CHUNK_FORWARD_TSN, 0),
+            Chunk::Reconfig(_) => (CHUNK_RECONFIG, 0),
+            Chunk::Unknown(kind) => (*kind, 0),
+        };
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&[0; 2]);
+
+        match self {
+            Chunk::Data(data) => {
+                out.extend_from_slice(&data.tsn.to_be_bytes());
+                out.extend_from_slice(&data.stream_id.to_be_bytes());
+                out.extend_from_slice(&data.ssn.to_be_bytes());
+                out.extend_from_slice(&data.ppid.to_be_bytes());
+                out.extend_from_slice(&data.payload);
+            }
+            Chunk::Init(init) | Chunk::InitAck(init) => init.write(out),
+            Chunk::Sack(sack) => sack.write(out),
+            Chunk::Heartbeat(value) | Chunk::HeartbeatAck(value) | Chunk::CookieEcho(value) => {
+                out.extend_from_slice(value)
+            }
+            Chunk::ForwardTsn(forward) => {
+                out.extend_from_slice(&forward.new_cumulative_tsn.to_be_bytes());
+                for (stream_id, ssn) in &forward.streams {
+                    out.extend_from_slice(&stream_id.to_be_bytes());
+                    out.extend_from_slice(&ssn.to_be_bytes());
+                }
+            }
+            Chunk::Reconfig(parameters) | Chunk::Error(parameters) => {
+                parameters.iter().for_each(|p| p.write(out))
+            }
+            _ => {}
+        }
+
+        let len = out.len() - start;
+        NetworkEndian::write_u16(&mut out[start + 2..start + 4], len as u16);
+        out.resize(start + padded(len), 0);
+    }
+}
+
+impl DataChunk {
+    fn parse(flags: u8, value: &[u8]) -> Result<DataChunk, SctpError> {
+        // A DATA chunk without user data is a protocol violation
+        if value.len() <= DATA_CHUNK_HEADER_LEN - CHUNK_HEADER_LEN {
+            return Err(SctpError::MalformedPacket("data chunk is empty"));
+        }
+        Ok(DataChunk {
+            tsn: NetworkEndian::read_u32(&value[0..4]),
+            stream_id: NetworkEndian::read_u16(&value[4..6]),
+            ssn: NetworkEndian::read_u16(&value[6..8]),
+            ppid: NetworkEndian::read_u32(&value[8..12]),
+            unordered: flags & DATA_FLAG_UNORDERED != 0,
+            beginning: flags & DATA_FLAG_BEGINNING != 0,
+            ending: flags & DATA_FLAG_END != 0,
+            payload: value[12..].to_vec(),
+        })
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.unordered {
+            flags |= DATA_FLAG_UNORDERED;
+        }
+        if self.beginning {
+            flags |= DATA_FLAG_BEGINNING;
+        }
+        if self.ending {
+            flags |= DATA_FLAG_END;
+        }
+        flags
+    }
+}
+
+impl InitChunk {
+    fn parse(value: &[u8]) -> Result<InitChunk, SctpError> {
+        if value.len() < INIT_HEADER_LEN - CHUNK_HEADER_LEN {
+            return Err(SctpError::MalformedPacket("init chunk is truncated"));
+        }
+        Ok(InitChunk {
+            initiate_tag: NetworkEndian::read_u32(&value[0..4]),
+            a_rwnd: NetworkEndian::read_u32(&value[4..8]),
+            outbound_streams: NetworkEndian::read_u16(&value[8..10]),
+            inbound_streams: NetworkEndian::read_u16(&value[10..12]),
+            initial_tsn: NetworkEndian::read_u32(&value[12..16]),
+            parameters: Parameter::parse_all(&value[16..])?,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.initiate_tag.to_be_bytes());
+        out.extend_from_slice(&self.a_rwnd.to_be_bytes());
+        out.extend_from_slice(&self.outbound_streams.to_be_bytes());
+        out.extend_from_slice(&self.inbound_streams.to_be_bytes());
+        out.extend_from_slice(&self.initial_tsn.to_be_bytes());
+        self.parameters.iter().for_each(|p| p.write(out));
+    }
+}
+
+impl SackChunk {
+    fn parse(value: &[u8]) -> Result<SackChunk, SctpError> {
+        const MALFORMED: SctpError = SctpError::MalformedPacket("sack chunk is truncated");
+        if value.len() < 12 {
+            return Err(MALFORMED);
+        }
+        let gap_blocks_len = NetworkEndian::read_u16(&value[8..10]) as usize;
+        let duplicates_len = NetworkEndian::read_u16(&value[10..12]) as usize;
+        let blocks = value.get(12..12 + gap_blocks_len * 4).ok_or(MALFORMED)?;
+        let duplicates = value
+            .get(12 + blocks.len()..12 + blocks.len() + duplicates_len * 4)
+            .ok_or(MALFORMED)?;
+        Ok(SackChunk {
+            cumulative_tsn_ack: NetworkEndian::read_u32(&value[0..4]),
+            a_rwnd: NetworkEndian::read_u32(&value[4..8]),
+            gap_blocks: blocks
+                .chunks_exact(4)
+                .map(|b| {
+                    (
+                        NetworkEndian::read_u16(&b[0..2]),
+                        NetworkEndian::read_u16(&b[2..4]),
+                    )
+                })
+                .collect(),
+            duplicates: duplicates
+                .chunks_exact(4)
+                .map(NetworkEndian::read_u32)
+                .collect(),
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.cumulative_tsn_ack.to_be_bytes());
+        out.extend_from_slice(&self.a_rwnd.to_be_bytes());
+        out.extend_from_slice(&(self.gap_blocks.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(self.duplicates.len() as u16).to_be_bytes());
+        for (start, end) in &self.gap_blocks {
+            out.extend_from_slice(&start.to_be_bytes());
+            out.extend_from_slice(&end.to_be_bytes());
+        }
+        for tsn in &self.duplicates {
+            out.extend_from_slice(&tsn.to_be_bytes());
+        }
+    }
+}
+
+impl ForwardTsnChunk {
+    fn parse(value: &[u8]) -> Result<ForwardTsnChunk, SctpError> {
+        if value.len() < 4 {
+            return Err(SctpError::MalformedPacket("forward tsn chunk is truncated"));
+        }
+        Ok(ForwardTsnChunk {
+            new_cumulative_tsn: NetworkEndian::read_u32(&value[0..4]),
+            streams: value[4..]
+                .chunks_exact(4)
+                .map(|s| {
+                    (
+                        NetworkEndian::read_u16(&s[0..2]),
+                        NetworkEndian::read_u16(&s[2..4]),
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Parameter {
+    pub fn new(kind: u16, value: Vec<u8>) -> Parameter {
+        Parameter { kind, value }
+    }
+
+    fn parse_all(mut buf: &[u8]) -> Result<Vec<Parameter>, SctpError> {
+        let mut parameters = Vec::new();
+        while buf.len() >= PARAMETER_HEADER_LEN {
+            let len = NetworkEndian::read_u16(&buf[2..4]) as usize;
+            if len < PARAMETER_HEADER_LEN || len > buf.len() {
+                return Err(SctpError::MalformedPacket("parameter length is invalid"));
+            }
+            parameters.push(Parameter {
+                kind: NetworkEndian::read_u16(&buf[0..2]),
+                value: buf[PARAMETER_HEADER_LEN..len].to_vec(),
+            });
+            buf = &buf[padded(len).min(buf.len())..];
+        }
+        Ok(parameters)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let len = PARAMETER_HEADER_LEN + self.value.len();
+        out.extend_from_slice(&self.kind.to_be_bytes());
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.extend_from_slice(&self.value);
+        out.resize(out.len() + padded(len) - len, 0);
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(chunk: Chunk) -> Chunk {
+        let mut encoded = Vec::new();
+        chunk.write(&mut encoded);
+        assert_eq!(encoded.len() % 4, 0);
+        let (parsed, len) = Chunk::parse(&encoded).unwrap();
+        assert_eq!(len, encoded.len());
+        assert_eq!(parsed, chunk);
+        parsed
+    }
+
+    #[test]
+    fn init_round_trips() {
+        round_trip(Chunk::Init(InitChunk {
+            initiate_tag: 0xdead_beef,
+            a_rwnd: 0x0002_0000,
+            outbound_streams: 1024,
+            inbound_streams: 65535,
+            initial_tsn: 0xffff_fffe,
+            parameters: vec![
+                Parameter::new(PARAM_STATE_COOKIE, vec![1, 2, 3, 4, 5]),
+                Parameter::new(PARAM_SUPPORTED_EXTENSIONS, vec![CHUNK_RECONFIG]),
+                Parameter::new(PARAM_FORWARD_TSN_SUPPORTED, Vec::new()),
+            ],
+        }));
+    }
+
+    #[test]
+    fn sack_with_gap_blocks_round_trips() {
+        let sack = SackChunk {
+            cumulative_tsn_ack: 1000,
+            a_rwnd: 65536,
+            gap_blocks: vec![(2, 3), (5, 5)],
+            duplicates: vec![998, 999],
+        };
+        let mut encoded = Vec::new();
+        Chunk::Sack(sack.clone()).write(&mut encoded);
+        assert_eq!(&encoded[..4], &[CHUNK_SACK, 0, 0, 32]);
+        assert_eq!(&encoded[12..16], &[0, 2, 0, 2]);
+        round_trip(Chunk::Sack(sack));
+
+        let truncated = [
+            CHUNK_SACK, 0, 0, 20, 0, 0, 3, 232, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 3,
+        ];
+        assert!(Chunk::parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn forward_tsn_round_trips() {
+        round_trip(Chunk::ForwardTsn(ForwardTsnChunk {
+            new_cumulative_tsn: 77,
+            streams: vec![(1, 4), (3, 0xffff)],
+        }));
+        round_trip(Chunk::ForwardTsn(ForwardTsnChunk {
+            new_cumulative_tsn: 78,
+            streams: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn reconfig_round_trips() {
+        let mut request = Vec::new();
+        request.extend_from_slice(&1u32.to_be_bytes());
+        request.extend_from_slice(&2u32.to_be_bytes());
+        request.extend_from_slice(&3u32.to_be_bytes());
+        request.extend_from_slice(&7u16.to_be_bytes());
+        round_trip(Chunk::Reconfig(vec![
+            Parameter::new(PARAM_OUTGOING_RESET_REQUEST, request),
+            Parameter::new(PARAM_RECONFIG_RESPONSE, vec![0, 0, 0, 1, 0, 0, 0, 1]),
+        ]));
+    }
+
+    #[test]
+    fn error_round_trips() {
+        round_trip(Chunk::Error(vec![Parameter::new(
+            CAUSE_INVALID_STREAM,
+            vec![0, 9, 0, 0],
+        )]));
+    }
+
+    #[test]
+    fn data_flags_round_trip() {
+        round_trip(Chunk::Data(DataChunk {
+            tsn: 5,
+            stream_id: 2,
+            ssn: 9,
+            ppid: 51,
+            unordered: true,
+            beginning: true,
+            ending: false,
+            payload: b"abc".to_vec(),
+        }));
+    }
+
+    #[test]
+    fn packets_are_checksummed() {
+        let mut chunks = Vec::new();
+        Chunk::CookieAck.write(&mut chunks);
+        Chunk::Heartbeat(vec![1, 2, 3]).write(&mut chunks);
+        let mut packet = write_packet(5000, 5001, 0x0102_0304, &chunks);
+
+        let parsed = SctpPacket::parse(&packet).unwrap();
+        assert_eq!(parsed.source_port, 5000);
+        assert_eq!(parsed.destination_port, 5001);
+        assert_eq!(parsed.verification_tag, 0x0102_0304);
+        assert_eq!(
+            parsed.chunks,
+            vec![Chunk::CookieAck, Chunk::Heartbeat(vec![1, 2, 3])]
+        );
+
+        packet[13] ^= 1;
+        assert!(matches!(
+            SctpPacket::parse(&packet),
+            Err(SctpError::BadChecksum)
+        ));
+    }
+}
@@ -1,47 +1,78 @@
 use crate::{
-    client::sessions::{Session, SessionMessage},
-    server::udp::{ServerDataRequest, UdpRecv},
+    client::{
+        group::{GroupMembers, GroupSettings, GroupSettingsRequest},
+        sessions::{
+            Negotiation, NegotiationAnswer, NegotiationOffer, NegotiationRequest,
+            RemoteSessionRequest, Session, SessionData, SessionDescription, SessionMessage,
+        },
+        tracks::{AnnouncedTrack, TrackKey},
+    },
+    dtls::{connector::DtlsRole, fingerprint::Fingerprint as DtlsFingerprint},
+    rtp::codec::{Codec, Codecs, MediaKind},
+    sctp::association::MAX_MESSAGE_SIZE,
+    server::{
+        candidates::{CandidateTransport, IceCandidate},
+        config::ServerConfig,
+        meta::IceCredentials,
+        udp::{ServerDataRequest, UdpRecv},
+    },
 };
 use actix::prelude::*;
-use futures::{future::ready, stream::iter, StreamExt, TryStreamExt};
+use futures::{stream::iter, StreamExt, TryStreamExt};
 use rand::{prelude::ThreadRng, Rng};
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::{Debug, Display, Formatter},
     net::SocketAddr,
     sync::Arc,
+    time::SystemTime,
 };
 use webrtc_sdp::{
-    address::{Address, ExplicitlyTypedAddress},
+    address::{Address, AddressType, ExplicitlyTypedAddress},
     attribute_type::{
         SdpAttribute,
         SdpAttribute::{
-            Candidate, EndOfCandidates, Fingerprint, Group, IceLite, MsidSemantic, Rtcp,
-            Sendrecv as SendrecvAttr, Setup,
+            Candidate, EndOfCandidates, Fingerprint, Group, IceLite, MaxMessageSize, MsidSemantic,
+            Rtcp, SctpPort, Setup,
         },
-        SdpAttributeCandidate, SdpAttributeCandidateTransport, SdpAttributeCandidateType,
-        SdpAttributeFingerprint,
+        SdpAttributeCandidate, SdpAttributeCandidateTcpType, SdpAttributeCandidateTransport,
+        SdpAttributeCandidateType, SdpAttributeFingerprint,
         SdpAttributeFingerprintHashType::Sha256,
         SdpAttributeGroup,
         SdpAttributeGroupSemantic::Bundle,
-        SdpAttributeMsidSemantic, SdpAttributeRtcp,
-        SdpAttributeSetup::Passive,
-        SdpAttributeType::{Group as GroupType, IceUfrag, Msid, Sendrecv, Ssrc, SsrcGroup},
+        SdpAttributeMsid, SdpAttributeMsidSemantic, SdpAttributePayloadType, SdpAttributeRtcp,
+        SdpAttributeRtpmap,
+        SdpAttributeSetup::{Active, Actpass, Passive},
+        SdpAttributeSsrc,
+        SdpAttributeType::{
+            Candidate as CandidateType, Fingerprint as FingerprintType, Fmtp as FmtpType,
+            Group as GroupType, IceUfrag, Inactive, Mid as MidType, Msid, Recvonly,
+            Rtpmap as RtpmapType, Sctpmap as SctpmapType, Sendonly, Sendrecv, Setup as SetupType,
+            Ssrc, SsrcGroup,
+        },
+        SdpSsrcGroupSemantic,
     },
     error::{SdpParserError, SdpParserInternalError},
-    media_type::SdpMedia,
+    media_type::{SdpMedia, SdpMediaValue},
     parse_sdp, SdpConnection, SdpSession, SdpTiming,
 };
 
+const SERVER_MID_PREFIX: &str = "sfu-";
+const SCTP_PORT: u64 = 5000;
+
+/// `settings` are applied to the group when the client is the first to join it.
 pub async fn generate_streamer_response(
     sdp: &str,
     recv: Arc<Addr<UdpRecv>>,
     group_id: usize,
-    sdp_addr: SocketAddr,
+    settings: Option<GroupSettings>,
 ) -> Result<SdpSession, SdpResponseGeneratorError> {
-    let req = parse_sdp(sdp, true)?;
+    let mut req = parse_sdp(sdp, true)?;
 
     let server_data = recv.send(ServerDataRequest).await?;
+    let sdp_addr = connection_address(&server_data.candidates, &req)
+        .ok_or_else(|| SdpResponseGeneratorError::from("There are no ICE candidates"))?;
 
     let version = req.version;
     let session = req
@@ -49,21 +80,85 @@ pub async fn generate_streamer_response(
         .clone()
         .ok_or_else(|| SdpResponseGeneratorError::from("Session is empty"))?;
     let mut origin = req.get_origin().clone();
+    let remote_session_id = origin.session_id;
+    let fingerprints = extract_fingerprints(&req);
+    if fingerprints.is_empty() {
+        return Err(SdpResponseGeneratorError::from(
+            "Offer has no DTLS fingerprint",
+        ));
+    }
+    let dtls_role = extract_dtls_role(&req);
+
+    let mut rng = rand::thread_rng();
+    let credentials = IceCredentials::generate(&mut rng);
+
+    let restarted = recv
+        .send(RemoteSessionRequest(
+            group_id,
+            remote_session_id,
+            fingerprints.clone(),
+        ))
+        .await?;
+    let settings = recv.send(GroupSettingsRequest(group_id, settings)).await?;
+    let members = recv.send(GroupMembers(group_id)).await?;
+    let group_codecs: Vec<Codec> = members
+        .iter()
+        .flat_map(|(_, member)| member.codecs.iter().cloned())
+        .collect();
+    for m in req.media.iter_mut().filter(|m| m.get_port() != 0) {
+        if let Some(kind) = media_kind(m) {
+            negotiate_codec(m, kind, &server_data.config, settings, &group_codecs)?;
+        }
+    }
+    let codecs = extract_codecs(&req.media);
+    let plan_b = is_plan_b(&req.media);
+    let publishes = req
+        .media
+        .iter()
+        .any(|m| media_kind(m).is_some() && is_sending(m));
+    let subscribes = req
+        .media
+        .iter()
+        .any(|m| media_kind(m).is_some() && is_receiving(m));
+    let (stream_id, cname, track_ids) = extract_tracks(&req.media, &mut rng);
+    let rtx_sources = extract_rtx_sources(&req.media);
+    let announced = if subscribes {
+        match &restarted {
+            Some(previous) => update_tracks(
+                &members,
+                &codecs,
+                &previous.description.announced,
+                previous.addr,
+                &mut rng,
+            ),
+            None => announce_tracks(&members, &codecs, &[], &mut rng),
+        }
+    } else {
+        Vec::new()
+    };
+    let mut assigned = assign_tracks(&req.media, &announced, plan_b);
 
-    let server_user = server_data.meta.user.clone();
-    let server_passwd = server_data.meta.password.clone();
+    let description = Arc::new(SessionDescription {
+        codecs,
+        plan_b,
+        publishes,
+        subscribes,
+        cname,
+        stream_id,
+        track_ids,
+        rtx_sources,
+        announced,
+        fingerprints,
+        dtls_role,
+    });
 
-    let _inserted = iter(&req.media)
-        .filter_map(|m| ready(m.get_attribute(IceUfrag)))
+    let client_users: Vec<String> = req
+        .media
+        .iter()
+        .filter_map(|m| m.get_attribute(IceUfrag))
         .map(|m| m.to_string().replace("ice-ufrag:", ""))
-        .map(|client_user| Session::new(server_user.clone(), client_user))
-        .map(|session| SessionMessage(session, group_id))
-        .then(|session_message| recv.send(session_message))
-        .try_collect::<Vec<_>>()
-        .await
-        .expect("session sending error");
+        .collect();
 
-    let mut rng = rand::thread_rng();
     origin.session_id = rng.gen();
     origin.unicast_addr = ExplicitlyTypedAddress::from(sdp_addr.ip());
 
@@ -82,18 +177,26 @@ pub async fn generate_streamer_response(
     let media: Vec<SdpMedia> = req
         .media
         .into_iter()
-        .map(|mut m| {
+        .zip(assigned.iter_mut())
+        .map(|(mut m, tracks)| {
             m.set_port(sdp_addr.port() as u32);
 
+            // Data channels have no direction, the SCTP association is always bidirectional
+            let direction = media_kind(&m).map(|_| answer_direction(&m));
             remove_useless_attributes(&mut m);
             set_attributes(
                 &mut m,
-                server_user.clone(),
-                server_passwd.clone(),
+                direction,
+                &credentials,
                 server_data.crypto.digest.clone(),
+                dtls_role,
                 sdp_addr,
-                &mut rng,
+                &server_data.candidates,
             )?;
+            if *m.get_type() == SdpMediaValue::Application {
+                set_sctp_attributes(&mut m)?;
+            }
+            add_announced_tracks(&mut m, tracks, plan_b)?;
             replace_connection(m.get_connection(), sdp_addr);
             Ok(m)
         })
@@ -106,10 +209,7 @@ pub async fn generate_streamer_response(
     origin.username = String::from("-");
 
     let mut res = SdpSession::new(version, origin, session);
-    res.add_attribute(MsidSemantic(SdpAttributeMsidSemantic {
-        semantic: String::from("WMS"),
-        msids: Vec::new(),
-    }))?;
+    res.add_attribute(msid_semantic(&description.announced))?;
 
     res.add_attribute(group)?;
 
@@ -118,9 +218,316 @@ pub async fn generate_streamer_response(
     res.set_timing(SdpTiming { start: 0, stop: 0 });
 
     res.media = media;
+
+    let _inserted = iter(client_users)
+        .map(|client_user| Session::new(credentials.user.clone(), client_user))
+        .map(|session| {
+            SessionMessage(
+                session,
+                SessionData {
+                    group_id,
+                    password: credentials.password.clone(),
+                    remote_session_id,
+                    description: Arc::clone(&description),
+                    local_description: res.clone(),
+                    pending: None,
+                    addr: None,
+                    restarted_from: None,
+                    ttl: SystemTime::now(),
+                },
+            )
+        })
+        .then(|session_message| recv.send(session_message))
+        .try_collect::<Vec<_>>()
+        .await
+        .expect("session sending error");
+
     Ok(res)
 }
 
+/// Builds a new offer for an established session when the group membership has changed.
+///
+/// `sdp` is the current local description of the client, it is only used to find the session.
+pub async fn generate_streamer_offer(
+    sdp: &str,
+    recv: Arc<Addr<UdpRecv>>,
+    group_id: usize,
+) -> Result<Option<SdpSession>, SdpResponseGeneratorError> {
+    let req = parse_sdp(sdp, true)?;
+    let negotiation = find_negotiation(&req, &recv, group_id).await?;
+    let description = Arc::clone(&negotiation.description);
+    if !description.subscribes {
+        return Ok(None);
+    }
+
+    let members = recv.send(GroupMembers(group_id)).await?;
+    let mut rng = rand::thread_rng();
+    let announced = update_tracks(
+        &members,
+        &description.codecs,
+        &description.announced,
+        negotiation.addr,
+        &mut rng,
+    );
+    if announced
+        .iter()
+        .map(|track| track.ssrc)
+        .eq(description.announced.iter().map(|track| track.ssrc))
+    {
+        return Ok(None);
+    }
+
+    let offer = build_offer(
+        &negotiation.local_description,
+        &announced,
+        description.plan_b,
+    )?;
+    let description = Arc::new(SessionDescription {
+        announced,
+        ..SessionDescription::clone(&description)
+    });
+
+    if !recv
+        .send(NegotiationOffer(
+            negotiation.session,
+            offer.clone(),
+            description,
+        ))
+        .await?
+    {
+        return Err(SdpResponseGeneratorError::from("Session is not found"));
+    }
+    Ok(Some(offer))
+}
+
+pub async fn accept_streamer_answer(
+    sdp: &str,
+    recv: Arc<Addr<UdpRecv>>,
+    group_id: usize,
+) -> Result<(), SdpResponseGeneratorError> {
+    let answer = parse_sdp(sdp, true)?;
+    let negotiation = find_negotiation(&answer, &recv, group_id).await?;
+
+    let mut offer = negotiation
+        .pending
+        .ok_or_else(|| SdpResponseGeneratorError::from("There is no pending offer"))?;
+    let rejected = match_answer(&mut offer, &answer)?;
+
+    if !recv
+        .send(NegotiationAnswer(negotiation.session, offer, rejected))
+        .await?
+    {
+        return Err(SdpResponseGeneratorError::from("There is no pending offer"));
+    }
+    Ok(())
+}
+
+/// Checks that every m-line of the answer is the offered one, same mid and kind, with a
+/// direction the offer allows. M-lines rejected by the answer are disabled in the offer,
+/// returning the ssrcs announced in them.
+fn match_answer(
+    offer: &mut SdpSession,
+    answer: &SdpSession,
+) -> Result<Vec<u32>, SdpResponseGeneratorError> {
+    if offer.media.len() != answer.media.len() {
+        return Err(SdpResponseGeneratorError::from(
+            "Answer does not match the offer",
+        ));
+    }
+
+    let mut rejected = Vec::new();
+    for (offered, answered) in offer.media.iter_mut().zip(answer.media.iter()) {
+        if offered.get_type() != answered.get_type() {
+            return Err(SdpResponseGeneratorError::from(
+                "Answer media kind does not match the offer",
+            ));
+        }
+        let mid = media_mid(answered);
+        if mid != media_mid(offered) && (answered.get_port() != 0 || mid.is_some()) {
+            return Err(SdpResponseGeneratorError::from(
+                "Answer mid does not match the offer",
+            ));
+        }
+
+        if answered.get_port() == 0 {
+            if offered.get_port() != 0 {
+                rejected.extend(media_ssrcs(offered));
+                clear_tracks(offered);
+                offered.set_port(0);
+                set_direction(offered, SdpAttribute::Inactive)?;
+            }
+            continue;
+        }
+        if offered.get_port() == 0 {
+            return Err(SdpResponseGeneratorError::from(
+                "Answer accepts a rejected m-line",
+            ));
+        }
+        if media_kind(offered).is_some()
+            && ((is_sending(answered) && !is_receiving(offered))
+                || (is_receiving(answered) && !is_sending(offered)))
+        {
+            return Err(SdpResponseGeneratorError::from(
+                "Answer direction does not match the offer",
+            ));
+        }
+    }
+    Ok(rejected)
+}
+
+async fn find_negotiation(
+    sdp: &SdpSession,
+    recv: &Addr<UdpRecv>,
+    group_id: usize,
+) -> Result<Negotiation, SdpResponseGeneratorError> {
+    let client_user = sdp
+        .media
+        .iter()
+        .find_map(|m| m.get_attribute(IceUfrag))
+        .or_else(|| sdp.get_attribute(IceUfrag))
+        .and_then(|attribute| match attribute {
+            SdpAttribute::IceUfrag(ufrag) => Some(ufrag.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| SdpResponseGeneratorError::from("Ice ufrag is empty"))?;
+
+    recv.send(NegotiationRequest(client_user, group_id))
+        .await?
+        .ok_or_else(|| SdpResponseGeneratorError::from("Session is not found"))
+}
+
+/// Unified plan tracks keep their m-line, m-lines of departed publishers are rejected
+/// and recycled for the next publishers of the same kind.
+fn build_offer(
+    local: &SdpSession,
+    announced: &[AnnouncedTrack],
+    plan_b: bool,
+) -> Result<SdpSession, SdpResponseGeneratorError> {
+    let mut offer = local.clone();
+    offer.origin.session_version += 1;
+
+    let previous: Vec<Vec<u32>> = offer.media.iter().map(media_ssrcs).collect();
+    for m in offer.media.iter_mut() {
+        clear_tracks(m);
+        m.set_attribute(Setup(Actpass))?;
+    }
+
+    if plan_b {
+        for kind in &[MediaKind::Audio, MediaKind::Video] {
+            let m = offer
+                .media
+                .iter_mut()
+                .find(|m| media_kind(m) == Some(*kind) && m.get_port() != 0);
+            if let Some(m) = m {
+                let tracks: Vec<AnnouncedTrack> = announced
+                    .iter()
+                    .filter(|track| track.key.kind == *kind)
+                    .cloned()
+                    .collect();
+                add_announced_tracks(m, &tracks, true)?;
+            }
+        }
+    } else {
+        let mut recyclable = Vec::new();
+        let mut placed = HashSet::new();
+        for (i, m) in offer.media.iter_mut().enumerate() {
+            let is_server_media = is_server_media(m);
+            if m.get_port() == 0 {
+                if is_server_media {
+                    recyclable.push(i);
+                }
+                continue;
+            }
+            match announced
+                .iter()
+                .find(|track| previous[i].contains(&track.ssrc))
+            {
+                Some(track) => {
+                    add_announced_tracks(m, std::slice::from_ref(track), false)?;
+                    placed.insert(track.ssrc);
+                }
+                None if is_server_media => {
+                    m.set_port(0);
+                    set_direction(m, SdpAttribute::Inactive)?;
+                }
+                None => {}
+            }
+        }
+
+        for track in announced
+            .iter()
+            .filter(|track| !placed.contains(&track.ssrc))
+        {
+            let template = offer
+                .media
+                .iter()
+                .find(|m| media_kind(m) == Some(track.key.kind) && !is_server_media(m));
+            let mut m = match template {
+                Some(template) => template.clone(),
+                None => continue,
+            };
+            clear_tracks(&mut m);
+            m.set_attribute(SdpAttribute::Mid(format!(
+                "{}{}",
+                SERVER_MID_PREFIX, track.ssrc
+            )))?;
+            set_direction(&mut m, SdpAttribute::Sendonly)?;
+            add_announced_tracks(&mut m, std::slice::from_ref(track), false)?;
+
+            let slot = recyclable
+                .iter()
+                .position(|i| media_kind(&offer.media[*i]) == Some(track.key.kind));
+            match slot {
+                Some(position) => offer.media[recyclable.remove(position)] = m,
+                None => offer.media.push(m),
+            }
+        }
+    }
+
+    offer.attribute.retain(|attribute| {
+        !matches!(
+            attribute,
+            SdpAttribute::Group(_) | SdpAttribute::MsidSemantic(_)
+        )
+    });
+    offer.add_attribute(msid_semantic(announced))?;
+    offer.add_attribute(Group(SdpAttributeGroup {
+        semantics: Bundle,
+        tags: offer
+            .media
+            .iter()
+            .filter(|m| m.get_port() != 0)
+            .filter_map(|m| match m.get_attribute(MidType) {
+                Some(SdpAttribute::Mid(mid)) => Some(mid.clone()),
+                _ => None,
+            })
+            .collect(),
+    }))?;
+
+    Ok(offer)
+}
+
+/// Address for the `c=` and `a=rtcp` lines, a candidate of the family the offer uses for its
+/// own connection address, IPv4 when it has none.
+fn connection_address(candidates: &[IceCandidate], req: &SdpSession) -> Option<SocketAddr> {
+    let connection = req
+        .get_connection()
+        .as_ref()
+        .or_else(|| req.media.iter().find_map(|m| m.get_connection().as_ref()));
+    let ipv6 = match connection.map(|connection| &connection.address) {
+        Some(ExplicitlyTypedAddress::Ip(ip)) => ip.is_ipv6(),
+        Some(ExplicitlyTypedAddress::Fqdn { address_type, .. }) => {
+            *address_type == AddressType::IpV6
+        }
+        None => false,
+    };
+    candidates
+        .iter()
+        .find(|candidate| candidate.addr.is_ipv6() == ipv6)
+        .or_else(|| candidates.first())
+        .map(|candidate| candidate.addr)
+}
+
 fn replace_connection(connection: &Option<SdpConnection>, addr: SocketAddr) {
     #[allow(mutable_transmutes)]
     #[allow(clippy::transmute_ptr_to_ptr)]
@@ -134,70 +541,547 @@ fn replace_connection(connection: &Option<SdpConnection>, addr: SocketAddr) {
     });
 }
 
-fn remove_useless_attributes(m: &mut SdpMedia) {
-    m.remove_attribute(Msid);
+/// Session level and media level fingerprints, bundled media usually repeat the same one.
+fn extract_fingerprints(req: &SdpSession) -> Vec<DtlsFingerprint> {
+    let mut fingerprints: Vec<DtlsFingerprint> = Vec::new();
+    let attributes = req.get_attribute(FingerprintType).into_iter().chain(
+        req.media
+            .iter()
+            .flat_map(|m| m.get_attributes_of_type(FingerprintType)),
+    );
+    for attribute in attributes {
+        if let Fingerprint(fingerprint) = attribute {
+            let fingerprint = DtlsFingerprint::from(fingerprint);
+            if !fingerprints.contains(&fingerprint) {
+                fingerprints.push(fingerprint);
+            }
+        }
+    }
+    fingerprints
+}
+
+/// The offerer hands the server the active role with `a=setup:passive`,
+/// `actpass` is answered passive as browsers do.
+fn extract_dtls_role(req: &SdpSession) -> DtlsRole {
+    let setup = req
+        .media
+        .iter()
+        .filter_map(|m| m.get_attribute(SetupType))
+        .chain(req.get_attribute(SetupType))
+        .next();
+    match setup {
+        Some(Setup(Passive)) => DtlsRole::Active,
+        _ => DtlsRole::Passive,
+    }
+}
+
+fn media_kind(m: &SdpMedia) -> Option<MediaKind> {
+    match m.get_type() {
+        SdpMediaValue::Audio => Some(MediaKind::Audio),
+        SdpMediaValue::Video => Some(MediaKind::Video),
+        SdpMediaValue::Application => None,
+    }
+}
+
+fn is_sending(m: &SdpMedia) -> bool {
+    m.get_attribute(Recvonly).is_none() && m.get_attribute(Inactive).is_none()
+}
+
+fn is_receiving(m: &SdpMedia) -> bool {
+    m.get_attribute(Sendonly).is_none() && m.get_attribute(Inactive).is_none()
+}
+
+/// Direction of the answer is the offer's one seen from the server side.
+fn answer_direction(m: &SdpMedia) -> SdpAttribute {
+    match (is_sending(m), is_receiving(m)) {
+        (true, true) => SdpAttribute::Sendrecv,
+        (true, false) => SdpAttribute::Recvonly,
+        (false, true) => SdpAttribute::Sendonly,
+        (false, false) => SdpAttribute::Inactive,
+    }
+}
+
+fn extract_codecs(media: &[SdpMedia]) -> Codecs {
+    let mut codecs = Codecs::default();
+    media
+        .iter()
+        .filter_map(|m| Some((m, media_kind(m)?)))
+        .flat_map(|(m, kind)| media_codecs(m, kind))
+        .for_each(|codec| codecs.insert(codec));
+    codecs
+}
+
+fn media_codecs(m: &SdpMedia, kind: MediaKind) -> Vec<Codec> {
+    m.get_attributes_of_type(RtpmapType)
+        .into_iter()
+        .filter_map(|attribute| match attribute {
+            SdpAttribute::Rtpmap(rtpmap) => {
+                let fmtp = m
+                    .get_attributes_of_type(FmtpType)
+                    .into_iter()
+                    .find_map(|attribute| match attribute {
+                        SdpAttribute::Fmtp(fmtp) if fmtp.payload_type == rtpmap.payload_type => {
+                            Some(&fmtp.parameters)
+                        }
+                        _ => None,
+                    });
+                Some(Codec {
+                    payload_type: rtpmap.payload_type,
+                    kind,
+                    name: rtpmap.codec_name.clone(),
+                    clock_rate: rtpmap.frequency,
+                    channels: rtpmap.channels,
+                    packetization_mode: fmtp.map_or(0, |p| p.packetization_mode),
+                    apt: fmtp.and_then(|p| p.rtx).map(|rtx| rtx.apt),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Trims the m-line down to the most preferred codec the group is able to forward and its rtx.
+///
+/// A codec is accepted when some group member uses it, or every member in single codec groups.
+fn negotiate_codec(
+    m: &mut SdpMedia,
+    kind: MediaKind,
+    config: &ServerConfig,
+    settings: GroupSettings,
+    group: &[Codec],
+) -> Result<(), SdpResponseGeneratorError> {
+    let offered = media_codecs(m, kind);
+    let group: Vec<&Codec> = group
+        .iter()
+        .filter(|codec| codec.kind == kind && codec.apt.is_none())
+        .collect();
+    let is_shared = |codec: &Codec| {
+        group.is_empty()
+            || if settings.single_codec {
+                group.iter().all(|member| member.same_format(codec))
+            } else {
+                group.iter().any(|member| member.same_format(codec))
+            }
+    };
+
+    let chosen = config
+        .codecs(kind)
+        .iter()
+        .flat_map(|name| {
+            offered
+                .iter()
+                .filter(move |codec| codec.apt.is_none() && codec.name.eq_ignore_ascii_case(name))
+        })
+        .find(|codec| is_shared(codec))
+        .ok_or_else(|| {
+            SdpResponseGeneratorError::Custom(if group.is_empty() {
+                format!("Offer has no supported {:?} codec", kind)
+            } else {
+                format!("Offer shares no {:?} codec with the group", kind)
+            })
+        })?;
+    let payload_types: Vec<u8> = offered
+        .iter()
+        .filter(|codec| {
+            codec.payload_type == chosen.payload_type || codec.apt == Some(chosen.payload_type)
+        })
+        .map(|codec| codec.payload_type)
+        .collect();
+
+    let rtpmaps: Vec<SdpAttributeRtpmap> = m
+        .get_attributes_of_type(RtpmapType)
+        .into_iter()
+        .filter_map(|attribute| match attribute {
+            SdpAttribute::Rtpmap(rtpmap) if payload_types.contains(&rtpmap.payload_type) => {
+                Some(rtpmap.clone())
+            }
+            _ => None,
+        })
+        .collect();
+    let parameters: Vec<SdpAttribute> = m
+        .get_attributes()
+        .iter()
+        .filter(|attribute| match attribute {
+            SdpAttribute::Fmtp(fmtp) => payload_types.contains(&fmtp.payload_type),
+            SdpAttribute::Rtcpfb(rtcpfb) => match rtcpfb.payload_type {
+                SdpAttributePayloadType::PayloadType(payload_type) => {
+                    payload_types.contains(&payload_type)
+                }
+                SdpAttributePayloadType::Wildcard => true,
+            },
+            _ => false,
+        })
+        .cloned()
+        .collect();
+
+    m.remove_codecs();
+    for rtpmap in rtpmaps {
+        m.add_codec(rtpmap)?;
+    }
+    for attribute in parameters {
+        m.add_attribute(attribute)?;
+    }
+    Ok(())
+}
+
+fn is_plan_b(media: &[SdpMedia]) -> bool {
+    let has_msid = media.iter().any(|m| m.get_attribute(Msid).is_some());
+    let has_plan_b_mid = media.iter().any(|m| match m.get_attribute(MidType) {
+        Some(SdpAttribute::Mid(mid)) => mid == "audio" || mid == "video",
+        _ => false,
+    });
+    !has_msid && has_plan_b_mid
+}
+
+fn is_server_media(m: &SdpMedia) -> bool {
+    match m.get_attribute(MidType) {
+        Some(SdpAttribute::Mid(mid)) => mid.starts_with(SERVER_MID_PREFIX),
+        _ => false,
+    }
+}
+
+fn media_mid(m: &SdpMedia) -> Option<&String> {
+    match m.get_attribute(MidType) {
+        Some(SdpAttribute::Mid(mid)) => Some(mid),
+        _ => None,
+    }
+}
+
+fn media_ssrcs(m: &SdpMedia) -> Vec<u32> {
+    m.get_attributes_of_type(Ssrc)
+        .into_iter()
+        .filter_map(|attribute| match attribute {
+            SdpAttribute::Ssrc(ssrc) => Some(ssrc.id),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ssrc_attributes<'a>(m: &'a SdpMedia, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    m.get_attributes_of_type(Ssrc)
+        .into_iter()
+        .filter_map(move |attribute| match attribute {
+            SdpAttribute::Ssrc(ssrc) if ssrc.attribute.as_deref() == Some(name) => {
+                ssrc.value.as_deref()
+            }
+            _ => None,
+        })
+}
+
+fn extract_tracks(
+    media: &[SdpMedia],
+    rng: &mut ThreadRng,
+) -> (String, String, HashMap<MediaKind, String>) {
+    let msids: Vec<(MediaKind, String, Option<String>)> = media
+        .iter()
+        .filter(|m| is_sending(m))
+        .filter_map(|m| {
+            let kind = media_kind(m)?;
+            let msid = match m.get_attribute(Msid) {
+                Some(SdpAttribute::Msid(msid)) => Some((msid.id.clone(), msid.appdata.clone())),
+                _ => ssrc_attributes(m, "msid").next().map(|msid| {
+                    let mut parts = msid.split_whitespace();
+                    let id = parts.next().unwrap_or_default().to_string();
+                    (id, parts.next().map(String::from))
+                }),
+            };
+            Some(match msid {
+                Some((id, appdata)) => (kind, id, appdata),
+                None => (kind, String::new(), None),
+            })
+        })
+        .collect();
+
+    let stream_id = msids
+        .iter()
+        .map(|(_, id, _)| id.clone())
+        .find(|id| !id.is_empty() && id != "-")
+        .unwrap_or_else(|| format!("{:016x}", rng.gen::<u64>()));
+    let cname = media
+        .iter()
+        .find_map(|m| ssrc_attributes(m, "cname").next())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rng.gen::<u64>()));
+
+    let mut track_ids = HashMap::new();
+    msids.into_iter().for_each(|(kind, _, appdata)| {
+        track_ids
+            .entry(kind)
+            .or_insert_with(|| appdata.unwrap_or_else(|| format!("{}-{:?}", stream_id, kind)));
+    });
+
+    (stream_id, cname, track_ids)
+}
+
+/// Retransmission ssrcs of the offer mapped to the ssrcs they repair, as in `a=ssrc-group:FID`.
+fn extract_rtx_sources(media: &[SdpMedia]) -> HashMap<u32, u32> {
+    media
+        .iter()
+        .flat_map(|m| m.get_attributes_of_type(SsrcGroup))
+        .filter_map(|attribute| match attribute {
+            SdpAttribute::SsrcGroup(SdpSsrcGroupSemantic::FlowIdentification, ssrcs)
+                if ssrcs.len() == 2 =>
+            {
+                Some((ssrcs[1].id, ssrcs[0].id))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn announce_tracks(
+    members: &[(SocketAddr, Arc<SessionDescription>)],
+    codecs: &Codecs,
+    existing: &[AnnouncedTrack],
+    rng: &mut ThreadRng,
+) -> Vec<AnnouncedTrack> {
+    let mut used: HashSet<u32> = existing
+        .iter()
+        .flat_map(|track| std::iter::once(track.ssrc).chain(track.rtx_ssrc))
+        .collect();
+    let mut next_ssrc = || loop {
+        let ssrc = rng.gen_range(2, u32::MAX);
+        if used.insert(ssrc) {
+            return ssrc;
+        }
+    };
+
+    let mut announced = Vec::new();
+    for (addr, member) in members {
+        for kind in &[MediaKind::Audio, MediaKind::Video] {
+            let track_id = match member.track_ids.get(kind) {
+                Some(track_id) if codecs.has_kind(*kind) => track_id,
+                _ => continue,
+            };
+            let ssrc = next_ssrc();
+            let rtx_ssrc = if codecs.has_rtx(*kind) && member.codecs.has_rtx(*kind) {
+                Some(next_ssrc())
+            } else {
+                None
+            };
+            announced.push(AnnouncedTrack {
+                key: TrackKey::new(*addr, *kind),
+                ssrc,
+                rtx_ssrc,
+                cname: member.cname.clone(),
+                stream_id: member.stream_id.clone(),
+                track_id: track_id.clone(),
+            });
+        }
+    }
+    announced
+}
+
+/// Keeps the tracks of `previous` whose publishers are still in the group
+/// and announces the tracks of the other members except `own`.
+fn update_tracks(
+    members: &[(SocketAddr, Arc<SessionDescription>)],
+    codecs: &Codecs,
+    previous: &[AnnouncedTrack],
+    own: Option<SocketAddr>,
+    rng: &mut ThreadRng,
+) -> Vec<AnnouncedTrack> {
+    let mut announced: Vec<AnnouncedTrack> = previous
+        .iter()
+        .filter(|track| members.iter().any(|(addr, _)| *addr == track.key.publisher))
+        .cloned()
+        .collect();
+    let joined: Vec<(SocketAddr, Arc<SessionDescription>)> = members
+        .iter()
+        .filter(|(addr, _)| {
+            Some(*addr) != own && !announced.iter().any(|track| track.key.publisher == *addr)
+        })
+        .cloned()
+        .collect();
+
+    let added = announce_tracks(&joined, codecs, &announced, rng);
+    announced.extend(added);
+    announced
+}
+
+/// Plan B carries every track of a kind in one m-line, unified plan needs an m-line per track.
+fn assign_tracks(
+    media: &[SdpMedia],
+    announced: &[AnnouncedTrack],
+    plan_b: bool,
+) -> Vec<Vec<AnnouncedTrack>> {
+    let mut assigned = vec![Vec::new(); media.len()];
+    for kind in &[MediaKind::Audio, MediaKind::Video] {
+        let slots: Vec<usize> = media
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| media_kind(m) == Some(*kind) && is_receiving(m))
+            .map(|(i, _)| i)
+            .collect();
+        let tracks = announced.iter().filter(|track| track.key.kind == *kind);
+
+        if plan_b {
+            if let Some(slot) = slots.first() {
+                assigned[*slot].extend(tracks.cloned());
+            }
+        } else {
+            slots
+                .into_iter()
+                .zip(tracks)
+                .for_each(|(slot, track)| assigned[slot].push(track.clone()));
+        }
+    }
+    assigned
+}
+
+fn add_announced_tracks(
+    m: &mut SdpMedia,
+    tracks: &[AnnouncedTrack],
+    plan_b: bool,
+) -> Result<(), SdpParserInternalError> {
+    let ssrc = |id: u32, attribute: Option<&str>, value: Option<String>| SdpAttributeSsrc {
+        id,
+        attribute: attribute.map(String::from),
+        value,
+    };
+
+    for track in tracks {
+        if !plan_b {
+            m.add_attribute(SdpAttribute::Msid(SdpAttributeMsid {
+                id: track.stream_id.clone(),
+                appdata: Some(track.track_id.clone()),
+            }))?;
+        }
+        if let Some(rtx_ssrc) = track.rtx_ssrc {
+            m.add_attribute(SdpAttribute::SsrcGroup(
+                SdpSsrcGroupSemantic::FlowIdentification,
+                vec![ssrc(track.ssrc, None, None), ssrc(rtx_ssrc, None, None)],
+            ))?;
+        }
+        let msid = format!("{} {}", track.stream_id, track.track_id);
+        for id in std::iter::once(track.ssrc).chain(track.rtx_ssrc) {
+            m.add_attribute(SdpAttribute::Ssrc(ssrc(
+                id,
+                Some("cname"),
+                Some(track.cname.clone()),
+            )))?;
+            m.add_attribute(SdpAttribute::Ssrc(ssrc(
+                id,
+                Some("msid"),
+                Some(msid.clone()),
+            )))?;
+        }
+    }
+    Ok(())
+}
+
+fn msid_semantic(announced: &[AnnouncedTrack]) -> SdpAttribute {
+    let mut msids: Vec<String> = announced
+        .iter()
+        .map(|track| track.stream_id.clone())
+        .collect();
+    msids.dedup();
+    MsidSemantic(SdpAttributeMsidSemantic {
+        semantic: String::from("WMS"),
+        msids,
+    })
+}
+
+fn set_direction(m: &mut SdpMedia, direction: SdpAttribute) -> Result<(), SdpParserInternalError> {
     m.remove_attribute(Sendrecv);
+    m.remove_attribute(Sendonly);
+    m.remove_attribute(Recvonly);
+    m.remove_attribute(Inactive);
+    m.add_attribute(direction)
+}
+
+fn clear_tracks(m: &mut SdpMedia) {
+    m.remove_attribute(Msid);
     m.remove_attribute(SsrcGroup);
     m.remove_attribute(Ssrc);
 }
 
+fn remove_useless_attributes(m: &mut SdpMedia) {
+    clear_tracks(m);
+    m.remove_attribute(Sendrecv);
+}
+
 fn set_attributes(
     m: &mut SdpMedia,
-    server_user: String,
-    server_passwd: String,
+    direction: Option<SdpAttribute>,
+    credentials: &IceCredentials,
     fingerprint: Vec<u8>,
+    dtls_role: DtlsRole,
     addr: SocketAddr,
-    rng: &mut ThreadRng,
+    candidates: &[IceCandidate],
 ) -> Result<(), SdpParserInternalError> {
-    m.set_attribute(SendrecvAttr)?;
-    m.set_attribute(SdpAttribute::IcePwd(server_passwd))?;
-    m.set_attribute(SdpAttribute::IceUfrag(server_user))?;
+    if let Some(direction) = direction {
+        set_direction(m, direction)?;
+    }
+    m.set_attribute(SdpAttribute::IcePwd(credentials.password.clone()))?;
+    m.set_attribute(SdpAttribute::IceUfrag(credentials.user.clone()))?;
     m.set_attribute(Fingerprint(SdpAttributeFingerprint {
         hash_algorithm: Sha256,
         fingerprint,
     }))?;
-    m.set_attribute(Setup(Passive))?;
+    m.set_attribute(Setup(match dtls_role {
+        DtlsRole::Active => Active,
+        DtlsRole::Passive => Passive,
+    }))?;
     m.set_attribute(Rtcp(SdpAttributeRtcp {
         port: addr.port(),
         unicast_addr: Some(ExplicitlyTypedAddress::from(addr.ip())),
     }))?;
-    m.set_attribute(Candidate(SdpAttributeCandidate {
-        foundation: "0".to_string(),
-        priority: rng.gen::<u32>() as u64,
-        address: Address::Ip(addr.ip()),
-        port: addr.port() as u32,
-        c_type: SdpAttributeCandidateType::Host,
-        raddr: None,
-        rport: None,
-        tcp_type: None,
-        generation: None,
-        ufrag: None,
-        networkcost: None,
-        transport: SdpAttributeCandidateTransport::Udp,
-        component: 1,
-        unknown_extensions: vec![],
-    }))?;
+    m.remove_attribute(CandidateType);
+    for candidate in candidates {
+        let (transport, tcp_type) = match candidate.transport {
+            CandidateTransport::Udp => (SdpAttributeCandidateTransport::Udp, None),
+            CandidateTransport::TcpPassive => (
+                SdpAttributeCandidateTransport::Tcp,
+                Some(SdpAttributeCandidateTcpType::Passive),
+            ),
+        };
+        m.add_attribute(Candidate(SdpAttributeCandidate {
+            foundation: candidate.foundation.clone(),
+            priority: candidate.priority as u64,
+            address: Address::Ip(candidate.addr.ip()),
+            port: candidate.addr.port() as u32,
+            c_type: SdpAttributeCandidateType::Host,
+            raddr: None,
+            rport: None,
+            tcp_type,
+            generation: None,
+            ufrag: None,
+            networkcost: None,
+            transport,
+            component: 1,
+            unknown_extensions: vec![],
+        }))?;
+    }
 
     m.set_attribute(EndOfCandidates)?;
 
     Ok(())
 }
 
+/// Legacy offers carry `a=sctpmap` with the port in it, RFC 8841 ones expect `a=sctp-port`.
+fn set_sctp_attributes(m: &mut SdpMedia) -> Result<(), SdpParserInternalError> {
+    if m.get_attribute(SctpmapType).is_none() {
+        m.set_attribute(SctpPort(SCTP_PORT))?;
+    }
+    m.set_attribute(MaxMessageSize(MAX_MESSAGE_SIZE as u64))
+}
+
 #[derive(Debug)]
 pub enum SdpResponseGeneratorError {
-    SdpParserError(SdpParserError),
-    SdpParserInternalError(SdpParserInternalError),
-    MailBoxError(MailboxError),
-    CustomError(String),
+    Parser(SdpParserError),
+    ParserInternal(SdpParserInternalError),
+    Mailbox(MailboxError),
+    Custom(String),
 }
 
 impl Display for SdpResponseGeneratorError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            SdpResponseGeneratorError::SdpParserError(e) => std::fmt::Display::fmt(&e, f),
-            SdpResponseGeneratorError::SdpParserInternalError(e) => std::fmt::Display::fmt(&e, f),
-            SdpResponseGeneratorError::MailBoxError(_) => write!(f, "Udp receiver is broken"),
-            SdpResponseGeneratorError::CustomError(m) => write!(f, "{}", m),
+            SdpResponseGeneratorError::Parser(e) => std::fmt::Display::fmt(&e, f),
+            SdpResponseGeneratorError::ParserInternal(e) => std::fmt::Display::fmt(&e, f),
+            SdpResponseGeneratorError::Mailbox(e) => write!(f, "Udp receiver is broken: {}", e),
+            SdpResponseGeneratorError::Custom(m) => write!(f, "{}", m),
         }
     }
 }
@@ -206,24 +1090,153 @@ impl Error for SdpResponseGeneratorError {}
 
 impl From<SdpParserError> for SdpResponseGeneratorError {
     fn from(e: SdpParserError) -> Self {
-        SdpResponseGeneratorError::SdpParserError(e)
+        SdpResponseGeneratorError::Parser(e)
     }
 }
 
 impl From<SdpParserInternalError> for SdpResponseGeneratorError {
     fn from(e: SdpParserInternalError) -> Self {
-        SdpResponseGeneratorError::SdpParserInternalError(e)
+        SdpResponseGeneratorError::ParserInternal(e)
     }
 }
 
 impl From<MailboxError> for SdpResponseGeneratorError {
     fn from(e: MailboxError) -> Self {
-        SdpResponseGeneratorError::MailBoxError(e)
+        SdpResponseGeneratorError::Mailbox(e)
     }
 }
 
 impl From<&str> for SdpResponseGeneratorError {
     fn from(e: &str) -> Self {
-        SdpResponseGeneratorError::CustomError(e.into())
+        SdpResponseGeneratorError::Custom(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: &str = "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n";
+
+    fn section(kind: &str, mid: &str, port: u16, direction: &str, ssrc: Option<u32>) -> String {
+        let mut m = format!(
+            "m={} {} UDP/TLS/RTP/SAVPF 96\r\nc=IN IP4 127.0.0.1\r\na=mid:{}\r\na={}\r\n\
+             a=rtpmap:96 VP8/90000\r\n",
+            kind, port, mid, direction
+        );
+        if let Some(ssrc) = ssrc {
+            m.push_str(&format!("a=ssrc:{} cname:test\r\n", ssrc));
+        }
+        m
+    }
+
+    fn sdp(sections: &[String]) -> SdpSession {
+        parse_sdp(&format!("{}{}", SESSION, sections.concat()), true).unwrap()
+    }
+
+    fn offer() -> SdpSession {
+        sdp(&[
+            section("video", "0", 9, "recvonly", None),
+            section("video", "sfu-1234", 9, "sendonly", Some(1234)),
+        ])
+    }
+
+    #[test]
+    fn matching_answer_is_accepted() {
+        let mut offer = offer();
+        let answer = sdp(&[
+            section("video", "0", 9, "sendonly", None),
+            section("video", "sfu-1234", 9, "recvonly", None),
+        ]);
+        assert_eq!(
+            match_answer(&mut offer, &answer).unwrap(),
+            Vec::<u32>::new()
+        );
+        assert_eq!(offer.media[1].get_port(), 9);
+    }
+
+    #[test]
+    fn rejected_section_is_disabled() {
+        let mut offer = offer();
+        let answer = sdp(&[
+            section("video", "0", 9, "sendonly", None),
+            section("video", "sfu-1234", 0, "inactive", None),
+        ]);
+        assert_eq!(match_answer(&mut offer, &answer).unwrap(), vec![1234]);
+        assert_eq!(offer.media[1].get_port(), 0);
+        assert!(media_ssrcs(&offer.media[1]).is_empty());
+        assert!(offer.media[1].get_attribute(Inactive).is_some());
+    }
+
+    #[test]
+    fn mismatched_answers_are_rejected() {
+        let answers = [
+            // Sections swapped
+            vec![
+                section("video", "sfu-1234", 9, "recvonly", None),
+                section("video", "0", 9, "sendonly", None),
+            ],
+            // Kind changed
+            vec![
+                section("audio", "0", 9, "sendonly", None),
+                section("video", "sfu-1234", 9, "recvonly", None),
+            ],
+            // Sending on a section the server only sends on
+            vec![
+                section("video", "0", 9, "sendonly", None),
+                section("video", "sfu-1234", 9, "sendrecv", None),
+            ],
+            // Section missing
+            vec![section("video", "0", 9, "sendonly", None)],
+        ];
+        for answer in answers.iter() {
+            assert!(match_answer(&mut offer(), &sdp(answer)).is_err());
+        }
+    }
+
+    #[test]
+    fn previously_rejected_section_stays_rejected() {
+        let mut offer = sdp(&[section("video", "0", 0, "inactive", None)]);
+        let accepted = sdp(&[section("video", "0", 9, "inactive", None)]);
+        assert!(match_answer(&mut offer, &accepted).is_err());
+        let rejected = sdp(&[section("video", "0", 0, "inactive", None)]);
+        assert_eq!(
+            match_answer(&mut offer, &rejected).unwrap(),
+            Vec::<u32>::new()
+        );
+    }
+
+    #[test]
+    fn connection_address_follows_the_offer_family() {
+        let candidates: Vec<IceCandidate> = ["[2001:db8::1]:3000", "192.0.2.1:3000"]
+            .iter()
+            .map(|addr| IceCandidate {
+                foundation: "1".to_string(),
+                priority: 1,
+                addr: addr.parse().unwrap(),
+                transport: CandidateTransport::Udp,
+            })
+            .collect();
+        let ipv4 = offer();
+        let ipv6 = parse_sdp(
+            &format!("{}{}", SESSION, section("video", "0", 9, "recvonly", None))
+                .replace("IN IP4 127.0.0.1", "IN IP6 ::1"),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            connection_address(&candidates, &ipv4),
+            Some(candidates[1].addr)
+        );
+        assert_eq!(
+            connection_address(&candidates, &ipv6),
+            Some(candidates[0].addr)
+        );
+        assert_eq!(
+            connection_address(&candidates[..1], &ipv4),
+            Some(candidates[0].addr)
+        );
+        assert_eq!(connection_address(&[], &ipv4), None);
     }
 }
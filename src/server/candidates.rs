@@ -0,0 +1,154 @@
+use log::warn;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+/// Type preference of host candidates, RFC 8445 section 5.1.2.2.
+const HOST_TYPE_PREFERENCE: u32 = 126;
+const RTP_COMPONENT: u32 = 1;
+/// Direction preference of passive TCP candidates, RFC 6544 section 4.2.
+const TCP_PASSIVE_DIRECTION_PREFERENCE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateTransport {
+    Udp,
+    TcpPassive,
+}
+
+impl fmt::Display for CandidateTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CandidateTransport::Udp => write!(f, "udp"),
+            CandidateTransport::TcpPassive => write!(f, "tcp passive"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IceCandidate {
+    pub foundation: String,
+    pub priority: u32,
+    pub addr: SocketAddr,
+    pub transport: CandidateTransport,
+}
+
+/// Collects the host candidates advertised in SDP answers.
+///
+/// Advertised IPs replace the local addresses when the server is behind a 1:1 NAT,
+/// otherwise an unspecified bind address expands to the addresses of all interfaces.
+/// With a `tcp_port` every address also gets a passive TCP candidate ranked below the UDP ones.
+pub fn gather_candidates(
+    binds: &[SocketAddr],
+    advertised: &[IpAddr],
+    tcp_port: Option<u16>,
+) -> Vec<IceCandidate> {
+    let gathered: Vec<SocketAddr> = if !advertised.is_empty() {
+        advertised
+            .iter()
+            .filter_map(|ip| {
+                let bind = binds
+                    .iter()
+                    .find(|bind| bind.is_ipv4() == ip.is_ipv4())
+                    .or_else(|| binds.first())?;
+                Some(SocketAddr::new(*ip, bind.port()))
+            })
+            .collect()
+    } else {
+        let dual_stack = is_dual_stack(binds);
+        let mut local: Vec<SocketAddr> = binds
+            .iter()
+            .flat_map(|bind| {
+                let ips = if bind.ip().is_unspecified() {
+                    interface_ips(bind.ip(), dual_stack)
+                } else {
+                    vec![bind.ip()]
+                };
+                ips.into_iter()
+                    .map(move |ip| SocketAddr::new(ip, bind.port()))
+            })
+            .collect();
+        // IPv6 addresses go first
+        local.sort_by_key(SocketAddr::is_ipv4);
+        local
+    };
+
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in gathered {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    let mut candidates: Vec<IceCandidate> = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| IceCandidate {
+            foundation: (i + 1).to_string(),
+            priority: priority(HOST_TYPE_PREFERENCE, local_preference(i), RTP_COMPONENT),
+            addr: *addr,
+            transport: CandidateTransport::Udp,
+        })
+        .collect();
+    if let Some(port) = tcp_port {
+        candidates.extend(addrs.iter().enumerate().map(|(i, addr)| IceCandidate {
+            foundation: (addrs.len() + i + 1).to_string(),
+            priority: priority(HOST_TYPE_PREFERENCE, tcp_local_preference(i), RTP_COMPONENT),
+            addr: SocketAddr::new(addr.ip(), port),
+            transport: CandidateTransport::TcpPassive,
+        }));
+    }
+    candidates
+}
+
+/// With sockets of both families IPv6 sockets are bound v6-only and IPv4 goes to its own socket.
+pub fn is_dual_stack(binds: &[SocketAddr]) -> bool {
+    binds.iter().any(SocketAddr::is_ipv4) && binds.iter().any(SocketAddr::is_ipv6)
+}
+
+/// Candidate priority formula of RFC 8445 section 5.1.2.1.
+pub fn priority(type_preference: u32, local_preference: u32, component: u32) -> u32 {
+    (type_preference << 24) + (local_preference << 8) + (256 - component)
+}
+
+fn local_preference(index: usize) -> u32 {
+    0xffff - (index as u32).min(0xffff)
+}
+
+/// RFC 6544 section 4.2 splits the local preference into direction and address parts.
+fn tcp_local_preference(index: usize) -> u32 {
+    (TCP_PASSIVE_DIRECTION_PREFERENCE << 13) + 0x1fff - (index as u32).min(0x1fff)
+}
+
+/// An IPv6 wildcard socket also receives IPv4 traffic unless IPv4 has a socket of its own.
+fn interface_ips(bind: IpAddr, dual_stack: bool) -> Vec<IpAddr> {
+    let interfaces = match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("could not enumerate network interfaces: {}", e);
+            return vec![bind];
+        }
+    };
+
+    let mut ips: Vec<IpAddr> = interfaces
+        .iter()
+        .filter(|interface| !interface.is_loopback())
+        .map(|interface| interface.ip())
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => bind.is_ipv4() || !dual_stack,
+            IpAddr::V6(ip) => bind.is_ipv6() && ip.segments()[0] & 0xffc0 != 0xfe80,
+        })
+        .collect();
+
+    if ips.is_empty() {
+        ips = interfaces
+            .iter()
+            .map(|interface| interface.ip())
+            .filter(|ip| match ip {
+                IpAddr::V4(_) => bind.is_ipv4() || !dual_stack,
+                IpAddr::V6(_) => bind.is_ipv6(),
+            })
+            .collect();
+    }
+    ips
+}
@@ -0,0 +1,133 @@
+use crate::{rtp::codec::MediaKind, turn::peers::IpRange};
+use std::{collections::HashMap, env, net::IpAddr, time::Duration};
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub audio_codecs: Vec<String>,
+    pub video_codecs: Vec<String>,
+    pub advertised_ips: Vec<IpAddr>,
+    pub ice_tcp_port: Option<u16>,
+    pub turn_port: Option<u16>,
+    pub turn_realm: String,
+    pub turn_users: HashMap<String, String>,
+    pub turn_secret: Option<String>,
+    pub turn_credential_ttl: Duration,
+    pub turn_allowed_peers: Vec<IpRange>,
+    pub turn_denied_peers: Vec<IpRange>,
+    pub turn_user_quota: usize,
+    pub turn_total_quota: usize,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub srtp_profiles: Vec<String>,
+}
+
+impl ServerConfig {
+    /// Codec names are listed by preference, e.g. `STREAMER_VIDEO_CODECS=VP8,H264,VP9`.
+    ///
+    /// `STREAMER_ADVERTISED_IPS` lists the public addresses of a 1:1 NAT in candidate order.
+    /// `STREAMER_ICE_TCP_PORT` enables passive ICE-TCP candidates on the given port.
+    ///
+    /// `STREAMER_TURN_PORT` starts the embedded TURN relay, it accepts the long-term credentials
+    /// of `STREAMER_TURN_USERS=user:password,...` in `STREAMER_TURN_REALM`.
+    /// `STREAMER_TURN_SECRET` enables the ephemeral TURN REST credentials handed out to clients,
+    /// they are valid for `STREAMER_TURN_CREDENTIAL_TTL` seconds, a day by default.
+    /// Relaying to private, loopback and link-local peers is refused, `STREAMER_TURN_DENIED_PEERS`
+    /// adds ranges to them and `STREAMER_TURN_ALLOWED_PEERS` exempts ranges, e.g. `10.0.0.0/8`.
+    /// `STREAMER_TURN_USER_QUOTA` and `STREAMER_TURN_TOTAL_QUOTA` limit the allocations per user
+    /// and in total.
+    ///
+    /// `STREAMER_CERT_PATH` and `STREAMER_KEY_PATH` point to a PEM certificate and key for DTLS,
+    /// without them a self-signed certificate is generated on startup.
+    ///
+    /// `STREAMER_SRTP_PROFILES` lists the DTLS-SRTP protection profiles by preference.
+    pub fn from_env() -> ServerConfig {
+        ServerConfig {
+            audio_codecs: env_list("STREAMER_AUDIO_CODECS", &["opus"]),
+            video_codecs: env_list("STREAMER_VIDEO_CODECS", &["VP8", "H264", "VP9"]),
+            advertised_ips: env_list("STREAMER_ADVERTISED_IPS", &[])
+                .iter()
+                .map(|ip| ip.parse().expect("could not parse STREAMER_ADVERTISED_IPS"))
+                .collect(),
+            ice_tcp_port: env::var("STREAMER_ICE_TCP_PORT")
+                .ok()
+                .map(|port| port.parse().expect("could not parse STREAMER_ICE_TCP_PORT")),
+            turn_port: env::var("STREAMER_TURN_PORT")
+                .ok()
+                .map(|port| port.parse().expect("could not parse STREAMER_TURN_PORT")),
+            turn_realm: env::var("STREAMER_TURN_REALM").unwrap_or_else(|_| "r-streamer".into()),
+            turn_users: env_list("STREAMER_TURN_USERS", &[])
+                .iter()
+                .map(|user| {
+                    let mut credentials = user.splitn(2, ':');
+                    match (credentials.next(), credentials.next()) {
+                        (Some(user), Some(password)) => (user.to_owned(), password.to_owned()),
+                        _ => panic!("could not parse STREAMER_TURN_USERS"),
+                    }
+                })
+                .collect(),
+            turn_secret: env::var("STREAMER_TURN_SECRET").ok(),
+            turn_credential_ttl: Duration::from_secs(
+                env::var("STREAMER_TURN_CREDENTIAL_TTL")
+                    .ok()
+                    .map(|ttl| {
+                        ttl.parse()
+                            .expect("could not parse STREAMER_TURN_CREDENTIAL_TTL")
+                    })
+                    .unwrap_or(86400),
+            ),
+            turn_allowed_peers: env_ranges("STREAMER_TURN_ALLOWED_PEERS"),
+            turn_denied_peers: env_ranges("STREAMER_TURN_DENIED_PEERS"),
+            turn_user_quota: env_number("STREAMER_TURN_USER_QUOTA", 10),
+            turn_total_quota: env_number("STREAMER_TURN_TOTAL_QUOTA", 1000),
+            cert_path: env::var("STREAMER_CERT_PATH").ok(),
+            key_path: env::var("STREAMER_KEY_PATH").ok(),
+            srtp_profiles: env_list(
+                "STREAMER_SRTP_PROFILES",
+                &[
+                    "SRTP_AEAD_AES_128_GCM",
+                    "SRTP_AEAD_AES_256_GCM",
+                    "SRTP_AES128_CM_SHA1_80",
+                    "SRTP_AES128_CM_SHA1_32",
+                ],
+            ),
+        }
+    }
+
+    pub fn codecs(&self, kind: MediaKind) -> &[String] {
+        match kind {
+            MediaKind::Audio => &self.audio_codecs,
+            MediaKind::Video => &self.video_codecs,
+        }
+    }
+}
+
+fn env_list(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+fn env_number(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("could not parse {}", name)),
+        Err(_) => default,
+    }
+}
+
+fn env_ranges(name: &str) -> Vec<IpRange> {
+    env_list(name, &[])
+        .iter()
+        .map(|range| {
+            range
+                .parse()
+                .unwrap_or_else(|e| panic!("could not parse {}: {}", name, e))
+        })
+        .collect()
+}
@@ -1,50 +1,205 @@
+use crate::server::config::ServerConfig;
 use openssl::ssl::SslSessionCacheMode;
 use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
     error::ErrorStack,
     hash::MessageDigest,
+    nid::Nid,
     pkey::{PKey, Private},
-    ssl::{SslAcceptor, SslMethod, SslVerifyMode},
-    x509::X509,
+    ssl::{SslAcceptor, SslConnector, SslContextBuilder, SslMethod, SslVerifyMode},
+    x509::{X509NameBuilder, X509},
 };
-use std::sync::Arc;
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    fs, io,
+    sync::Arc,
+};
+
+const CERTIFICATE_COMMON_NAME: &str = "r-streamer";
+const CERTIFICATE_VALIDITY_DAYS: u32 = 365;
 
 #[derive(Clone)]
 pub struct Crypto {
-    pub key: PKey<Private>,
-    pub x509: X509,
     pub digest: Vec<u8>,
     pub ssl_acceptor: Arc<SslAcceptor>,
+    pub ssl_connector: Arc<SslConnector>,
 }
 
 impl Crypto {
-    pub fn init() -> Result<Crypto, ErrorStack> {
-        let x509 = X509::from_pem(include_bytes!("../../cert/cert.pem"))?;
-        let key = PKey::private_key_from_pem(include_bytes!("../../cert/key.pem"))?;
+    /// Loads the configured certificate and key or generates a self-signed pair,
+    /// the fingerprint announced in SDP is the digest of the certificate in use.
+    /// Configuring only one of the two is an error.
+    pub fn init(config: &ServerConfig) -> Result<Crypto, CryptoError> {
+        let (x509, key) = match (&config.cert_path, &config.key_path) {
+            (Some(cert_path), Some(key_path)) => (
+                X509::from_pem(&fs::read(cert_path)?)?,
+                PKey::private_key_from_pem(&fs::read(key_path)?)?,
+            ),
+            (None, None) => generate_certificate()?,
+            (Some(_), None) => return Err(CryptoError::MissingPath("STREAMER_KEY_PATH")),
+            (None, Some(_)) => return Err(CryptoError::MissingPath("STREAMER_CERT_PATH")),
+        };
 
         let digest = x509.digest(MessageDigest::sha256())?.to_vec();
 
         let mut ssl_acceptor_builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::dtls())?;
+        configure_dtls(&mut ssl_acceptor_builder, config, &x509, &key)?;
+        ssl_acceptor_builder.check_private_key()?;
+        let ssl_acceptor = Arc::new(ssl_acceptor_builder.build());
 
-        ssl_acceptor_builder.set_verify(SslVerifyMode::NONE);
+        let mut ssl_connector_builder = SslConnector::builder(SslMethod::dtls())?;
+        configure_dtls(&mut ssl_connector_builder, config, &x509, &key)?;
+        let ssl_connector = Arc::new(ssl_connector_builder.build());
 
-        ssl_acceptor_builder.set_tlsext_use_srtp("SRTP_AES128_CM_SHA1_80")?;
+        Ok(Crypto {
+            digest,
+            ssl_acceptor,
+            ssl_connector,
+        })
+    }
+}
 
-        ssl_acceptor_builder.set_read_ahead(true);
+/// Settings shared by both DTLS roles.
+fn configure_dtls(
+    builder: &mut SslContextBuilder,
+    config: &ServerConfig,
+    x509: &X509,
+    key: &PKey<Private>,
+) -> Result<(), ErrorStack> {
+    // Peers use self-signed certificates, they are checked against the SDP fingerprint
+    // once the handshake is done.
+    builder.set_verify_callback(
+        SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        |_, _| true,
+    );
 
-        ssl_acceptor_builder.set_session_cache_mode(SslSessionCacheMode::OFF);
+    // OpenSSL picks the first of the server profiles that the client offers
+    builder.set_tlsext_use_srtp(&config.srtp_profiles.join(":"))?;
 
-        ssl_acceptor_builder.set_private_key(&key)?;
-        ssl_acceptor_builder.set_certificate(&x509)?;
+    builder.set_read_ahead(true);
 
-        ssl_acceptor_builder.check_private_key()?;
+    builder.set_session_cache_mode(SslSessionCacheMode::OFF);
 
-        let ssl_acceptor = Arc::new(ssl_acceptor_builder.build());
+    builder.set_private_key(key)?;
+    builder.set_certificate(x509)?;
 
-        Ok(Crypto {
-            key,
-            x509,
-            digest,
-            ssl_acceptor,
-        })
+    Ok(())
+}
+
+/// Self-signed ECDSA P-256 certificate, browsers only check it against the SDP fingerprint.
+fn generate_certificate() -> Result<(X509, PKey<Private>), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, CERTIFICATE_COMMON_NAME)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERTIFICATE_VALIDITY_DAYS)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Openssl(ErrorStack),
+    Io(io::Error),
+    /// The certificate and the key have to be configured together.
+    MissingPath(&'static str),
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Openssl(e) => write!(f, "{}", e),
+            CryptoError::Io(e) => write!(f, "could not read certificate: {}", e),
+            CryptoError::MissingPath(name) => {
+                write!(f, "{} is required with a configured certificate", name)
+            }
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+impl From<ErrorStack> for CryptoError {
+    fn from(e: ErrorStack) -> Self {
+        CryptoError::Openssl(e)
+    }
+}
+
+impl From<io::Error> for CryptoError {
+    fn from(e: io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(cert_path: Option<&str>, key_path: Option<&str>) -> ServerConfig {
+        ServerConfig {
+            audio_codecs: Vec::new(),
+            video_codecs: Vec::new(),
+            advertised_ips: Vec::new(),
+            ice_tcp_port: None,
+            turn_port: None,
+            turn_realm: String::new(),
+            turn_users: Default::default(),
+            turn_secret: None,
+            turn_credential_ttl: Duration::from_secs(0),
+            turn_allowed_peers: Vec::new(),
+            turn_denied_peers: Vec::new(),
+            turn_user_quota: 0,
+            turn_total_quota: 0,
+            cert_path: cert_path.map(String::from),
+            key_path: key_path.map(String::from),
+            srtp_profiles: vec!["SRTP_AES128_CM_SHA1_80".to_string()],
+        }
+    }
+
+    #[test]
+    fn certificate_and_key_are_configured_together() {
+        assert!(matches!(
+            Crypto::init(&config(Some("cert.pem"), None)),
+            Err(CryptoError::MissingPath("STREAMER_KEY_PATH"))
+        ));
+        assert!(matches!(
+            Crypto::init(&config(None, Some("key.pem"))),
+            Err(CryptoError::MissingPath("STREAMER_CERT_PATH"))
+        ));
+    }
+
+    #[test]
+    fn self_signed_certificate_is_generated_without_paths() {
+        let crypto = Crypto::init(&config(None, None)).unwrap();
+        let certificate = crypto.ssl_acceptor.context().certificate().unwrap();
+        assert_eq!(
+            crypto.digest,
+            certificate
+                .digest(MessageDigest::sha256())
+                .unwrap()
+                .to_vec()
+        );
     }
 }
@@ -1,20 +1,20 @@
-#[derive(Clone)]
-pub struct ServerMeta {
+/// ICE ufrag and password the server uses for a single peer session.
+#[derive(Debug, Clone)]
+pub struct IceCredentials {
     pub user: String,
     pub password: String,
 }
 
-impl ServerMeta {
-    pub fn new() -> ServerMeta {
-        let mut rng = rand::thread_rng();
-        let user = rand_string(&mut rng, 12);
-        let password = rand_string(&mut rng, 24);
+impl IceCredentials {
+    pub fn generate<R: rand::Rng>(rng: &mut R) -> IceCredentials {
+        let user = rand_string(rng, 12);
+        let password = rand_string(rng, 24);
 
-        ServerMeta { user, password }
+        IceCredentials { user, password }
     }
 }
 
-fn rand_string<R: rand::Rng>(rng: &mut R, size: usize) -> String {
+pub fn rand_string<R: rand::Rng>(rng: &mut R, size: usize) -> String {
     const RAND_CHAR_TABLE: &[u8; 62] =
         b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
 
@@ -1,3 +1,7 @@
+pub mod candidates;
+pub mod config;
 pub mod crypto;
 pub mod meta;
+pub mod socket;
+pub mod tcp;
 pub mod udp;
@@ -0,0 +1,35 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{io, net::SocketAddr};
+use tokio::net::{TcpListener, UdpSocket};
+
+const TCP_BACKLOG: i32 = 128;
+
+/// Binds a UDP socket, `only_v6` keeps an IPv6 wildcard from taking the IPv4 port as well.
+pub fn bind_udp(addr: SocketAddr, only_v6: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(domain(addr), Type::dgram(), Some(Protocol::udp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+pub fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(domain(addr), Type::stream(), Some(Protocol::tcp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(TCP_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+fn domain(addr: SocketAddr) -> Domain {
+    match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    }
+}
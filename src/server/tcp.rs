@@ -0,0 +1,185 @@
+use actix::prelude::*;
+use log::warn;
+use std::{io, net::SocketAddr};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    stream::Stream,
+    time::{timeout, Duration},
+};
+
+/// RFC 4571 prefixes every packet with its length as a 16 bit integer.
+const FRAME_HEADER_LEN: usize = 2;
+/// Largest packet a peer sends, a DTLS record with its header and cipher expansion.
+const MAX_FRAME_LEN: usize = 16384 + 2048;
+/// A peer has to send its first binding request within this time after connecting.
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TcpConnection(pub TcpStream, pub SocketAddr);
+
+pub enum TcpFrame {
+    Packet(Vec<u8>, SocketAddr),
+    Closed(SocketAddr),
+}
+
+/// Registers the write half of an ICE-TCP connection, the peer is reached through it afterwards.
+pub struct TcpConnected(pub SocketAddr, pub OwnedWriteHalf);
+
+impl Message for TcpConnected {
+    type Result = ();
+}
+
+pub struct TcpDisconnected(pub SocketAddr);
+
+impl Message for TcpDisconnected {
+    type Result = ();
+}
+
+pub fn accept_connections(listener: TcpListener) -> impl Stream<Item = TcpConnection> {
+    futures::stream::unfold(listener, |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => return Some((TcpConnection(stream, addr), listener)),
+                Err(e) => warn!("could not accept TCP connection: {}", e),
+            }
+        }
+    })
+}
+
+/// Splits the byte stream of a connection into RFC 4571 frames, ending with `TcpFrame::Closed`.
+///
+/// The connection is closed when the first frame does not arrive in time or a frame is
+/// longer than any packet a peer sends.
+pub fn read_frames<R>(read: R, addr: SocketAddr) -> impl Stream<Item = TcpFrame>
+where
+    R: AsyncRead + Unpin,
+{
+    read_frames_within(read, addr, FIRST_FRAME_TIMEOUT)
+}
+
+fn read_frames_within<R>(
+    read: R,
+    addr: SocketAddr,
+    first_frame_timeout: Duration,
+) -> impl Stream<Item = TcpFrame>
+where
+    R: AsyncRead + Unpin,
+{
+    futures::stream::unfold((Some(read), true), move |(read, first)| async move {
+        let mut read = read?;
+        let frame = if first {
+            timeout(first_frame_timeout, read_frame(&mut read))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        } else {
+            read_frame(&mut read).await
+        };
+        match frame {
+            Ok(frame) => Some((TcpFrame::Packet(frame, addr), (Some(read), false))),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    warn!("ice-tcp connection from {} dropped: {}", addr, e);
+                }
+                Some((TcpFrame::Closed(addr), (None, false)))
+            }
+        }
+    })
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(read: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    read.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes(header) as usize;
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid RFC 4571 frame length {}", len),
+        ));
+    }
+    let mut frame = vec![0; len];
+    read.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+pub fn write_frame(message: &[u8]) -> io::Result<Vec<u8>> {
+    if message.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message does not fit into a RFC 4571 frame",
+        ));
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::stream::StreamExt;
+
+    fn addr() -> SocketAddr {
+        "192.0.2.1:5000".parse().unwrap()
+    }
+
+    async fn frames(buf: &[u8]) -> Vec<Option<Vec<u8>>> {
+        read_frames(buf, addr())
+            .map(|frame| match frame {
+                TcpFrame::Packet(packet, _) => Some(packet),
+                TcpFrame::Closed(_) => None,
+            })
+            .collect()
+            .await
+    }
+
+    #[actix_rt::test]
+    async fn frames_are_split_by_their_length() {
+        let mut buf = write_frame(&[1, 2, 3]).unwrap();
+        buf.extend(write_frame(&[4]).unwrap());
+        buf.extend_from_slice(&[0, 5, 1]);
+
+        assert_eq!(
+            frames(&buf).await,
+            vec![Some(vec![1, 2, 3]), Some(vec![4]), None]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn invalid_lengths_close_the_connection() {
+        let too_long = ((MAX_FRAME_LEN + 1) as u16).to_be_bytes();
+        assert_eq!(frames(&too_long).await, vec![None]);
+        assert_eq!(frames(&[0, 0, 0, 1, 7]).await, vec![None]);
+    }
+
+    /// Connection of a peer which never sends anything.
+    struct Silent;
+
+    impl AsyncRead for Silent {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[actix_rt::test]
+    async fn silent_connections_time_out() {
+        let closed: Vec<TcpFrame> = read_frames_within(Silent, addr(), Duration::from_millis(50))
+            .collect()
+            .await;
+        assert!(matches!(closed.as_slice(), [TcpFrame::Closed(_)]));
+    }
+
+    #[test]
+    fn oversized_messages_are_not_framed() {
+        assert_eq!(write_frame(&[9; 3]).unwrap(), vec![0, 3, 9, 9, 9]);
+        assert!(write_frame(&vec![0; u16::MAX as usize + 1]).is_err());
+    }
+}
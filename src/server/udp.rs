@@ -1,20 +1,48 @@
 use crate::{
     client::{
-        actor::ClientActor,
-        group::GroupId,
-        sessions::{Session, SessionMessage, SessionsStorage},
+        actor::{
+            ClientActor, DeleteMessage, Disconnected, MigrateClient, NewClient,
+            SubscribeDisconnected,
+        },
+        group::{GroupId, GroupMembers, GroupSettings, GroupSettingsRequest},
+        sessions::{
+            Negotiation, NegotiationAnswer, NegotiationOffer, NegotiationRequest,
+            RemoteSessionRequest, Session, SessionData, SessionDescription, SessionMessage,
+            SessionsStorage,
+        },
     },
-    dtls::is_dtls,
+    dtls::{fingerprint::Fingerprint, is_dtls},
     rtp::core::{is_rtcp, parse_rtp},
-    server::{crypto::Crypto, meta::ServerMeta},
-    stun::{parse_stun_binding_request, write_stun_success_response, StunBindingRequest},
+    server::{
+        candidates::{gather_candidates, is_dual_stack, IceCandidate},
+        config::ServerConfig,
+        crypto::Crypto,
+        socket::{bind_tcp, bind_udp},
+        tcp::{
+            accept_connections, read_frames, write_frame, TcpConnected, TcpConnection,
+            TcpDisconnected, TcpFrame,
+        },
+    },
+    stun::{
+        parse_stun_binding_request, write_stun_error_response, write_stun_success_response,
+        StunBindingRequest, StunErrorCode,
+    },
+    turn::server::TurnServer,
 };
 use actix::prelude::*;
 use log::{info, warn};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::SystemTime};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::OwnedWriteHalf,
     net::udp::{RecvHalf, SendHalf},
-    net::UdpSocket,
+    net::{TcpListener, UdpSocket},
     stream::StreamExt,
     sync::Mutex,
     time::Duration,
@@ -25,47 +53,102 @@ pub struct UdpRecv {
     dtls: Arc<Addr<ClientActor>>,
     data: Arc<ServerData>,
     sessions: SessionsStorage,
+    consents: HashMap<SocketAddr, Instant>,
+    /// Readers of the ICE-TCP connections with the time they were accepted.
+    tcp: HashMap<SocketAddr, (SpawnHandle, Instant)>,
 }
 
+/// Time since the last binding request or media after which a connected peer is dropped.
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONSENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const SESSION_TTL: Duration = Duration::from_secs(60);
+const MAX_TCP_CONNECTIONS: usize = 1024;
+
 impl Actor for UdpRecv {
     type Context = Context<Self>;
 }
 
 impl UdpRecv {
     pub fn new(
-        recv: RecvHalf,
+        recv: Vec<RecvHalf>,
+        tcp: Vec<TcpListener>,
         send: Arc<Addr<UdpSend>>,
         dtls: Arc<Addr<ClientActor>>,
         data: Arc<ServerData>,
     ) -> Addr<UdpRecv> {
         UdpRecv::create(|ctx| {
-            let stream = futures::stream::unfold(recv, |mut server| async move {
-                let mut message_buf: Vec<u8> = vec![0; 0x10000];
-
-                match server.recv_from(&mut message_buf).await {
-                    Ok((n, addr_from)) => {
-                        message_buf.truncate(n);
-                        Some(((message_buf, addr_from), server))
-                    }
-                    Err(err) => {
-                        warn!("could not receive UDP message: {}", err);
-                        None
+            for recv in recv {
+                let stream = futures::stream::unfold(recv, |mut server| async move {
+                    let mut message_buf: Vec<u8> = vec![0; 0x10000];
+
+                    match server.recv_from(&mut message_buf).await {
+                        Ok((n, addr_from)) => {
+                            message_buf.truncate(n);
+                            Some(((message_buf, addr_from), server))
+                        }
+                        Err(err) => {
+                            warn!("could not receive UDP message: {}", err);
+                            None
+                        }
                     }
-                }
-            })
-            .map(WebRtcRequest::from);
+                })
+                .map(WebRtcRequest::from);
 
-            ctx.add_stream(stream);
-            ctx.add_stream(tokio::time::interval(Duration::from_secs(60)).map(|_| ClearData));
+                ctx.add_stream(stream);
+            }
+            for listener in tcp {
+                ctx.add_stream(accept_connections(listener));
+            }
+            ctx.add_stream(tokio::time::interval(CONSENT_CHECK_INTERVAL).map(|_| ClearData));
+            dtls.do_send(SubscribeDisconnected(ctx.address().recipient()));
 
             UdpRecv {
                 send,
                 dtls,
                 data,
                 sessions: HashMap::new(),
+                consents: HashMap::new(),
+                tcp: HashMap::new(),
             }
         })
     }
+
+    /// Moves an ICE restarted peer to its new address, tracks announced from it follow along.
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr, group_id: usize) {
+        self.consents.remove(&from);
+        self.sessions
+            .values_mut()
+            .filter(|session_data| session_data.group_id == group_id)
+            .for_each(|session_data| {
+                if let Some(description) = session_data.description.migrate_publisher(from, to) {
+                    session_data.description = Arc::new(description);
+                }
+                if let Some((_, description)) = &mut session_data.pending {
+                    if let Some(migrated) = description.migrate_publisher(from, to) {
+                        *description = Arc::new(migrated);
+                    }
+                }
+            });
+        self.dtls.do_send(MigrateClient(from, to));
+    }
+
+    fn close_tcp(&mut self, addr: SocketAddr, ctx: &mut Context<Self>) {
+        if let Some((reader, _)) = self.tcp.remove(&addr) {
+            ctx.cancel_future(reader);
+            self.send.do_send(TcpDisconnected(addr));
+        }
+    }
+
+    /// Media is only accepted from addresses that passed a binding check.
+    fn refresh_consent(&mut self, addr: SocketAddr) -> bool {
+        match self.consents.get_mut(&addr) {
+            Some(last_seen) => {
+                *last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl Handler<ServerDataRequest> for UdpRecv {
@@ -76,22 +159,119 @@ impl Handler<ServerDataRequest> for UdpRecv {
     }
 }
 
+impl Handler<GroupMembers> for UdpRecv {
+    type Result = ResponseFuture<Vec<(SocketAddr, Arc<SessionDescription>)>>;
+
+    fn handle(&mut self, msg: GroupMembers, _ctx: &mut Context<Self>) -> Self::Result {
+        let dtls = Arc::clone(&self.dtls);
+        Box::pin(async move {
+            dtls.send(msg).await.unwrap_or_else(|e| {
+                warn!("udp recv to dtls: {:#?}", e);
+                Vec::new()
+            })
+        })
+    }
+}
+
+impl Handler<GroupSettingsRequest> for UdpRecv {
+    type Result = ResponseFuture<GroupSettings>;
+
+    fn handle(&mut self, msg: GroupSettingsRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let dtls = Arc::clone(&self.dtls);
+        Box::pin(async move {
+            dtls.send(msg).await.unwrap_or_else(|e| {
+                warn!("udp recv to dtls: {:#?}", e);
+                GroupSettings::default()
+            })
+        })
+    }
+}
+
+/// Lets signaling follow the clients leaving their groups.
+impl Handler<SubscribeDisconnected> for UdpRecv {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeDisconnected, _ctx: &mut Context<Self>) -> Self::Result {
+        self.dtls.do_send(msg);
+    }
+}
+
+/// A client dropped by the DTLS side loses its consent and sessions as well.
+impl Handler<Disconnected> for UdpRecv {
+    type Result = ();
+
+    fn handle(&mut self, Disconnected { addr, group_id }: Disconnected, _ctx: &mut Context<Self>) {
+        self.consents.remove(&addr);
+        self.sessions.retain(|_, session_data| {
+            session_data.addr != Some(addr)
+                || group_id
+                    .iter()
+                    .any(|group_id| *group_id != session_data.group_id)
+        });
+    }
+}
+
 impl StreamHandler<WebRtcRequest> for UdpRecv {
     fn handle(&mut self, item: WebRtcRequest, ctx: &mut Context<Self>) {
         match item {
             WebRtcRequest::Stun(req, addr) => {
                 let session = Session::new(req.server_user.clone(), req.remote_user.clone());
 
-                if let Some((group_id, ttl)) = self.sessions.get_mut(&session) {
-                    *ttl = SystemTime::now();
-                    let group_id = *group_id;
+                let verified = req
+                    .validate()
+                    .and_then(|_| match self.sessions.get(&session) {
+                        Some(session_data) => {
+                            req.verify_integrity(session_data.password.as_bytes())
+                        }
+                        None => Err(StunErrorCode::Unauthorized),
+                    });
+                if let Err(code) = verified {
+                    warn!("rejected stun request from {}: {:?}", addr, code);
+                    match write_stun_error_response(req.transaction_id, code) {
+                        Ok(response) => self.send.do_send(StunResponse(response, addr)),
+                        Err(e) => warn!("error on writing stun error response: {}", e),
+                    }
+                    return;
+                }
+
+                if let Some(session_data) = self.sessions.get_mut(&session) {
+                    let response = match write_stun_success_response(
+                        req.transaction_id,
+                        addr,
+                        session_data.password.as_bytes(),
+                    ) {
+                        Ok(response) => response,
+                        Err(e) => {
+                            warn!("error on writing stun response: {}", e);
+                            return;
+                        }
+                    };
+
+                    session_data.ttl = SystemTime::now();
+                    session_data.addr = Some(addr);
+                    let migrated = session_data
+                        .restarted_from
+                        .take()
+                        .filter(|previous| *previous != addr);
+                    let group_id = session_data.group_id;
+                    let description = Arc::clone(&session_data.description);
+
+                    let connected = self.consents.insert(addr, Instant::now()).is_none();
+                    if let Some(previous) = migrated {
+                        self.migrate(previous, addr, group_id);
+                    }
                     let udp_send = Arc::clone(&self.send);
                     let dtls = Arc::clone(&self.dtls);
                     ctx.spawn(
                         async move {
+                            if connected {
+                                if let Err(e) = dtls.send(NewClient(addr)).await {
+                                    warn!("udp recv to dtls: {:#?}", e)
+                                }
+                            }
                             let resp = futures::future::join(
-                                udp_send.send(WebRtcRequest::Stun(req, addr)),
-                                dtls.send(GroupId(group_id, addr)),
+                                udp_send.send(StunResponse(response, addr)),
+                                dtls.send(GroupId(group_id, addr, description)),
                             )
                             .await;
 
@@ -108,6 +288,9 @@ impl StreamHandler<WebRtcRequest> for UdpRecv {
                 }
             }
             WebRtcRequest::Dtls(message, addr) => {
+                if !self.refresh_consent(addr) {
+                    return;
+                }
                 let dtls = Arc::clone(&self.dtls);
                 ctx.spawn(
                     async move {
@@ -119,6 +302,9 @@ impl StreamHandler<WebRtcRequest> for UdpRecv {
                 );
             }
             WebRtcRequest::Rtc(message, addr) => {
+                if !self.refresh_consent(addr) {
+                    return;
+                }
                 let dtls = Arc::clone(&self.dtls);
                 ctx.spawn(
                     async move {
@@ -134,21 +320,83 @@ impl StreamHandler<WebRtcRequest> for UdpRecv {
     }
 }
 
+impl StreamHandler<TcpConnection> for UdpRecv {
+    fn handle(&mut self, TcpConnection(stream, addr): TcpConnection, ctx: &mut Context<Self>) {
+        if self.tcp.len() >= MAX_TCP_CONNECTIONS {
+            warn!(
+                "ice-tcp connection from {} refused, too many connections",
+                addr
+            );
+            return;
+        }
+        info!("ice-tcp connection from {}", addr);
+        let (read, write) = stream.into_split();
+        self.send.do_send(TcpConnected(addr, write));
+        let reader = ctx.add_stream(read_frames(read, addr));
+        self.tcp.insert(addr, (reader, Instant::now()));
+    }
+}
+
+impl StreamHandler<TcpFrame> for UdpRecv {
+    fn handle(&mut self, item: TcpFrame, ctx: &mut Context<Self>) {
+        match item {
+            TcpFrame::Packet(message, addr) => {
+                StreamHandler::<WebRtcRequest>::handle(self, (message, addr).into(), ctx)
+            }
+            TcpFrame::Closed(addr) => {
+                info!("ice-tcp connection from {} closed", addr);
+                self.tcp.remove(&addr);
+                self.send.do_send(TcpDisconnected(addr));
+                if self.consents.remove(&addr).is_some() {
+                    self.dtls.do_send(DeleteMessage(addr));
+                }
+            }
+        }
+    }
+
+    /// A closed connection must not stop the actor serving everyone else.
+    fn finished(&mut self, _ctx: &mut Context<Self>) {}
+}
+
 impl StreamHandler<ClearData> for UdpRecv {
-    fn handle(&mut self, _: ClearData, _ctx: &mut Context<Self>) {
-        let sessions_to_remove: Vec<Session> = self
-            .sessions
+    fn handle(&mut self, _: ClearData, ctx: &mut Context<Self>) {
+        let expired: Vec<SocketAddr> = self
+            .consents
             .iter()
-            .filter(|(_, (_, time))| match time.elapsed() {
-                Ok(d) => d > Duration::from_secs(60),
-                Err(_e) => false,
-            })
-            .map(|(s, (_, _))| s.clone())
+            .filter(|(_, last_seen)| last_seen.elapsed() > CONSENT_TIMEOUT)
+            .map(|(addr, _)| *addr)
             .collect();
 
-        sessions_to_remove.into_iter().for_each(|s| {
-            self.sessions.remove(&s);
+        expired.iter().for_each(|addr| {
+            info!("consent of {} expired", addr);
+            self.consents.remove(addr);
+            self.dtls.do_send(DeleteMessage(*addr));
         });
+
+        // Connections are dropped once their consent expired or when they never got one
+        let consents = &self.consents;
+        let unconsented: Vec<SocketAddr> = self
+            .tcp
+            .iter()
+            .filter(|(addr, (_, accepted))| {
+                !consents.contains_key(addr) && accepted.elapsed() > CONSENT_TIMEOUT
+            })
+            .map(|(addr, _)| *addr)
+            .chain(expired.iter().copied())
+            .collect();
+        unconsented
+            .into_iter()
+            .for_each(|addr| self.close_tcp(addr, ctx));
+
+        let consents = &self.consents;
+        self.sessions
+            .retain(|_, session_data| match session_data.addr {
+                Some(addr) => consents.contains_key(&addr),
+                None => match session_data.ttl.elapsed() {
+                    Ok(d) => d <= SESSION_TTL,
+                    Err(_e) => true,
+                },
+            });
     }
 }
 
@@ -157,115 +405,350 @@ impl Handler<SessionMessage> for UdpRecv {
 
     fn handle(
         &mut self,
-        SessionMessage(session, id): SessionMessage,
+        SessionMessage(session, session_data): SessionMessage,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        self.sessions.insert(session, (id, SystemTime::now()));
+        store_session(&mut self.sessions, &self.consents, session, session_data);
         true
     }
 }
 
-pub struct UdpSend {
-    send: Arc<Mutex<SendHalf>>,
-    data: Arc<ServerData>,
+impl Handler<RemoteSessionRequest> for UdpRecv {
+    type Result = Option<Negotiation>;
+
+    fn handle(
+        &mut self,
+        RemoteSessionRequest(group_id, remote_session_id, fingerprints): RemoteSessionRequest,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let (session, session_data) = self.sessions.iter().find(|(_, session_data)| {
+            is_live_restart(
+                session_data,
+                &self.consents,
+                group_id,
+                remote_session_id,
+                &fingerprints,
+            )
+        })?;
+        Some(Negotiation {
+            session: session.clone(),
+            addr: session_data.addr.or(session_data.restarted_from),
+            description: Arc::clone(&session_data.description),
+            local_description: session_data.local_description.clone(),
+            pending: None,
+        })
+    }
 }
 
-impl UdpSend {
-    pub fn new(send: SendHalf, data: Arc<ServerData>) -> Addr<Self> {
-        Self::create(|_| Self {
-            send: Arc::new(Mutex::new(send)),
-            data,
+/// Only a session whose peer still has consent can be restarted, the new session takes over
+/// its address once its first binding check arrives.
+fn is_live_restart(
+    previous: &SessionData,
+    consents: &HashMap<SocketAddr, Instant>,
+    group_id: usize,
+    remote_session_id: u64,
+    fingerprints: &[Fingerprint],
+) -> bool {
+    previous.is_restarted_by(group_id, remote_session_id, fingerprints)
+        && previous
+            .addr
+            .or(previous.restarted_from)
+            .iter()
+            .any(|addr| consents.contains_key(addr))
+}
+
+fn store_session(
+    sessions: &mut SessionsStorage,
+    consents: &HashMap<SocketAddr, Instant>,
+    session: Session,
+    mut session_data: SessionData,
+) {
+    let restarted: Vec<Session> = sessions
+        .iter()
+        .filter(|(previous, previous_data)| {
+            **previous != session
+                && is_live_restart(
+                    previous_data,
+                    consents,
+                    session_data.group_id,
+                    session_data.remote_session_id,
+                    &session_data.description.fingerprints,
+                )
         })
+        .map(|(previous, _)| previous.clone())
+        .collect();
+
+    for previous in restarted {
+        if let Some(previous_data) = sessions.remove(&previous) {
+            session_data.restarted_from = previous_data.addr.or(previous_data.restarted_from);
+        }
     }
+    sessions.insert(session, session_data);
 }
 
-impl Actor for UdpSend {
-    type Context = Context<Self>;
+impl Handler<NegotiationRequest> for UdpRecv {
+    type Result = Option<Negotiation>;
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        info!("stopping")
+    fn handle(
+        &mut self,
+        NegotiationRequest(client_user, group_id): NegotiationRequest,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let (session, session_data) = self.sessions.iter().find(|(session, session_data)| {
+            session.client_user() == client_user && session_data.group_id == group_id
+        })?;
+        Some(Negotiation {
+            session: session.clone(),
+            addr: session_data.addr,
+            description: Arc::clone(&session_data.description),
+            local_description: session_data.local_description.clone(),
+            pending: session_data
+                .pending
+                .as_ref()
+                .map(|(offer, _)| offer.clone()),
+        })
     }
 }
 
-impl Handler<WebRtcRequest> for UdpSend {
-    type Result = ();
+impl Handler<NegotiationOffer> for UdpRecv {
+    type Result = bool;
 
-    fn handle(&mut self, msg: WebRtcRequest, ctx: &mut Context<Self>) -> Self::Result {
-        let sender = Arc::clone(&self.send);
+    fn handle(
+        &mut self,
+        NegotiationOffer(session, offer, description): NegotiationOffer,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        match self.sessions.get_mut(&session) {
+            Some(session_data) => {
+                session_data.pending = Some((offer, description));
+                true
+            }
+            None => false,
+        }
+    }
+}
 
-        match msg {
-            WebRtcRequest::Stun(req, addr) => {
-                let mut message_buf: Vec<u8> = vec![0; 0x10000];
-                let n = write_stun_success_response(
-                    req.transaction_id,
-                    addr,
-                    self.data.meta.password.as_bytes(),
-                    &mut message_buf,
-                );
+impl Handler<NegotiationAnswer> for UdpRecv {
+    type Result = bool;
 
-                let n = match n {
-                    Ok(n) => n,
-                    Err(e) => {
-                        warn!("error on writing stun response: {}", e);
-                        return;
+    fn handle(
+        &mut self,
+        NegotiationAnswer(session, offer, rejected): NegotiationAnswer,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let session_data = match self.sessions.get_mut(&session) {
+            Some(session_data) => session_data,
+            None => return false,
+        };
+        let description = match session_data.pending.take() {
+            Some((_, description)) if rejected.is_empty() => description,
+            Some((_, description)) => Arc::new(description.without_tracks(&rejected)),
+            None => return false,
+        };
+        session_data.local_description = offer;
+        session_data.description = Arc::clone(&description);
+
+        if let Some(addr) = session_data.addr {
+            let group_id = session_data.group_id;
+            let dtls = Arc::clone(&self.dtls);
+            ctx.spawn(
+                async move {
+                    if let Err(e) = dtls.send(GroupId(group_id, addr, description)).await {
+                        warn!("udp recv to dtls: {:#?}", e)
                     }
-                };
+                }
+                .into_actor(self),
+            );
+        }
+        true
+    }
+}
+
+pub struct UdpSend {
+    send: Vec<(SocketAddr, Arc<Mutex<SendHalf>>)>,
+    tcp: HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>,
+}
+
+impl UdpSend {
+    /// Takes the send halves of the bound sockets with their local addresses.
+    pub fn new(send: Vec<(SocketAddr, SendHalf)>) -> Addr<Self> {
+        Self::create(|_| Self {
+            send: send
+                .into_iter()
+                .map(|(local, send)| (local, Arc::new(Mutex::new(send))))
+                .collect(),
+            tcp: HashMap::new(),
+        })
+    }
 
-                message_buf.truncate(n);
+    /// Peers connected over ICE-TCP get RFC 4571 framed messages, the rest is sent over UDP
+    /// from the socket of the peer's address family.
+    fn send_to(&self, message: Vec<u8>, addr: SocketAddr, ctx: &mut Context<Self>) {
+        match self.tcp.get(&addr) {
+            Some(writer) => {
+                let writer = Arc::clone(writer);
                 ctx.spawn(
                     async move {
-                        let result = sender.lock().await.send_to(&message_buf, &addr).await;
+                        let result = match write_frame(&message) {
+                            Ok(frame) => writer.lock().await.write_all(&frame).await,
+                            Err(e) => Err(e),
+                        };
                         if let Err(e) = result {
-                            if e.kind() != std::io::ErrorKind::AddrNotAvailable {
-                                warn!("err {:?}", e)
-                            }
+                            warn!("err tcp {:?}", e)
                         }
                     }
                     .into_actor(self),
                 );
             }
-            WebRtcRequest::Dtls(message, addr) => {
+            None => {
+                let sender = match self
+                    .send
+                    .iter()
+                    .find(|(local, _)| local.is_ipv4() == addr.is_ipv4())
+                    .or_else(|| self.send.first())
+                {
+                    Some((_, sender)) => Arc::clone(sender),
+                    None => return,
+                };
                 ctx.spawn(
                     async move {
                         let result = sender.lock().await.send_to(&message, &addr).await;
                         if let Err(e) = result {
-                            warn!("err dtls {:?}", e)
+                            if e.kind() != std::io::ErrorKind::AddrNotAvailable {
+                                warn!("err {:?}", e)
+                            }
                         }
                     }
                     .into_actor(self),
                 );
             }
-            WebRtcRequest::Rtc(message, addr) => {
-                ctx.spawn(
-                    async move {
-                        let result = sender.lock().await.send_to(&message, &addr).await;
-                        if let Err(e) = result {
-                            warn!("err dtls {:?}", e)
-                        }
-                    }
-                    .into_actor(self),
-                );
+        }
+    }
+}
+
+impl Actor for UdpSend {
+    type Context = Context<Self>;
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!("stopping")
+    }
+}
+
+impl Handler<WebRtcRequest> for UdpSend {
+    type Result = ();
+
+    fn handle(&mut self, msg: WebRtcRequest, ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            WebRtcRequest::Dtls(message, addr) | WebRtcRequest::Rtc(message, addr) => {
+                self.send_to(message, addr, ctx)
             }
-            WebRtcRequest::Unknown => warn!("unknown request"),
+            WebRtcRequest::Stun(_, _) | WebRtcRequest::Unknown => warn!("unknown request"),
         }
     }
 }
 
-pub async fn create_udp(addr: SocketAddr) -> (Addr<UdpRecv>, Arc<Addr<UdpSend>>) {
-    let server = UdpSocket::bind(addr).await.expect("udp must be up");
-    let meta = ServerMeta::new();
-    let crypto = Crypto::init().expect("WebRTC server could not initialize OpenSSL primitives");
-    let data = Arc::new(ServerData { meta, crypto });
+impl Handler<StunResponse> for UdpSend {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        StunResponse(message, addr): StunResponse,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.send_to(message, addr, ctx)
+    }
+}
+
+impl Handler<TcpConnected> for UdpSend {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        TcpConnected(addr, writer): TcpConnected,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.tcp.insert(addr, Arc::new(Mutex::new(writer)));
+    }
+}
+
+impl Handler<TcpDisconnected> for UdpSend {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        TcpDisconnected(addr): TcpDisconnected,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.tcp.remove(&addr);
+    }
+}
 
-    let (recv, send) = server.split();
-    let udp_send = Arc::new(UdpSend::new(send, Arc::clone(&data)));
+/// Binds a UDP socket for every address, IPv4 and IPv6 peers share groups across them.
+///
+/// Fails if none of the addresses yields an ICE candidate to advertise.
+pub async fn create_udp(addrs: &[SocketAddr]) -> io::Result<(Addr<UdpRecv>, Arc<Addr<UdpSend>>)> {
+    let only_v6 = is_dual_stack(addrs);
+    let servers: Vec<UdpSocket> = addrs
+        .iter()
+        .map(|addr| bind_udp(*addr, only_v6).expect("udp must be up"))
+        .collect();
+    let config = ServerConfig::from_env();
+    let crypto = Crypto::init(&config)
+        .unwrap_or_else(|e| panic!("WebRTC server could not initialize DTLS: {}", e));
+    let tcp: Vec<TcpListener> = match config.ice_tcp_port {
+        Some(port) => addrs
+            .iter()
+            .map(|addr| {
+                bind_tcp(SocketAddr::new(addr.ip(), port), only_v6).expect("tcp must be up")
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let candidates = gather_candidates(addrs, &config.advertised_ips, config.ice_tcp_port);
+    if candidates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no ice candidate to advertise, check the listen addresses and STREAMER_ADVERTISED_IPS",
+        ));
+    }
+    candidates
+        .iter()
+        .for_each(|candidate| info!("ice candidate {} {}", candidate.transport, candidate.addr));
+    if let Some(port) = config.turn_port {
+        let addr = SocketAddr::new(addrs[0].ip(), port);
+        let socket = bind_udp(addr, only_v6).expect("turn must be up");
+        let advertised = candidates
+            .iter()
+            .find(|candidate| candidate.addr.is_ipv4() == addr.is_ipv4())
+            .unwrap_or(&candidates[0]);
+        info!("turn relay on port {}", port);
+        let local: Vec<SocketAddr> = candidates.iter().map(|candidate| candidate.addr).collect();
+        TurnServer::new(socket, &config, advertised.addr.ip(), &local);
+    }
+    let data = Arc::new(ServerData {
+        crypto,
+        config,
+        candidates,
+    });
+
+    let (recv, send): (Vec<RecvHalf>, Vec<(SocketAddr, SendHalf)>) = servers
+        .into_iter()
+        .zip(addrs)
+        .map(|(server, addr)| {
+            let local = server.local_addr().unwrap_or(*addr);
+            let (recv, send) = server.split();
+            (recv, (local, send))
+        })
+        .unzip();
+    let udp_send = Arc::new(UdpSend::new(send));
     let dtls = Arc::new(ClientActor::new(
         Arc::clone(&data.crypto.ssl_acceptor),
+        Arc::clone(&data.crypto.ssl_connector),
         Arc::clone(&udp_send),
     ));
-    let udp_recv = UdpRecv::new(recv, Arc::clone(&udp_send), dtls, data);
+    let udp_recv = UdpRecv::new(recv, tcp, Arc::clone(&udp_send), dtls, data);
 
-    (udp_recv, Arc::clone(&udp_send))
+    Ok((udp_recv, Arc::clone(&udp_send)))
 }
 
 #[derive(Debug, Clone)]
@@ -307,10 +790,15 @@ impl Message for WebRtcRequest {
     type Result = ();
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StunResponse(pub Vec<u8>, pub SocketAddr);
+
 #[derive(MessageResponse, Clone)]
 pub struct ServerData {
     pub crypto: Crypto,
-    pub meta: ServerMeta,
+    pub config: ServerConfig,
+    pub candidates: Vec<IceCandidate>,
 }
 
 #[derive(Message)]
@@ -322,3 +810,109 @@ struct ClearData;
 impl Message for ClearData {
     type Result = ();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtls::fingerprint::FingerprintHash;
+    use std::net::Ipv4Addr;
+    use webrtc_sdp::{address::ExplicitlyTypedAddress, SdpOrigin, SdpSession};
+
+    const REMOTE_SESSION_ID: u64 = 4_611_686_018_427_387_904;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    fn fingerprint(byte: u8) -> Fingerprint {
+        Fingerprint {
+            hash: FingerprintHash::Sha256,
+            value: vec![byte; 32],
+        }
+    }
+
+    fn session_data(fingerprints: Vec<Fingerprint>, addr: Option<SocketAddr>) -> SessionData {
+        SessionData {
+            group_id: 1,
+            password: "password".to_string(),
+            remote_session_id: REMOTE_SESSION_ID,
+            description: Arc::new(SessionDescription {
+                fingerprints,
+                ..SessionDescription::default()
+            }),
+            local_description: SdpSession::new(
+                0,
+                SdpOrigin {
+                    username: "-".to_string(),
+                    session_id: 1,
+                    session_version: 1,
+                    unicast_addr: ExplicitlyTypedAddress::from(Ipv4Addr::UNSPECIFIED),
+                },
+                "-".to_string(),
+            ),
+            pending: None,
+            addr,
+            restarted_from: None,
+            ttl: SystemTime::now(),
+        }
+    }
+
+    fn storage(consents: &HashMap<SocketAddr, Instant>) -> (SessionsStorage, Session) {
+        let mut sessions = SessionsStorage::new();
+        let victim = Session::new("server1".to_string(), "client1".to_string());
+        let data = session_data(vec![fingerprint(1), fingerprint(2)], Some(addr(5000)));
+        store_session(&mut sessions, consents, victim.clone(), data);
+        (sessions, victim)
+    }
+
+    #[test]
+    fn offer_with_the_same_fingerprints_restarts_the_session() {
+        let consents: HashMap<_, _> = vec![(addr(5000), Instant::now())].into_iter().collect();
+        let (mut sessions, victim) = storage(&consents);
+
+        let restarted = Session::new("server2".to_string(), "client2".to_string());
+        let data = session_data(vec![fingerprint(2), fingerprint(1)], None);
+        store_session(&mut sessions, &consents, restarted.clone(), data);
+        assert!(!sessions.contains_key(&victim));
+        assert_eq!(sessions[&restarted].restarted_from, Some(addr(5000)));
+    }
+
+    #[test]
+    fn offer_with_other_fingerprints_creates_a_new_session() {
+        let consents: HashMap<_, _> = vec![(addr(5000), Instant::now())].into_iter().collect();
+        for fingerprints in [
+            vec![fingerprint(3)],
+            vec![fingerprint(1)],
+            vec![fingerprint(1), fingerprint(2), fingerprint(3)],
+        ] {
+            let (mut sessions, victim) = storage(&consents);
+            assert!(!is_live_restart(
+                &sessions[&victim],
+                &consents,
+                1,
+                REMOTE_SESSION_ID,
+                &fingerprints
+            ));
+
+            let attacker = Session::new("server2".to_string(), "client2".to_string());
+            store_session(
+                &mut sessions,
+                &consents,
+                attacker.clone(),
+                session_data(fingerprints, None),
+            );
+            assert_eq!(sessions[&victim].addr, Some(addr(5000)));
+            assert_eq!(sessions[&attacker].restarted_from, None);
+        }
+    }
+
+    #[test]
+    fn sessions_without_consent_are_not_restarted() {
+        let (mut sessions, victim) = storage(&HashMap::new());
+        let restarted = Session::new("server2".to_string(), "client2".to_string());
+        let data = session_data(vec![fingerprint(1), fingerprint(2)], None);
+        store_session(&mut sessions, &HashMap::new(), restarted.clone(), data);
+        assert!(sessions.contains_key(&victim));
+        assert_eq!(sessions[&restarted].restarted_from, None);
+    }
+}
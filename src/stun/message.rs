@@ -0,0 +1,875 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use crc32fast::Hasher as Crc32Hasher;
+use openssl::{error::ErrorStack, hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+
+pub const STUN_BINDING: u16 = 0x0001;
+pub const TURN_ALLOCATE: u16 = 0x0003;
+pub const TURN_REFRESH: u16 = 0x0004;
+pub const TURN_SEND: u16 = 0x0006;
+pub const TURN_DATA: u16 = 0x0007;
+pub const TURN_CREATE_PERMISSION: u16 = 0x0008;
+pub const TURN_CHANNEL_BIND: u16 = 0x0009;
+
+pub const STUN_HEADER_LEN: usize = 20;
+pub const STUN_TRANSACTION_ID_LEN: usize = 12;
+const STUN_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+const STUN_CRC_XOR: u32 = 0x5354_554e;
+const STUN_ALIGNMENT: usize = 4;
+const STUN_ATTRIBUTE_HEADER_LEN: usize = 4;
+
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000a;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000c;
+const ATTR_LIFETIME: u16 = 0x000d;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_ADDRESS_FAMILY: u16 = 0x0017;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_MESSAGE_INTEGRITY_SHA256: u16 = 0x001c;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_PRIORITY: u16 = 0x0024;
+const ATTR_USE_CANDIDATE: u16 = 0x0025;
+const ATTR_SOFTWARE: u16 = 0x8022;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+const ATTR_ICE_CONTROLLED: u16 = 0x8029;
+const ATTR_ICE_CONTROLLING: u16 = 0x802a;
+
+const SHA1_INTEGRITY_LEN: usize = 20;
+const SHA256_INTEGRITY_LEN: usize = 32;
+pub const FAMILY_IPV4: u8 = 0x01;
+pub const FAMILY_IPV6: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StunClass {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+/// Typed STUN attribute.
+///
+/// MESSAGE-INTEGRITY, MESSAGE-INTEGRITY-SHA256 and FINGERPRINT hold the received values after
+/// parsing, on writing they are computed in place and their values are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StunAttribute {
+    Username(String),
+    MessageIntegrity(Vec<u8>),
+    MessageIntegritySha256(Vec<u8>),
+    Fingerprint(u32),
+    XorMappedAddress(SocketAddr),
+    Priority(u32),
+    UseCandidate,
+    IceControlled(u64),
+    IceControlling(u64),
+    ErrorCode(u16, String),
+    UnknownAttributes(Vec<u16>),
+    Software(String),
+    ChannelNumber(u16),
+    Lifetime(u32),
+    XorPeerAddress(SocketAddr),
+    Data(Vec<u8>),
+    Realm(String),
+    Nonce(String),
+    XorRelayedAddress(SocketAddr),
+    RequestedAddressFamily(u8),
+    RequestedTransport(u8),
+    Unknown(u16, Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct StunMessage {
+    pub class: StunClass,
+    pub method: u16,
+    pub transaction_id: [u8; STUN_TRANSACTION_ID_LEN],
+    pub attributes: Vec<StunAttribute>,
+    raw: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl StunMessage {
+    pub fn new(
+        class: StunClass,
+        method: u16,
+        transaction_id: [u8; STUN_TRANSACTION_ID_LEN],
+    ) -> StunMessage {
+        StunMessage {
+            class,
+            method,
+            transaction_id,
+            attributes: Vec::new(),
+            raw: Vec::new(),
+            offsets: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, attribute: StunAttribute) -> StunMessage {
+        self.attributes.push(attribute);
+        self
+    }
+
+    pub fn is_stun(buf: &[u8]) -> bool {
+        buf.len() >= STUN_HEADER_LEN
+            && buf[0] & 0xc0 == 0
+            && buf[4..8] == STUN_COOKIE
+            && NetworkEndian::read_u16(&buf[2..4]) as usize & (STUN_ALIGNMENT - 1) == 0
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<StunMessage, StunError> {
+        if !StunMessage::is_stun(buf) {
+            return Err(StunError::NotStun);
+        }
+
+        let message_type = NetworkEndian::read_u16(&buf[0..2]);
+        let len = STUN_HEADER_LEN + NetworkEndian::read_u16(&buf[2..4]) as usize;
+        if buf.len() < len {
+            return Err(StunError::MalformedMessage("stun message is truncated"));
+        }
+
+        let mut message = StunMessage::new(
+            decode_class(message_type),
+            decode_method(message_type),
+            [0; STUN_TRANSACTION_ID_LEN],
+        );
+        message
+            .transaction_id
+            .copy_from_slice(&buf[8..STUN_HEADER_LEN]);
+        message.raw = buf[..len].to_vec();
+
+        let mut integrity = None;
+        let mut offset = STUN_HEADER_LEN;
+        while offset < len {
+            if offset + STUN_ATTRIBUTE_HEADER_LEN > len {
+                return Err(StunError::MalformedMessage("stun attribute is truncated"));
+            }
+            let attribute_type = NetworkEndian::read_u16(&buf[offset..offset + 2]);
+            let value_len = NetworkEndian::read_u16(&buf[offset + 2..offset + 4]) as usize;
+            let value_start = offset + STUN_ATTRIBUTE_HEADER_LEN;
+            if value_start + padded(value_len) > len {
+                return Err(StunError::MalformedMessage("stun attribute is truncated"));
+            }
+
+            // Attributes following the integrity are not covered by it and ignored,
+            // except for a MESSAGE-INTEGRITY-SHA256 after MESSAGE-INTEGRITY and FINGERPRINT,
+            // RFC 8489 section 14
+            let ignored = match integrity {
+                Some(ATTR_MESSAGE_INTEGRITY) => {
+                    attribute_type != ATTR_MESSAGE_INTEGRITY_SHA256
+                        && attribute_type != ATTR_FINGERPRINT
+                }
+                Some(_) => attribute_type != ATTR_FINGERPRINT,
+                None => false,
+            };
+            if !ignored {
+                let value = &buf[value_start..value_start + value_len];
+                let attribute = read_attribute(attribute_type, value, &message.transaction_id)?;
+                message.attributes.push(attribute);
+                message.offsets.push(offset);
+                if attribute_type == ATTR_MESSAGE_INTEGRITY
+                    || attribute_type == ATTR_MESSAGE_INTEGRITY_SHA256
+                {
+                    integrity = Some(attribute_type);
+                }
+            }
+            offset = value_start + padded(value_len);
+        }
+
+        Ok(message)
+    }
+
+    /// Serializes the message, `key` is used for the MESSAGE-INTEGRITY attributes.
+    pub fn write(&self, key: Option<&[u8]>) -> Result<Vec<u8>, StunError> {
+        let mut out = Vec::with_capacity(STUN_HEADER_LEN + 64);
+        out.extend_from_slice(&encode_type(self.class, self.method).to_be_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&STUN_COOKIE);
+        out.extend_from_slice(&self.transaction_id);
+
+        for attribute in &self.attributes {
+            match attribute {
+                StunAttribute::MessageIntegrity(_) => {
+                    let key = key.ok_or(StunError::MissingKey)?;
+                    set_length(&mut out, SHA1_INTEGRITY_LEN);
+                    let hmac = hmac(MessageDigest::sha1(), key, &[&out])?;
+                    write_attribute(&mut out, ATTR_MESSAGE_INTEGRITY, &hmac);
+                }
+                StunAttribute::MessageIntegritySha256(_) => {
+                    let key = key.ok_or(StunError::MissingKey)?;
+                    set_length(&mut out, SHA256_INTEGRITY_LEN);
+                    let hmac = hmac(MessageDigest::sha256(), key, &[&out])?;
+                    write_attribute(&mut out, ATTR_MESSAGE_INTEGRITY_SHA256, &hmac);
+                }
+                StunAttribute::Fingerprint(_) => {
+                    set_length(&mut out, 4);
+                    let fingerprint = fingerprint(&out);
+                    write_attribute(&mut out, ATTR_FINGERPRINT, &fingerprint.to_be_bytes());
+                }
+                attribute => {
+                    let (attribute_type, value) = attribute_value(attribute, &self.transaction_id);
+                    write_attribute(&mut out, attribute_type, &value);
+                }
+            }
+        }
+
+        set_length(&mut out, 0);
+        Ok(out)
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                StunAttribute::Username(username) => Some(username.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Types of received attributes in the comprehension-required range this codec does not know.
+    pub fn unknown_required(&self) -> Vec<u16> {
+        self.attributes
+            .iter()
+            .filter_map(|attribute| match attribute {
+                StunAttribute::Unknown(attribute_type, _) if *attribute_type < 0x8000 => {
+                    Some(*attribute_type)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn has_integrity(&self) -> bool {
+        self.attributes.iter().any(|attribute| {
+            matches!(
+                attribute,
+                StunAttribute::MessageIntegrity(_) | StunAttribute::MessageIntegritySha256(_)
+            )
+        })
+    }
+
+    /// Checks every received MESSAGE-INTEGRITY attribute against `key`.
+    pub fn verify_integrity(&self, key: &[u8]) -> bool {
+        let mut verified = false;
+        for (attribute, offset) in self.attributes.iter().zip(&self.offsets) {
+            let (digest, received) = match attribute {
+                StunAttribute::MessageIntegrity(hmac) => (MessageDigest::sha1(), hmac),
+                StunAttribute::MessageIntegritySha256(hmac) => (MessageDigest::sha256(), hmac),
+                _ => continue,
+            };
+
+            let mut header = [0; STUN_HEADER_LEN];
+            header.copy_from_slice(&self.raw[..STUN_HEADER_LEN]);
+            let len = offset + STUN_ATTRIBUTE_HEADER_LEN + received.len() - STUN_HEADER_LEN;
+            NetworkEndian::write_u16(&mut header[2..4], len as u16);
+
+            let computed = match hmac(digest, key, &[&header, &self.raw[STUN_HEADER_LEN..*offset]])
+            {
+                Ok(computed) => computed,
+                Err(_) => return false,
+            };
+            if received.len() > computed.len() || !memcmp::eq(&computed[..received.len()], received)
+            {
+                return false;
+            }
+            verified = true;
+        }
+        verified
+    }
+
+    /// Checks the received FINGERPRINT, which has to be the last attribute.
+    pub fn verify_fingerprint(&self) -> bool {
+        match (self.attributes.last(), self.offsets.last()) {
+            (Some(StunAttribute::Fingerprint(received)), Some(offset)) => {
+                fingerprint(&self.raw[..*offset]) == *received
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StunError {
+    Openssl(ErrorStack),
+    NotStun,
+    MissingKey,
+    MalformedMessage(&'static str),
+}
+
+impl Display for StunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StunError::Openssl(e) => write!(f, "{}", e),
+            StunError::NotStun => write!(f, "Not a stun message"),
+            StunError::MissingKey => write!(f, "Message integrity requires a key"),
+            StunError::MalformedMessage(e) => write!(f, "Malformed message: {}", e),
+        }
+    }
+}
+
+impl Error for StunError {}
+
+impl From<ErrorStack> for StunError {
+    fn from(e: ErrorStack) -> Self {
+        StunError::Openssl(e)
+    }
+}
+
+fn read_attribute(
+    attribute_type: u16,
+    value: &[u8],
+    transaction_id: &[u8; STUN_TRANSACTION_ID_LEN],
+) -> Result<StunAttribute, StunError> {
+    let attribute = match attribute_type {
+        ATTR_USERNAME => StunAttribute::Username(read_string(value)?),
+        ATTR_MESSAGE_INTEGRITY if value.len() == SHA1_INTEGRITY_LEN => {
+            StunAttribute::MessageIntegrity(value.to_vec())
+        }
+        ATTR_MESSAGE_INTEGRITY_SHA256
+            if value.len() >= 16 && value.len() <= SHA256_INTEGRITY_LEN && value.len() & 3 == 0 =>
+        {
+            StunAttribute::MessageIntegritySha256(value.to_vec())
+        }
+        ATTR_FINGERPRINT if value.len() == 4 => {
+            StunAttribute::Fingerprint(NetworkEndian::read_u32(value))
+        }
+        ATTR_XOR_MAPPED_ADDRESS => {
+            StunAttribute::XorMappedAddress(read_xor_address(value, transaction_id)?)
+        }
+        ATTR_PRIORITY if value.len() == 4 => {
+            StunAttribute::Priority(NetworkEndian::read_u32(value))
+        }
+        ATTR_USE_CANDIDATE if value.is_empty() => StunAttribute::UseCandidate,
+        ATTR_ICE_CONTROLLED if value.len() == 8 => {
+            StunAttribute::IceControlled(NetworkEndian::read_u64(value))
+        }
+        ATTR_ICE_CONTROLLING if value.len() == 8 => {
+            StunAttribute::IceControlling(NetworkEndian::read_u64(value))
+        }
+        ATTR_ERROR_CODE if value.len() >= 4 => StunAttribute::ErrorCode(
+            (value[2] & 0x07) as u16 * 100 + value[3] as u16,
+            read_string(&value[4..])?,
+        ),
+        ATTR_UNKNOWN_ATTRIBUTES if value.len() & 1 == 0 => StunAttribute::UnknownAttributes(
+            value.chunks_exact(2).map(NetworkEndian::read_u16).collect(),
+        ),
+        ATTR_SOFTWARE => StunAttribute::Software(read_string(value)?),
+        ATTR_CHANNEL_NUMBER if value.len() == 4 => {
+            StunAttribute::ChannelNumber(NetworkEndian::read_u16(value))
+        }
+        ATTR_LIFETIME if value.len() == 4 => {
+            StunAttribute::Lifetime(NetworkEndian::read_u32(value))
+        }
+        ATTR_XOR_PEER_ADDRESS => {
+            StunAttribute::XorPeerAddress(read_xor_address(value, transaction_id)?)
+        }
+        ATTR_DATA => StunAttribute::Data(value.to_vec()),
+        ATTR_REALM => StunAttribute::Realm(read_string(value)?),
+        ATTR_NONCE => StunAttribute::Nonce(read_string(value)?),
+        ATTR_XOR_RELAYED_ADDRESS => {
+            StunAttribute::XorRelayedAddress(read_xor_address(value, transaction_id)?)
+        }
+        ATTR_REQUESTED_ADDRESS_FAMILY if value.len() == 4 => {
+            StunAttribute::RequestedAddressFamily(value[0])
+        }
+        ATTR_REQUESTED_TRANSPORT if value.len() == 4 => StunAttribute::RequestedTransport(value[0]),
+        ATTR_MESSAGE_INTEGRITY
+        | ATTR_MESSAGE_INTEGRITY_SHA256
+        | ATTR_FINGERPRINT
+        | ATTR_PRIORITY
+        | ATTR_USE_CANDIDATE
+        | ATTR_ICE_CONTROLLED
+        | ATTR_ICE_CONTROLLING
+        | ATTR_ERROR_CODE
+        | ATTR_UNKNOWN_ATTRIBUTES
+        | ATTR_CHANNEL_NUMBER
+        | ATTR_LIFETIME
+        | ATTR_REQUESTED_ADDRESS_FAMILY
+        | ATTR_REQUESTED_TRANSPORT => {
+            return Err(StunError::MalformedMessage(
+                "stun attribute has invalid length",
+            ))
+        }
+        attribute_type => StunAttribute::Unknown(attribute_type, value.to_vec()),
+    };
+    Ok(attribute)
+}
+
+fn attribute_value(
+    attribute: &StunAttribute,
+    transaction_id: &[u8; STUN_TRANSACTION_ID_LEN],
+) -> (u16, Vec<u8>) {
+    match attribute {
+        StunAttribute::Username(username) => (ATTR_USERNAME, username.as_bytes().to_vec()),
+        StunAttribute::MessageIntegrity(hmac) => (ATTR_MESSAGE_INTEGRITY, hmac.clone()),
+        StunAttribute::MessageIntegritySha256(hmac) => {
+            (ATTR_MESSAGE_INTEGRITY_SHA256, hmac.clone())
+        }
+        StunAttribute::Fingerprint(crc) => (ATTR_FINGERPRINT, crc.to_be_bytes().to_vec()),
+        StunAttribute::XorMappedAddress(addr) => (
+            ATTR_XOR_MAPPED_ADDRESS,
+            write_xor_address(*addr, transaction_id),
+        ),
+        StunAttribute::Priority(priority) => (ATTR_PRIORITY, priority.to_be_bytes().to_vec()),
+        StunAttribute::UseCandidate => (ATTR_USE_CANDIDATE, Vec::new()),
+        StunAttribute::IceControlled(tiebreaker) => {
+            (ATTR_ICE_CONTROLLED, tiebreaker.to_be_bytes().to_vec())
+        }
+        StunAttribute::IceControlling(tiebreaker) => {
+            (ATTR_ICE_CONTROLLING, tiebreaker.to_be_bytes().to_vec())
+        }
+        StunAttribute::ErrorCode(code, reason) => {
+            let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+            value.extend_from_slice(reason.as_bytes());
+            (ATTR_ERROR_CODE, value)
+        }
+        StunAttribute::UnknownAttributes(types) => (
+            ATTR_UNKNOWN_ATTRIBUTES,
+            types
+                .iter()
+                .flat_map(|t| t.to_be_bytes().to_vec())
+                .collect(),
+        ),
+        StunAttribute::Software(software) => (ATTR_SOFTWARE, software.as_bytes().to_vec()),
+        StunAttribute::ChannelNumber(number) => {
+            let mut value = number.to_be_bytes().to_vec();
+            value.extend_from_slice(&[0, 0]);
+            (ATTR_CHANNEL_NUMBER, value)
+        }
+        StunAttribute::Lifetime(lifetime) => (ATTR_LIFETIME, lifetime.to_be_bytes().to_vec()),
+        StunAttribute::XorPeerAddress(addr) => (
+            ATTR_XOR_PEER_ADDRESS,
+            write_xor_address(*addr, transaction_id),
+        ),
+        StunAttribute::Data(data) => (ATTR_DATA, data.clone()),
+        StunAttribute::Realm(realm) => (ATTR_REALM, realm.as_bytes().to_vec()),
+        StunAttribute::Nonce(nonce) => (ATTR_NONCE, nonce.as_bytes().to_vec()),
+        StunAttribute::XorRelayedAddress(addr) => (
+            ATTR_XOR_RELAYED_ADDRESS,
+            write_xor_address(*addr, transaction_id),
+        ),
+        StunAttribute::RequestedAddressFamily(family) => {
+            (ATTR_REQUESTED_ADDRESS_FAMILY, vec![*family, 0, 0, 0])
+        }
+        StunAttribute::RequestedTransport(protocol) => {
+            (ATTR_REQUESTED_TRANSPORT, vec![*protocol, 0, 0, 0])
+        }
+        StunAttribute::Unknown(attribute_type, value) => (*attribute_type, value.clone()),
+    }
+}
+
+fn read_string(value: &[u8]) -> Result<String, StunError> {
+    str::from_utf8(value)
+        .map(str::to_owned)
+        .map_err(|_| StunError::MalformedMessage("stun attribute is not utf-8"))
+}
+
+fn read_xor_address(
+    value: &[u8],
+    transaction_id: &[u8; STUN_TRANSACTION_ID_LEN],
+) -> Result<SocketAddr, StunError> {
+    const MALFORMED: StunError = StunError::MalformedMessage("stun address is invalid");
+
+    if value.len() < 4 {
+        return Err(MALFORMED);
+    }
+    let port = NetworkEndian::read_u16(&value[2..4]) ^ NetworkEndian::read_u16(&STUN_COOKIE);
+    let ip = match (value[1], value.len()) {
+        (FAMILY_IPV4, 8) => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&value[4..8]);
+            xor_range(&mut octets, &STUN_COOKIE);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (FAMILY_IPV6, 20) => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&value[4..20]);
+            xor_range(&mut octets[..4], &STUN_COOKIE);
+            xor_range(&mut octets[4..], transaction_id);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(MALFORMED),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// IPv4-mapped addresses of a dual-stack IPv6 socket are written with the IPv4 family.
+fn write_xor_address(addr: SocketAddr, transaction_id: &[u8; STUN_TRANSACTION_ID_LEN]) -> Vec<u8> {
+    let mut value = vec![0; 4];
+    NetworkEndian::write_u16(&mut value[2..4], addr.port());
+    xor_range(&mut value[2..4], &STUN_COOKIE);
+    match unmapped(addr.ip()) {
+        IpAddr::V4(ip) => {
+            value[1] = FAMILY_IPV4;
+            value.extend_from_slice(&ip.octets());
+            xor_range(&mut value[4..8], &STUN_COOKIE);
+        }
+        IpAddr::V6(ip) => {
+            value[1] = FAMILY_IPV6;
+            value.extend_from_slice(&ip.octets());
+            xor_range(&mut value[4..8], &STUN_COOKIE);
+            xor_range(&mut value[8..20], transaction_id);
+        }
+    }
+    value
+}
+
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ))
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+fn write_attribute(out: &mut Vec<u8>, attribute_type: u16, value: &[u8]) {
+    out.extend_from_slice(&attribute_type.to_be_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+    out.resize(out.len() + padded(value.len()) - value.len(), 0);
+}
+
+/// Sets the header length as if an attribute of `pending` bytes was already appended.
+fn set_length(out: &mut [u8], pending: usize) {
+    let pending = if pending > 0 {
+        STUN_ATTRIBUTE_HEADER_LEN + pending
+    } else {
+        0
+    };
+    let len = out.len() - STUN_HEADER_LEN + pending;
+    NetworkEndian::write_u16(&mut out[2..4], len as u16);
+}
+
+fn hmac(digest: MessageDigest, key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(digest, &key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    signer.sign_to_vec()
+}
+
+fn fingerprint(buf: &[u8]) -> u32 {
+    let mut crc = Crc32Hasher::new();
+    crc.update(buf);
+    crc.finalize() ^ STUN_CRC_XOR
+}
+
+fn encode_type(class: StunClass, method: u16) -> u16 {
+    let class = match class {
+        StunClass::Request => 0b00,
+        StunClass::Indication => 0b01,
+        StunClass::SuccessResponse => 0b10,
+        StunClass::ErrorResponse => 0b11,
+    };
+    (method & 0x000f)
+        | ((method & 0x0070) << 1)
+        | ((method & 0x0f80) << 2)
+        | ((class & 0b01) << 4)
+        | ((class & 0b10) << 7)
+}
+
+fn decode_class(message_type: u16) -> StunClass {
+    match ((message_type >> 7) & 0b10) | ((message_type >> 4) & 0b01) {
+        0b00 => StunClass::Request,
+        0b01 => StunClass::Indication,
+        0b10 => StunClass::SuccessResponse,
+        _ => StunClass::ErrorResponse,
+    }
+}
+
+fn decode_method(message_type: u16) -> u16 {
+    (message_type & 0x000f) | ((message_type >> 1) & 0x0070) | ((message_type >> 2) & 0x0f80)
+}
+
+fn padded(len: usize) -> usize {
+    (len + STUN_ALIGNMENT - 1) & !(STUN_ALIGNMENT - 1)
+}
+
+fn xor_range(target: &mut [u8], xor: &[u8]) {
+    target.iter_mut().zip(xor).for_each(|(t, x)| *t ^= x);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION_ID: [u8; STUN_TRANSACTION_ID_LEN] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// Sample request of RFC 5769 section 2.1.
+    const SAMPLE_REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74,
+        0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e,
+        0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20,
+        0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+        0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5,
+        0x7a, 0x3b, 0xcf,
+    ];
+    const SAMPLE_REQUEST_PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    /// Sample IPv4 and IPv6 responses of RFC 5769 sections 2.2 and 2.3.
+    const SAMPLE_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+    const SAMPLE_IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+    const SAMPLE_RESPONSE_PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    #[test]
+    fn parses_sample_request() {
+        let message = StunMessage::parse(&SAMPLE_REQUEST).unwrap();
+        assert_eq!(message.class, StunClass::Request);
+        assert_eq!(message.method, STUN_BINDING);
+        assert_eq!(message.transaction_id, TRANSACTION_ID);
+        assert_eq!(message.username(), Some("evtj:h6vY"));
+        assert_eq!(
+            message.attributes[..3],
+            [
+                StunAttribute::Software("STUN test client".to_owned()),
+                StunAttribute::Priority(0x6e00_01ff),
+                StunAttribute::IceControlled(0x932f_f9b1_5126_3b36),
+            ]
+        );
+        assert!(message.verify_fingerprint());
+        assert!(message.verify_integrity(SAMPLE_REQUEST_PASSWORD));
+        assert!(!message.verify_integrity(b"wrong password"));
+        assert!(message.unknown_required().is_empty());
+    }
+
+    #[test]
+    fn ignores_attributes_after_the_integrity() {
+        // USERNAME and LIFETIME inserted between MESSAGE-INTEGRITY and FINGERPRINT
+        let mut buf = SAMPLE_REQUEST[..100].to_vec();
+        buf.extend_from_slice(&[0x00, 0x06, 0x00, 0x04, b'e', b'v', b'i', b'l']);
+        buf.extend_from_slice(&[0x00, 0x0d, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01]);
+        let len = buf.len() + 8 - STUN_HEADER_LEN;
+        NetworkEndian::write_u16(&mut buf[2..4], len as u16);
+        let crc = fingerprint(&buf);
+        write_attribute(&mut buf, ATTR_FINGERPRINT, &crc.to_be_bytes());
+
+        let message = StunMessage::parse(&buf).unwrap();
+        assert_eq!(message.username(), Some("evtj:h6vY"));
+        assert!(!message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, StunAttribute::Lifetime(_))));
+        assert!(matches!(
+            message.attributes.last(),
+            Some(StunAttribute::Fingerprint(_))
+        ));
+        assert!(message.verify_integrity(SAMPLE_REQUEST_PASSWORD));
+        assert!(message.verify_fingerprint());
+    }
+
+    #[test]
+    fn parses_sample_responses() {
+        let expected = [
+            (&SAMPLE_IPV4_RESPONSE[..], "192.0.2.1:32853"),
+            (
+                &SAMPLE_IPV6_RESPONSE[..],
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853",
+            ),
+        ];
+        for (buf, addr) in expected.iter() {
+            let message = StunMessage::parse(buf).unwrap();
+            assert_eq!(message.class, StunClass::SuccessResponse);
+            assert_eq!(
+                message.attributes[1],
+                StunAttribute::XorMappedAddress(addr.parse().unwrap())
+            );
+            assert!(message.verify_fingerprint());
+            assert!(message.verify_integrity(SAMPLE_RESPONSE_PASSWORD));
+        }
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut buf = SAMPLE_REQUEST;
+        buf[45] ^= 1;
+        let message = StunMessage::parse(&buf).unwrap();
+        assert!(!message.verify_fingerprint());
+        assert!(!message.verify_integrity(SAMPLE_REQUEST_PASSWORD));
+    }
+
+    #[test]
+    fn writes_and_verifies_integrity() {
+        let addr: SocketAddr = "[::ffff:192.0.2.1]:32853".parse().unwrap();
+        let buf = StunMessage::new(StunClass::SuccessResponse, STUN_BINDING, TRANSACTION_ID)
+            .with(StunAttribute::XorMappedAddress(addr))
+            .with(StunAttribute::MessageIntegrity(Vec::new()))
+            .with(StunAttribute::MessageIntegritySha256(Vec::new()))
+            .with(StunAttribute::Fingerprint(0))
+            .write(Some(b"key"))
+            .unwrap();
+
+        let message = StunMessage::parse(&buf).unwrap();
+        // IPv4-mapped addresses go out with the IPv4 family
+        assert_eq!(
+            message.attributes[0],
+            StunAttribute::XorMappedAddress("192.0.2.1:32853".parse().unwrap())
+        );
+        assert!(message.verify_integrity(b"key"));
+        assert!(!message.verify_integrity(b"other key"));
+        assert!(message.verify_fingerprint());
+    }
+
+    #[test]
+    fn integrity_requires_key() {
+        let result = StunMessage::new(StunClass::Request, STUN_BINDING, TRANSACTION_ID)
+            .with(StunAttribute::MessageIntegrity(Vec::new()))
+            .write(None);
+        assert!(matches!(result, Err(StunError::MissingKey)));
+    }
+
+    #[test]
+    fn turn_attributes_round_trip() {
+        let attributes = vec![
+            StunAttribute::ErrorCode(486, "Allocation Quota Reached".to_owned()),
+            StunAttribute::UnknownAttributes(vec![0x0030, 0x0031]),
+            StunAttribute::ChannelNumber(0x4001),
+            StunAttribute::Lifetime(600),
+            StunAttribute::XorPeerAddress("[2001:db8::1]:5000".parse().unwrap()),
+            StunAttribute::Data(vec![1, 2, 3, 4, 5]),
+            StunAttribute::Realm("realm".to_owned()),
+            StunAttribute::Nonce("nonce".to_owned()),
+            StunAttribute::XorRelayedAddress("203.0.113.1:49152".parse().unwrap()),
+            StunAttribute::RequestedAddressFamily(FAMILY_IPV6),
+            StunAttribute::RequestedTransport(17),
+            StunAttribute::UseCandidate,
+            StunAttribute::IceControlling(42),
+            StunAttribute::Unknown(0x8050, vec![9]),
+        ];
+        let mut message = StunMessage::new(StunClass::ErrorResponse, TURN_ALLOCATE, TRANSACTION_ID);
+        message.attributes = attributes.clone();
+        let buf = message.write(None).unwrap();
+        assert_eq!(&buf[..2], &[0x01, 0x13]);
+
+        let parsed = StunMessage::parse(&buf).unwrap();
+        assert_eq!(parsed.class, StunClass::ErrorResponse);
+        assert_eq!(parsed.method, TURN_ALLOCATE);
+        assert_eq!(parsed.attributes, attributes);
+        assert!(parsed.unknown_required().is_empty());
+    }
+
+    #[test]
+    fn encodes_message_types() {
+        let classes = [
+            StunClass::Request,
+            StunClass::Indication,
+            StunClass::SuccessResponse,
+            StunClass::ErrorResponse,
+        ];
+        let methods = [
+            STUN_BINDING,
+            TURN_ALLOCATE,
+            TURN_REFRESH,
+            TURN_SEND,
+            TURN_DATA,
+            TURN_CREATE_PERMISSION,
+            TURN_CHANNEL_BIND,
+        ];
+        for class in classes.iter() {
+            for method in methods.iter() {
+                let message_type = encode_type(*class, *method);
+                assert_eq!(message_type & 0xc000, 0);
+                assert_eq!(decode_class(message_type), *class);
+                assert_eq!(decode_method(message_type), *method);
+            }
+        }
+        assert_eq!(encode_type(StunClass::Indication, TURN_SEND), 0x0016);
+        assert_eq!(
+            encode_type(StunClass::SuccessResponse, STUN_BINDING),
+            0x0101
+        );
+    }
+
+    #[test]
+    fn reports_unknown_required_attributes() {
+        let buf = StunMessage::new(StunClass::Request, STUN_BINDING, TRANSACTION_ID)
+            .with(StunAttribute::Unknown(0x0030, vec![1, 2]))
+            .with(StunAttribute::Unknown(0x8030, vec![1]))
+            .write(None)
+            .unwrap();
+        assert_eq!(
+            StunMessage::parse(&buf).unwrap().unknown_required(),
+            vec![0x0030]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(
+            StunMessage::parse(&SAMPLE_REQUEST[..19]),
+            Err(StunError::NotStun)
+        ));
+
+        let mut buf = SAMPLE_REQUEST;
+        buf[4] = 0;
+        assert!(matches!(StunMessage::parse(&buf), Err(StunError::NotStun)));
+
+        assert!(matches!(
+            StunMessage::parse(&SAMPLE_REQUEST[..100]),
+            Err(StunError::MalformedMessage(_))
+        ));
+
+        // Attribute running past the message length
+        let mut buf = SAMPLE_REQUEST;
+        buf[23] = 0x40;
+        assert!(matches!(
+            StunMessage::parse(&buf),
+            Err(StunError::MalformedMessage(_))
+        ));
+
+        // PRIORITY with a two byte value
+        let buf = [
+            0x00, 0x01, 0x00, 0x08, 0x21, 0x12, 0xa4, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0x00, 0x24, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00,
+        ];
+        assert!(matches!(
+            StunMessage::parse(&buf),
+            Err(StunError::MalformedMessage(_))
+        ));
+
+        // XOR-MAPPED-ADDRESS with an unknown family
+        let buf = [
+            0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0x00, 0x20, 0x00, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert!(matches!(
+            StunMessage::parse(&buf),
+            Err(StunError::MalformedMessage(_))
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+use actix::SpawnHandle;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::udp::SendHalf, sync::Mutex};
+
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Relayed transport address of a single client with its permissions and channels.
+pub struct Allocation {
+    pub relayed: SocketAddr,
+    pub username: String,
+    pub relay: Arc<Mutex<SendHalf>>,
+    pub handle: SpawnHandle,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+}
+
+impl Allocation {
+    pub fn new(
+        relayed: SocketAddr,
+        username: String,
+        relay: Arc<Mutex<SendHalf>>,
+        handle: SpawnHandle,
+        lifetime: Duration,
+    ) -> Allocation {
+        Allocation {
+            relayed,
+            username,
+            relay,
+            handle,
+            expires: Instant::now() + lifetime,
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn refresh(&mut self, lifetime: Duration) {
+        self.expires = Instant::now() + lifetime;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= Instant::now()
+    }
+
+    pub fn permit(&mut self, ip: IpAddr) {
+        self.permissions
+            .insert(ip, Instant::now() + PERMISSION_LIFETIME);
+    }
+
+    pub fn is_permitted(&self, ip: IpAddr) -> bool {
+        match self.permissions.get(&ip) {
+            Some(expires) => *expires > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// A channel stays bound to one peer and a peer to one channel until the binding expires.
+    pub fn bind_channel(&mut self, number: u16, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        let conflict = self.channels.iter().any(|(bound, (bound_peer, expires))| {
+            *expires > now && ((*bound == number) != (*bound_peer == peer))
+        });
+        if conflict {
+            return false;
+        }
+        self.channels.insert(number, (peer, now + CHANNEL_LIFETIME));
+        self.permit(peer.ip());
+        true
+    }
+
+    pub fn channel_peer(&self, number: u16) -> Option<SocketAddr> {
+        match self.channels.get(&number) {
+            Some((peer, expires)) if *expires > Instant::now() => Some(*peer),
+            _ => None,
+        }
+    }
+
+    pub fn peer_channel(&self, peer: SocketAddr) -> Option<u16> {
+        let now = Instant::now();
+        self.channels
+            .iter()
+            .find(|(_, (bound_peer, expires))| *bound_peer == peer && *expires > now)
+            .map(|(number, _)| *number)
+    }
+
+    pub fn clear_expired(&mut self) {
+        let now = Instant::now();
+        self.permissions.retain(|_, expires| *expires > now);
+        self.channels.retain(|_, (_, expires)| *expires > now);
+    }
+}
@@ -0,0 +1,129 @@
+pub mod allocation;
+pub mod peers;
+pub mod server;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use openssl::{
+    base64,
+    error::ErrorStack,
+    hash::{hash, MessageDigest},
+    pkey::PKey,
+    sign::Signer,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+pub const MIN_CHANNEL_NUMBER: u16 = 0x4000;
+pub const MAX_CHANNEL_NUMBER: u16 = 0x4fff;
+
+pub fn is_channel_data(buf: &[u8]) -> bool {
+    buf.len() >= CHANNEL_DATA_HEADER_LEN && buf[0] >> 6 == 0b01
+}
+
+/// Returns the channel number and application data of a ChannelData message.
+pub fn parse_channel_data(buf: &[u8]) -> Option<(u16, &[u8])> {
+    if !is_channel_data(buf) {
+        return None;
+    }
+    let number = NetworkEndian::read_u16(&buf[0..2]);
+    let len = NetworkEndian::read_u16(&buf[2..4]) as usize;
+    let data = buf.get(CHANNEL_DATA_HEADER_LEN..CHANNEL_DATA_HEADER_LEN + len)?;
+    Some((number, data))
+}
+
+/// Over UDP the ChannelData message is not padded, RFC 8656 section 12.5.
+pub fn write_channel_data(number: u16, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(CHANNEL_DATA_HEADER_LEN + data.len());
+    out.extend_from_slice(&number.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Long-term credential key, MD5(username ":" realm ":" password).
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Result<Vec<u8>, ErrorStack> {
+    let credentials = format!("{}:{}:{}", username, realm, password);
+    Ok(hash(MessageDigest::md5(), credentials.as_bytes())?.to_vec())
+}
+
+/// Ephemeral credentials of the TURN REST API, the username `expiry:user` carries the unix
+/// time they expire at and the password is derived from it with the shared secret.
+pub fn rest_credentials(
+    secret: &str,
+    user: &str,
+    ttl: Duration,
+) -> Result<(String, String), ErrorStack> {
+    let username = format!("{}:{}", unix_time() + ttl.as_secs(), user);
+    let password = rest_password(secret, &username)?;
+    Ok((username, password))
+}
+
+/// base64(HMAC-SHA1(secret, username)).
+pub fn rest_password(secret: &str, username: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(username.as_bytes())?;
+    Ok(base64::encode_block(&signer.sign_to_vec()?))
+}
+
+/// Expiry and user of a TURN REST username, `None` for any other username.
+pub fn parse_rest_username(username: &str) -> Option<(u64, &str)> {
+    let mut parts = username.splitn(2, ':');
+    let expiry = parts.next()?.parse().ok()?;
+    Some((expiry, parts.next()?))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rest_password_is_the_hmac_of_the_username() {
+        assert_eq!(
+            rest_password("north", "1433895918:alice").unwrap(),
+            "RqMvcGPYTJMGThVLSi4amT4zFtI="
+        );
+    }
+
+    #[test]
+    fn rest_credentials_expire_after_their_ttl() {
+        let (username, password) =
+            rest_credentials("north", "alice", Duration::from_secs(60)).unwrap();
+        let (expiry, user) = parse_rest_username(&username).unwrap();
+
+        assert_eq!(user, "alice");
+        assert!(expiry >= unix_time() + 59 && expiry <= unix_time() + 60);
+        assert_eq!(password, rest_password("north", &username).unwrap());
+        assert_ne!(password, rest_password("south", &username).unwrap());
+    }
+
+    #[test]
+    fn static_usernames_are_not_rest_ones() {
+        assert_eq!(parse_rest_username("alice"), None);
+        assert_eq!(parse_rest_username("alice:bob"), None);
+        assert_eq!(parse_rest_username("1433895918"), None);
+        assert_eq!(
+            parse_rest_username("1433895918:2001:db8::1"),
+            Some((1433895918, "2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn channel_data_round_trip() {
+        let message = write_channel_data(MIN_CHANNEL_NUMBER, &[1, 2, 3]);
+        assert_eq!(message, vec![0x40, 0x00, 0x00, 0x03, 1, 2, 3]);
+        assert_eq!(
+            parse_channel_data(&message),
+            Some((MIN_CHANNEL_NUMBER, &[1, 2, 3][..]))
+        );
+        assert_eq!(parse_channel_data(&message[..5]), None);
+        assert_eq!(parse_channel_data(&[0x00, 0x01, 0x00, 0x00]), None);
+    }
+}
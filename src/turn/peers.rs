@@ -0,0 +1,217 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+
+/// Peers a client must not reach through the relay unless they are allowed explicitly:
+/// this host, private and link-local networks with the cloud metadata services in them
+/// (169.254.169.254, 100.100.100.200, fd00:ec2::254), multicast and reserved blocks.
+const DENIED_BY_DEFAULT: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Block of addresses in CIDR notation, e.g. `10.0.0.0/8`, a bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                matches_prefix(&range.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                matches_prefix(&range.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = IpRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IpRangeError(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or_else(invalid)?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpRange {
+            addr: canonical(addr),
+            prefix,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct IpRangeError(String);
+
+impl Display for IpRangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid address range {}", self.0)
+    }
+}
+
+impl Error for IpRangeError {}
+
+/// Decides which peers a relay may talk to, like the denied-peer-ip and allowed-peer-ip
+/// options of coturn: allowed ranges win over the denied ones and the default ones.
+#[derive(Debug, Clone)]
+pub struct PeerFilter {
+    allowed: Vec<IpRange>,
+    denied: Vec<IpRange>,
+    local: Vec<SocketAddr>,
+}
+
+impl PeerFilter {
+    /// `local` addresses of the server itself stay reachable, clients relay their media to them.
+    pub fn new(allowed: &[IpRange], denied: &[IpRange], local: &[SocketAddr]) -> PeerFilter {
+        let defaults = DENIED_BY_DEFAULT
+            .iter()
+            .map(|range| range.parse().expect("default denied range"));
+        PeerFilter {
+            allowed: allowed.to_vec(),
+            denied: defaults.chain(denied.iter().copied()).collect(),
+            local: local.to_vec(),
+        }
+    }
+
+    pub fn is_allowed(&self, peer: &SocketAddr) -> bool {
+        let ip = canonical(peer.ip());
+        if peer.port() == 0 || ip.is_unspecified() {
+            return false;
+        }
+        let local = self
+            .local
+            .iter()
+            .any(|local| canonical(local.ip()) == ip && local.port() == peer.port());
+        local
+            || self.allowed.iter().any(|range| range.contains(ip))
+            || !self.denied.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are checked as the IPv4 ones they are.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn matches_prefix(range: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if range[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (range[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn ranges(ranges: &[&str]) -> Vec<IpRange> {
+        ranges.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains("172.31.255.255".parse().unwrap()));
+        assert!(!range.contains("172.32.0.0".parse().unwrap()));
+        assert!(!range.contains("fe80::1".parse().unwrap()));
+
+        let single: IpRange = "2001:db8::1".parse().unwrap();
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        for invalid in &["10.0.0.0/33", "fe80::/129", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn internal_peers_are_denied_by_default() {
+        let filter = PeerFilter::new(&[], &[], &[]);
+        for denied in &[
+            "127.0.0.1:80",
+            "10.1.2.3:80",
+            "172.20.0.1:80",
+            "192.168.1.1:80",
+            "169.254.169.254:80",
+            "100.100.100.200:80",
+            "0.0.0.0:80",
+            "224.0.0.1:80",
+            "[::1]:80",
+            "[::]:80",
+            "[fd00:ec2::254]:80",
+            "[fe80::1]:80",
+            "[::ffff:127.0.0.1]:80",
+            "[::ffff:169.254.169.254]:80",
+            "198.51.100.1:0",
+        ] {
+            assert!(!filter.is_allowed(&peer(denied)), "{}", denied);
+        }
+        for allowed in &["198.51.100.1:5000", "[2001:db8::1]:5000", "8.8.8.8:53"] {
+            assert!(filter.is_allowed(&peer(allowed)), "{}", allowed);
+        }
+    }
+
+    #[test]
+    fn configured_ranges_override_the_defaults() {
+        let filter = PeerFilter::new(
+            &ranges(&["10.0.0.0/24"]),
+            &ranges(&["198.51.100.0/24", "10.0.0.0/8"]),
+            &[peer("127.0.0.1:3336")],
+        );
+        assert!(filter.is_allowed(&peer("10.0.0.7:5000")));
+        assert!(!filter.is_allowed(&peer("10.0.1.7:5000")));
+        assert!(!filter.is_allowed(&peer("198.51.100.1:5000")));
+        assert!(filter.is_allowed(&peer("127.0.0.1:3336")));
+        assert!(filter.is_allowed(&peer("[::ffff:127.0.0.1]:3336")));
+        assert!(!filter.is_allowed(&peer("127.0.0.1:22")));
+    }
+}
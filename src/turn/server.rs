@@ -0,0 +1,753 @@
+use crate::{
+    server::config::ServerConfig,
+    stun::{
+        message::{
+            StunAttribute, StunClass, StunError, StunMessage, FAMILY_IPV4, FAMILY_IPV6,
+            STUN_BINDING, TURN_ALLOCATE, TURN_CHANNEL_BIND, TURN_CREATE_PERMISSION, TURN_DATA,
+            TURN_REFRESH, TURN_SEND,
+        },
+        StunErrorCode,
+    },
+    turn::{
+        allocation::Allocation, is_channel_data, long_term_key, parse_channel_data,
+        parse_rest_username, peers::PeerFilter, rest_password, unix_time, write_channel_data,
+        MAX_CHANNEL_NUMBER, MIN_CHANNEL_NUMBER,
+    },
+};
+use actix::prelude::*;
+use log::{info, warn};
+use openssl::{error::ErrorStack, hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::udp::{RecvHalf, SendHalf},
+    net::UdpSocket,
+    stream::{Stream, StreamExt},
+    sync::Mutex,
+};
+
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+const NONCE_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_MAC_LEN: usize = 16;
+const EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const UDP_PROTOCOL: u8 = 17;
+
+/// TURN relay of RFC 8656 with UDP allocations and long-term credentials, either static ones
+/// or the ephemeral ones of the TURN REST API.
+pub struct TurnServer {
+    send: Arc<Mutex<SendHalf>>,
+    realm: String,
+    users: HashMap<String, String>,
+    secret: Option<String>,
+    peers: PeerFilter,
+    user_quota: usize,
+    total_quota: usize,
+    relay_ip: IpAddr,
+    advertised_ip: IpAddr,
+    allocations: HashMap<SocketAddr, Allocation>,
+    /// Key of the nonce HMACs, nonces stay valid as long as the server runs.
+    nonce_key: [u8; 32],
+}
+
+impl Actor for TurnServer {
+    type Context = Context<Self>;
+}
+
+struct TurnPacket(Vec<u8>, SocketAddr);
+
+struct RelayPacket {
+    data: Vec<u8>,
+    peer: SocketAddr,
+    client: SocketAddr,
+}
+
+struct ClearAllocations;
+
+impl TurnServer {
+    /// Relay sockets are bound to the IP of `socket`, `advertised_ip` is told to the clients.
+    /// The `local` addresses of the server may be relayed to whatever the peer filter says.
+    pub fn new(
+        socket: UdpSocket,
+        config: &ServerConfig,
+        advertised_ip: IpAddr,
+        local: &[SocketAddr],
+    ) -> Addr<Self> {
+        let peers = PeerFilter::new(&config.turn_allowed_peers, &config.turn_denied_peers, local);
+        let relay_ip = socket
+            .local_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(advertised_ip);
+        let (recv, send) = socket.split();
+
+        TurnServer::create(|ctx| {
+            ctx.add_stream(receive(recv).map(|(data, addr)| TurnPacket(data, addr)));
+            ctx.add_stream(
+                tokio::time::interval(EXPIRATION_CHECK_INTERVAL).map(|_| ClearAllocations),
+            );
+
+            TurnServer {
+                send: Arc::new(Mutex::new(send)),
+                realm: config.turn_realm.clone(),
+                users: config.turn_users.clone(),
+                secret: config.turn_secret.clone(),
+                peers,
+                user_quota: config.turn_user_quota,
+                total_quota: config.turn_total_quota,
+                relay_ip,
+                advertised_ip,
+                allocations: HashMap::new(),
+                nonce_key: rand::thread_rng().gen(),
+            }
+        })
+    }
+
+    fn handle_request(&mut self, request: StunMessage, addr: SocketAddr, ctx: &mut Context<Self>) {
+        let unknown = request.unknown_required();
+        if !unknown.is_empty() {
+            let response = error_response(&request, StunErrorCode::UnknownAttribute)
+                .with(StunAttribute::UnknownAttributes(unknown));
+            return self.respond(response, None, addr, ctx);
+        }
+
+        let (username, key) = match self.authenticate(&request, addr) {
+            Ok(credentials) => credentials,
+            Err(code) => {
+                let mut response = error_response(&request, code);
+                if code == StunErrorCode::Unauthorized || code == StunErrorCode::StaleNonce {
+                    match issue_nonce(&self.nonce_key, addr, unix_time()) {
+                        Ok(nonce) => {
+                            response = response
+                                .with(StunAttribute::Realm(self.realm.clone()))
+                                .with(StunAttribute::Nonce(nonce))
+                        }
+                        Err(e) => warn!("could not issue turn nonce: {}", e),
+                    }
+                }
+                return self.respond(response, None, addr, ctx);
+            }
+        };
+
+        let result = match request.method {
+            TURN_ALLOCATE => self.allocate(&request, addr, username, ctx),
+            TURN_REFRESH => self.refresh(&request, addr, ctx),
+            TURN_CREATE_PERMISSION => self.create_permission(&request, addr),
+            TURN_CHANNEL_BIND => self.channel_bind(&request, addr),
+            _ => Err(StunErrorCode::BadRequest),
+        };
+        let response = match result {
+            Ok(attributes) => attributes.into_iter().fold(
+                StunMessage::new(
+                    StunClass::SuccessResponse,
+                    request.method,
+                    request.transaction_id,
+                ),
+                StunMessage::with,
+            ),
+            Err(code) => error_response(&request, code),
+        };
+        self.respond(
+            response.with(StunAttribute::MessageIntegrity(Vec::new())),
+            Some(&key),
+            addr,
+            ctx,
+        );
+    }
+
+    /// Checks the long-term credentials of a request and returns the username with its key.
+    /// TURN REST usernames are accepted until the expiry they carry.
+    fn authenticate(
+        &mut self,
+        request: &StunMessage,
+        addr: SocketAddr,
+    ) -> Result<(String, Vec<u8>), StunErrorCode> {
+        if !request.has_integrity() {
+            return Err(StunErrorCode::Unauthorized);
+        }
+        let (username, realm, nonce) =
+            match (request.username(), find_realm(request), find_nonce(request)) {
+                (Some(username), Some(realm), Some(nonce)) => (username, realm, nonce),
+                _ => return Err(StunErrorCode::BadRequest),
+            };
+
+        if !is_fresh_nonce(&self.nonce_key, addr, nonce, unix_time()) {
+            return Err(StunErrorCode::StaleNonce);
+        }
+        if realm != self.realm {
+            return Err(StunErrorCode::Unauthorized);
+        }
+
+        let password = match (&self.secret, parse_rest_username(username)) {
+            (Some(secret), Some((expiry, _))) => {
+                if expiry <= unix_time() {
+                    return Err(StunErrorCode::Unauthorized);
+                }
+                rest_password(secret, username).map_err(|e| {
+                    warn!("could not derive turn rest password: {}", e);
+                    StunErrorCode::Unauthorized
+                })?
+            }
+            _ => self
+                .users
+                .get(username)
+                .cloned()
+                .ok_or(StunErrorCode::Unauthorized)?,
+        };
+        let key = long_term_key(username, &self.realm, &password).map_err(|e| {
+            warn!("could not derive turn key: {}", e);
+            StunErrorCode::Unauthorized
+        })?;
+        if !request.verify_integrity(&key) {
+            return Err(StunErrorCode::Unauthorized);
+        }
+
+        match self.allocations.get(&addr) {
+            Some(allocation) if allocation.username != username => {
+                Err(StunErrorCode::WrongCredentials)
+            }
+            _ => Ok((username.to_owned(), key)),
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        request: &StunMessage,
+        addr: SocketAddr,
+        username: String,
+        ctx: &mut Context<Self>,
+    ) -> Result<Vec<StunAttribute>, StunErrorCode> {
+        if self.allocations.contains_key(&addr) {
+            return Err(StunErrorCode::AllocationMismatch);
+        }
+        check_quota(
+            self.allocations
+                .values()
+                .map(|allocation| quota_user(&allocation.username)),
+            quota_user(&username),
+            self.user_quota,
+            self.total_quota,
+        )?;
+        match find_requested_transport(request) {
+            Some(UDP_PROTOCOL) => {}
+            Some(_) => return Err(StunErrorCode::UnsupportedTransportProtocol),
+            None => return Err(StunErrorCode::BadRequest),
+        }
+        let family = match self.relay_ip {
+            IpAddr::V4(_) => FAMILY_IPV4,
+            IpAddr::V6(_) => FAMILY_IPV6,
+        };
+        match find_requested_address_family(request) {
+            Some(requested) if requested != family => {
+                return Err(StunErrorCode::AddressFamilyNotSupported)
+            }
+            _ => {}
+        }
+
+        let socket = bind_relay(SocketAddr::new(self.relay_ip, 0)).map_err(|e| {
+            warn!("could not bind turn relay: {}", e);
+            StunErrorCode::InsufficientCapacity
+        })?;
+        let port = socket
+            .local_addr()
+            .map_err(|_| StunErrorCode::InsufficientCapacity)?
+            .port();
+        let (recv, send) = socket.split();
+        let handle = ctx.add_stream(receive(recv).map(move |(data, peer)| RelayPacket {
+            data,
+            peer,
+            client: addr,
+        }));
+
+        let relayed = SocketAddr::new(self.advertised_ip, port);
+        // Zero only deletes an existing allocation
+        let lifetime = requested_lifetime(request).max(DEFAULT_LIFETIME);
+        info!("turn allocation {} for {} ({})", relayed, addr, username);
+        self.allocations.insert(
+            addr,
+            Allocation::new(
+                relayed,
+                username,
+                Arc::new(Mutex::new(send)),
+                handle,
+                lifetime,
+            ),
+        );
+
+        Ok(vec![
+            StunAttribute::XorRelayedAddress(relayed),
+            StunAttribute::Lifetime(lifetime.as_secs() as u32),
+            StunAttribute::XorMappedAddress(addr),
+        ])
+    }
+
+    fn refresh(
+        &mut self,
+        request: &StunMessage,
+        addr: SocketAddr,
+        ctx: &mut Context<Self>,
+    ) -> Result<Vec<StunAttribute>, StunErrorCode> {
+        let lifetime = requested_lifetime(request);
+        if lifetime.as_secs() == 0 {
+            let allocation = self
+                .allocations
+                .remove(&addr)
+                .ok_or(StunErrorCode::AllocationMismatch)?;
+            info!("turn allocation {} released", allocation.relayed);
+            ctx.cancel_future(allocation.handle);
+        } else {
+            self.allocations
+                .get_mut(&addr)
+                .ok_or(StunErrorCode::AllocationMismatch)?
+                .refresh(lifetime);
+        }
+        Ok(vec![StunAttribute::Lifetime(lifetime.as_secs() as u32)])
+    }
+
+    fn create_permission(
+        &mut self,
+        request: &StunMessage,
+        addr: SocketAddr,
+    ) -> Result<Vec<StunAttribute>, StunErrorCode> {
+        let peers = find_peers(request);
+        if peers.is_empty() {
+            return Err(StunErrorCode::BadRequest);
+        }
+        if !peers.iter().all(|peer| self.peers.is_allowed(peer)) {
+            return Err(StunErrorCode::Forbidden);
+        }
+        let allocation = self
+            .allocations
+            .get_mut(&addr)
+            .ok_or(StunErrorCode::AllocationMismatch)?;
+        peers.iter().for_each(|peer| allocation.permit(peer.ip()));
+        Ok(Vec::new())
+    }
+
+    fn channel_bind(
+        &mut self,
+        request: &StunMessage,
+        addr: SocketAddr,
+    ) -> Result<Vec<StunAttribute>, StunErrorCode> {
+        let number = request
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                StunAttribute::ChannelNumber(number) => Some(*number),
+                _ => None,
+            })
+            .filter(|number| (MIN_CHANNEL_NUMBER..=MAX_CHANNEL_NUMBER).contains(number))
+            .ok_or(StunErrorCode::BadRequest)?;
+        let peer = match find_peers(request).as_slice() {
+            [peer] => *peer,
+            _ => return Err(StunErrorCode::BadRequest),
+        };
+        if !self.peers.is_allowed(&peer) {
+            return Err(StunErrorCode::Forbidden);
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(&addr)
+            .ok_or(StunErrorCode::AllocationMismatch)?;
+        if !allocation.bind_channel(number, peer) {
+            return Err(StunErrorCode::BadRequest);
+        }
+        Ok(Vec::new())
+    }
+
+    /// Send indications are not authenticated, they are dropped without a permission.
+    fn send_indication(
+        &mut self,
+        indication: StunMessage,
+        addr: SocketAddr,
+        ctx: &mut Context<Self>,
+    ) {
+        let peer = find_peers(&indication).into_iter().next();
+        let data = indication
+            .attributes
+            .into_iter()
+            .find_map(|attribute| match attribute {
+                StunAttribute::Data(data) => Some(data),
+                _ => None,
+            });
+        if let (Some(peer), Some(data)) = (peer, data) {
+            self.relay(addr, peer, data, ctx);
+        }
+    }
+
+    fn relay(
+        &mut self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+        data: Vec<u8>,
+        ctx: &mut Context<Self>,
+    ) {
+        if let Some(allocation) = self.allocations.get(&addr) {
+            if allocation.is_permitted(peer.ip()) && self.peers.is_allowed(&peer) {
+                self.send_to(Arc::clone(&allocation.relay), data, peer, ctx);
+            }
+        }
+    }
+
+    fn send_to(
+        &self,
+        sender: Arc<Mutex<SendHalf>>,
+        message: Vec<u8>,
+        addr: SocketAddr,
+        ctx: &mut Context<Self>,
+    ) {
+        ctx.spawn(
+            async move {
+                let result = sender.lock().await.send_to(&message, &addr).await;
+                if let Err(e) = result {
+                    warn!("err turn {:?}", e)
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn respond(
+        &self,
+        response: StunMessage,
+        key: Option<&[u8]>,
+        addr: SocketAddr,
+        ctx: &mut Context<Self>,
+    ) {
+        match response.write(key) {
+            Ok(response) => self.send_to(Arc::clone(&self.send), response, addr, ctx),
+            Err(e) => warn!("error on writing turn response: {}", e),
+        }
+    }
+}
+
+impl StreamHandler<TurnPacket> for TurnServer {
+    fn handle(&mut self, TurnPacket(data, addr): TurnPacket, ctx: &mut Context<Self>) {
+        if is_channel_data(&data) {
+            let peer = parse_channel_data(&data).and_then(|(number, data)| {
+                let peer = self.allocations.get(&addr)?.channel_peer(number)?;
+                Some((peer, data.to_vec()))
+            });
+            if let Some((peer, data)) = peer {
+                self.relay(addr, peer, data, ctx);
+            }
+            return;
+        }
+
+        let message = match StunMessage::parse(&data) {
+            Ok(message) => message,
+            Err(StunError::NotStun) => return,
+            Err(e) => {
+                warn!("invalid turn message from {}: {}", addr, e);
+                return;
+            }
+        };
+        match (message.class, message.method) {
+            (StunClass::Request, STUN_BINDING) => {
+                let response = StunMessage::new(
+                    StunClass::SuccessResponse,
+                    STUN_BINDING,
+                    message.transaction_id,
+                )
+                .with(StunAttribute::XorMappedAddress(addr))
+                .with(StunAttribute::Fingerprint(0));
+                self.respond(response, None, addr, ctx);
+            }
+            (StunClass::Request, _) => self.handle_request(message, addr, ctx),
+            (StunClass::Indication, TURN_SEND) => self.send_indication(message, addr, ctx),
+            _ => {}
+        }
+    }
+}
+
+impl StreamHandler<RelayPacket> for TurnServer {
+    fn handle(&mut self, packet: RelayPacket, ctx: &mut Context<Self>) {
+        let allocation = match self.allocations.get(&packet.client) {
+            Some(allocation) if allocation.is_permitted(packet.peer.ip()) => allocation,
+            _ => return,
+        };
+
+        let message = match allocation.peer_channel(packet.peer) {
+            Some(number) => write_channel_data(number, &packet.data),
+            None => {
+                let indication = StunMessage::new(StunClass::Indication, TURN_DATA, rand::random())
+                    .with(StunAttribute::XorPeerAddress(packet.peer))
+                    .with(StunAttribute::Data(packet.data));
+                match indication.write(None) {
+                    Ok(indication) => indication,
+                    Err(e) => {
+                        warn!("error on writing turn data indication: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        self.send_to(Arc::clone(&self.send), message, packet.client, ctx);
+    }
+
+    /// Relay sockets are only closed by expiring their allocation.
+    fn finished(&mut self, _ctx: &mut Context<Self>) {}
+}
+
+impl StreamHandler<ClearAllocations> for TurnServer {
+    fn handle(&mut self, _: ClearAllocations, ctx: &mut Context<Self>) {
+        let expired: Vec<SocketAddr> = self
+            .allocations
+            .iter()
+            .filter(|(_, allocation)| allocation.is_expired())
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            if let Some(allocation) = self.allocations.remove(&addr) {
+                info!("turn allocation {} expired", allocation.relayed);
+                ctx.cancel_future(allocation.handle);
+            }
+        }
+
+        self.allocations
+            .values_mut()
+            .for_each(Allocation::clear_expired);
+    }
+}
+
+fn receive(recv: RecvHalf) -> impl Stream<Item = (Vec<u8>, SocketAddr)> {
+    futures::stream::unfold(recv, |mut recv| async move {
+        let mut message_buf: Vec<u8> = vec![0; 0x10000];
+
+        match recv.recv_from(&mut message_buf).await {
+            Ok((n, addr_from)) => {
+                message_buf.truncate(n);
+                Some(((message_buf, addr_from), recv))
+            }
+            Err(err) => {
+                warn!("could not receive TURN message: {}", err);
+                None
+            }
+        }
+    })
+}
+
+fn bind_relay(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Allocations of TURN REST usernames count for the user part, whatever their expiry.
+fn quota_user(username: &str) -> &str {
+    parse_rest_username(username).map_or(username, |(_, user)| user)
+}
+
+/// Refuses another allocation of `user` once the relay or the user has as many as allowed.
+fn check_quota<'a>(
+    allocated: impl Iterator<Item = &'a str>,
+    user: &str,
+    user_quota: usize,
+    total_quota: usize,
+) -> Result<(), StunErrorCode> {
+    let (total, of_user) = allocated.fold((0, 0), |(total, of_user), allocated| {
+        (total + 1, of_user + (allocated == user) as usize)
+    });
+    if total >= total_quota {
+        Err(StunErrorCode::InsufficientCapacity)
+    } else if of_user >= user_quota {
+        Err(StunErrorCode::AllocationQuotaReached)
+    } else {
+        Ok(())
+    }
+}
+
+/// Nonces are not stored, they carry the unix time they were issued at and an HMAC of it
+/// and the client address, so unauthenticated requests cost no memory.
+fn issue_nonce(key: &[u8], addr: SocketAddr, issued: u64) -> Result<String, ErrorStack> {
+    let mac = nonce_mac(key, addr, issued)?;
+    Ok(format!("{:016x}{}", issued, mac))
+}
+
+fn nonce_mac(key: &[u8], addr: SocketAddr, issued: u64) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(addr.to_string().as_bytes())?;
+    signer.update(&issued.to_be_bytes())?;
+    Ok(signer.sign_to_vec()?[..NONCE_MAC_LEN]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn is_fresh_nonce(key: &[u8], addr: SocketAddr, nonce: &str, now: u64) -> bool {
+    if nonce.len() != 16 + NONCE_MAC_LEN * 2 || !nonce.is_ascii() {
+        return false;
+    }
+    let (issued, mac) = nonce.split_at(16);
+    let issued = match u64::from_str_radix(issued, 16) {
+        Ok(issued) if issued <= now && now - issued < NONCE_LIFETIME.as_secs() => issued,
+        _ => return false,
+    };
+    match nonce_mac(key, addr, issued) {
+        Ok(expected) => memcmp::eq(expected.as_bytes(), mac.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+fn error_response(request: &StunMessage, code: StunErrorCode) -> StunMessage {
+    StunMessage::new(
+        StunClass::ErrorResponse,
+        request.method,
+        request.transaction_id,
+    )
+    .with(StunAttribute::ErrorCode(
+        code as u16,
+        code.reason().to_owned(),
+    ))
+}
+
+/// Lifetimes below the default are raised to it and long ones capped, RFC 8656 section 7.2,
+/// zero is kept as it asks a refresh to delete the allocation.
+fn requested_lifetime(request: &StunMessage) -> Duration {
+    request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            StunAttribute::Lifetime(lifetime) => Some(Duration::from_secs(*lifetime as u64)),
+            _ => None,
+        })
+        .map(|lifetime| match lifetime.as_secs() {
+            0 => lifetime,
+            _ => lifetime.max(DEFAULT_LIFETIME).min(MAX_LIFETIME),
+        })
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+fn find_realm(request: &StunMessage) -> Option<&str> {
+    request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            StunAttribute::Realm(realm) => Some(realm.as_str()),
+            _ => None,
+        })
+}
+
+fn find_nonce(request: &StunMessage) -> Option<&str> {
+    request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            StunAttribute::Nonce(nonce) => Some(nonce.as_str()),
+            _ => None,
+        })
+}
+
+fn find_requested_transport(request: &StunMessage) -> Option<u8> {
+    request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            StunAttribute::RequestedTransport(protocol) => Some(*protocol),
+            _ => None,
+        })
+}
+
+fn find_requested_address_family(request: &StunMessage) -> Option<u8> {
+    request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            StunAttribute::RequestedAddressFamily(family) => Some(*family),
+            _ => None,
+        })
+}
+
+fn find_peers(request: &StunMessage) -> Vec<SocketAddr> {
+    request
+        .attributes
+        .iter()
+        .filter_map(|attribute| match attribute {
+            StunAttribute::XorPeerAddress(peer) => Some(*peer),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotas_limit_users_and_the_relay() {
+        let allocated = ["alice", "bob", "alice"];
+        let check = |user, user_quota, total_quota| {
+            check_quota(allocated.iter().copied(), user, user_quota, total_quota)
+        };
+
+        assert_eq!(check("alice", 3, 4), Ok(()));
+        assert_eq!(
+            check("alice", 2, 4),
+            Err(StunErrorCode::AllocationQuotaReached)
+        );
+        assert_eq!(check("carol", 1, 4), Ok(()));
+        assert_eq!(
+            check("carol", 1, 3),
+            Err(StunErrorCode::InsufficientCapacity)
+        );
+    }
+
+    #[test]
+    fn nonces_are_bound_to_the_address_and_expire() {
+        let key = [7; 32];
+        let addr: SocketAddr = "198.51.100.1:5000".parse().unwrap();
+        let now = 1_600_000_000;
+        let nonce = issue_nonce(&key, addr, now).unwrap();
+
+        assert!(is_fresh_nonce(&key, addr, &nonce, now));
+        assert!(is_fresh_nonce(&key, addr, &nonce, now + 599));
+        assert!(!is_fresh_nonce(&key, addr, &nonce, now + 600));
+        assert!(!is_fresh_nonce(&key, addr, &nonce, now - 1));
+        assert!(!is_fresh_nonce(
+            &key,
+            "198.51.100.1:5001".parse().unwrap(),
+            &nonce,
+            now
+        ));
+        assert!(!is_fresh_nonce(&[8; 32], addr, &nonce, now));
+
+        // A later issue time with the old MAC does not extend the nonce
+        let forged = format!("{:016x}{}", now + 300, &nonce[16..]);
+        assert!(!is_fresh_nonce(&key, addr, &forged, now + 700));
+        assert!(!is_fresh_nonce(&key, addr, "", now));
+        assert!(!is_fresh_nonce(&key, addr, &nonce[1..], now));
+    }
+
+    #[test]
+    fn requested_lifetimes_are_clamped() {
+        let lifetime = |requested: Option<u32>| {
+            let request = StunMessage::new(StunClass::Request, TURN_REFRESH, [0; 12]);
+            let request = match requested {
+                Some(requested) => request.with(StunAttribute::Lifetime(requested)),
+                None => request,
+            };
+            requested_lifetime(&request).as_secs()
+        };
+        assert_eq!(lifetime(None), 600);
+        assert_eq!(lifetime(Some(0)), 0);
+        assert_eq!(lifetime(Some(1)), 600);
+        assert_eq!(lifetime(Some(599)), 600);
+        assert_eq!(lifetime(Some(1800)), 1800);
+        assert_eq!(lifetime(Some(3600)), 3600);
+        assert_eq!(lifetime(Some(3601)), 3600);
+        assert_eq!(lifetime(Some(u32::MAX)), 3600);
+    }
+
+    #[test]
+    fn rest_usernames_share_the_quota_of_their_user() {
+        assert_eq!(quota_user("1433895918:alice"), "alice");
+        assert_eq!(quota_user("1433895919:alice"), "alice");
+        assert_eq!(quota_user("alice"), "alice");
+    }
+}